@@ -1,21 +1,38 @@
-use super::entity::AppState;
+use super::{entity::AppState, token::require_session};
 use crate::{
     result::{ApiError, Result},
-    server::entity::User,
+    server::entity::{Credential, Session, User},
+};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
 };
 use axum::{
     body::Body,
     extract::{FromRequestParts, State},
-    http::{Request, StatusCode},
+    http::{header, Request, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
     Json,
 };
+use headers::{
+    authorization::{Authorization, Bearer},
+    HeaderMapExt,
+};
+use rand::RngCore;
 use serde::Deserialize;
-use tower_cookies::{Cookie, Cookies};
+use sha2::{Digest, Sha256};
+use tower_cookies::{
+    cookie::{time::Duration, SameSite},
+    Cookie, Cookies,
+};
 use tracing::{error, info};
 
 pub const AUTH_TOKEN: &str = "auth-token";
+pub const ACCESS_TOKEN_PARAM: &str = "access_token";
+const MIN_PASSWORD_LEN: usize = 8;
+/// hash of a random password with the default argon2 params, verified against when the user doesn't exist
+const DUMMY_PASSWORD_HASH: &str = "$argon2id$v=19$m=19456,t=2,p=1$fWkpd7CznoNskjgDRUQjLA$zeVz87O/JYTZl1G991pqc1O9zvRMZUBCfO5Hfx05cSo";
 
 #[derive(Deserialize)]
 pub struct LoginInfo {
@@ -23,46 +40,140 @@ pub struct LoginInfo {
     password: String,
 }
 
-pub async fn login(mut state: State<AppState>, cookies: Cookies, login_info: Json<LoginInfo>) -> Result<Response> {
-    if login_info.username != "test" || login_info.password != "test" {
-        cookies.remove(Cookie::from(AUTH_TOKEN));
-        return Err(ApiError::NotAuthenticated);
+#[derive(Deserialize)]
+pub struct RegisterInfo {
+    username: String,
+    password: String,
+    #[serde(default)]
+    phone_number: String,
+    #[serde(default)]
+    email: String,
+}
+
+/// Usernames are stored and looked up without surrounding whitespace
+fn normalize_username(username: &str) -> &str {
+    username.trim()
+}
+
+pub async fn register(state: State<AppState>, register_info: Json<RegisterInfo>) -> Result<Response> {
+    let username = normalize_username(&register_info.username);
+    if username.is_empty() || register_info.password.len() < MIN_PASSWORD_LEN {
+        return Err(ApiError::InvalidArgument(format!(
+            "username must not be empty and password must be at least {MIN_PASSWORD_LEN} characters"
+        )));
     }
 
-    // query the user from DB
     let user = User {
-        id: 123,
-        username: login_info.username.to_string(),
-        password: "password".to_string(),
-        phone_number: "13800138000".to_string(),
-        email: "test@gmail.com".to_string(),
-        create_time: "haha".to_string(),
+        username: username.to_string(),
+        password: hash_password(&register_info.password)?,
+        phone_number: register_info.phone_number.clone(),
+        email: register_info.email.clone(),
+        ..Default::default()
     };
 
+    if !state.get_database().save_user(&user)? {
+        return Err(ApiError::UserAlreadyExists);
+    }
+
+    info!("new user registered:{username}");
+    Ok(StatusCode::CREATED.into_response())
+}
+
+pub async fn login(state: State<AppState>, cookies: Cookies, login_info: Json<LoginInfo>) -> Result<Response> {
     let db = state.get_database();
-    db.save_user(&user).unwrap();
-    let user = db.query_user(&user.username, &user.password).unwrap();
+    // an unknown user costs as much as a wrong password, so the timing doesn't tell which accounts exist
+    let user = db.query_user(normalize_username(&login_info.username));
+    let password_hash = match &user {
+        Some(user) => user.password.as_str(),
+        None => DUMMY_PASSWORD_HASH,
+    };
+    let verified = verify_password(&login_info.password, password_hash);
+    let user = match user {
+        Some(user) if verified => user,
+        _ => {
+            cookies.remove(Cookie::from(AUTH_TOKEN));
+            return Err(ApiError::IncorrectCrecidentials);
+        }
+    };
+
+    db.delete_expired_sessions()?;
 
-    let mut cookie = Cookie::new(AUTH_TOKEN, user.id.to_string());
+    let token = generate_token();
+    let session = Session {
+        token_hash: hash_token(&token),
+        user_id: user.id,
+        ..Default::default()
+    };
+    let config = state.get_config();
+    let ttl_secs = config.session_ttl_secs;
+    db.save_session(&session, ttl_secs)?;
+
+    let mut cookie = Cookie::new(AUTH_TOKEN, token);
     cookie.set_http_only(true);
+    cookie.set_secure(config.secure_cookies);
+    cookie.set_same_site(SameSite::Lax);
     cookie.set_path("/");
+    cookie.set_max_age(Duration::seconds(ttl_secs as i64));
     cookies.add(cookie);
 
     info!("new user logged in:{}", user.id);
 
-    state.put_user(user);
+    Ok(StatusCode::OK.into_response())
+}
+
+pub async fn logout(credential: Credential, state: State<AppState>, cookies: Cookies) -> Result<Response> {
+    if let Credential::Session(token_hash) = credential {
+        state.get_database().delete_session(&token_hash)?;
+    }
+    cookies.remove(Cookie::build(AUTH_TOKEN).path("/").into());
+    Ok(StatusCode::OK.into_response())
+}
 
+pub async fn logout_all(user: User, credential: Credential, state: State<AppState>, cookies: Cookies) -> Result<Response> {
+    require_session(&credential)?;
+    let revoked = state.get_database().delete_user_sessions(user.id)?;
+    cookies.remove(Cookie::build(AUTH_TOKEN).path("/").into());
+    info!("revoked {revoked} sessions of user:{}", user.id);
     Ok(StatusCode::OK.into_response())
 }
 
-pub async fn user_resolver(state: State<AppState>, cookies: Cookies, mut request: Request<Body>, next: Next) -> impl IntoResponse {
-    let user = match cookies.get(AUTH_TOKEN).map(|cookie| cookie.value().parse().unwrap_or(0)) {
-        Some(uid) if uid > 0 => state.get_user(uid),
-        _ => None,
-    };
+pub(crate) fn generate_token() -> String {
+    let mut token = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut token);
+    hex::encode(token)
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| {
+            error!("failed to hash password: {e}");
+            ApiError::InternalError
+        })
+}
 
-    match user {
-        Some(user) => request.extensions_mut().insert(Ok::<User, ApiError>(user)),
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+        Err(e) => {
+            error!("malformed password hash: {e}");
+            false
+        }
+    }
+}
+
+pub async fn user_resolver(state: State<AppState>, cookies: Cookies, mut request: Request<Body>, next: Next) -> impl IntoResponse {
+    match resolve_credential(&state, &cookies, &request) {
+        Some((user, credential)) => {
+            request.extensions_mut().insert(Ok::<User, ApiError>(user));
+            request.extensions_mut().insert(credential);
+        }
         _ => {
             error!("user not authorized!");
             return ApiError::NotAuthenticated.into_response();
@@ -72,6 +183,38 @@ pub async fn user_resolver(state: State<AppState>, cookies: Cookies, mut request
     next.run(request).await.into_response()
 }
 
+fn resolve_credential(state: &AppState, cookies: &Cookies, request: &Request<Body>) -> Option<(User, Credential)> {
+    let db = state.get_database();
+    if let Some(token) = api_token_of(request) {
+        return db
+            .query_api_token_user(&hash_token(&token))
+            .map(|(user, token)| (user, Credential::ApiToken(token)));
+    }
+
+    let token_hash = hash_token(cookies.get(AUTH_TOKEN)?.value());
+    db.query_session_user(&token_hash)
+        .map(|user| (user, Credential::Session(token_hash)))
+}
+
+fn api_token_of(request: &Request<Body>) -> Option<String> {
+    if let Some(Authorization(bearer)) = request.headers().typed_get::<Authorization<Bearer>>() {
+        return Some(bearer.token().to_string());
+    }
+
+    // browsers can't set headers on WebSocket handshakes, so accept the token in the query string there
+    let is_ws_upgrade = request
+        .headers()
+        .get(header::UPGRADE)
+        .is_some_and(|upgrade| upgrade.as_bytes().eq_ignore_ascii_case(b"websocket"));
+    if !is_ws_upgrade {
+        return None;
+    }
+
+    url::form_urlencoded::parse(request.uri().query()?.as_bytes())
+        .find(|(key, _)| key == ACCESS_TOKEN_PARAM)
+        .map(|(_, value)| value.into_owned())
+}
+
 #[async_trait::async_trait]
 impl<S: Send + Sync> FromRequestParts<S> for User {
     type Rejection = ApiError;
@@ -80,3 +223,12 @@ impl<S: Send + Sync> FromRequestParts<S> for User {
         parts.extensions.get::<Result<User>>().ok_or(ApiError::NotAuthenticated)?.clone()
     }
 }
+
+#[async_trait::async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for Credential {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut axum::http::request::Parts, _state: &S) -> Result<Self> {
+        parts.extensions.get::<Credential>().cloned().ok_or(ApiError::NotAuthenticated)
+    }
+}
@@ -1,12 +1,10 @@
 use crate::{
-    server::entity::User,
-    storage::{
-        database::Database,
-        database_manager::{DatabaseConfig, DatabaseManager},
-    },
+    server::config::ServerConfig,
+    storage::{database::Database, database_manager::DatabaseManager, storage_registry::StorageRegistry, StorageContext},
 };
-use dashmap::DashMap;
+use anyhow::Result;
 use std::{path::PathBuf, sync::Arc};
+use tracing::info;
 
 // #[derive(Clone, Debug)]
 // struct UserInner {
@@ -44,31 +42,33 @@ use std::{path::PathBuf, sync::Arc};
 //     }
 // }
 
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct AppState {
-    users: Arc<DashMap<u32, User>>,
+    config: Arc<ServerConfig>,
     db_manager: DatabaseManager,
+    file_storage: Arc<StorageRegistry>,
     assets_base_dir: PathBuf,
 }
 
 impl AppState {
-    pub fn new() -> Self {
-        let db_config = DatabaseConfig {
-            uri: "./rsdrive.db".to_string(),
-        };
-        Self {
-            users: Arc::new(DashMap::new()),
-            db_manager: DatabaseManager::new(db_config),
-            assets_base_dir: homedir::get_my_home().unwrap_or(Some(PathBuf::from("./assets_base_dir"))).unwrap(),
+    pub fn new(config: ServerConfig) -> Result<Self> {
+        let file_storage = StorageRegistry::new(&config.storage, &config.storage_dir, config.hash_algorithm)?;
+        let db_manager = DatabaseManager::new(config.database.clone());
+        let assigned = db_manager.get_database()?.assign_storage_backend(file_storage.default_backend())?;
+        if assigned > 0 {
+            info!("assigned {assigned} blobs to storage backend:{}", file_storage.default_backend());
         }
-    }
 
-    pub fn get_user(&self, uid: u32) -> Option<User> {
-        self.users.get(&uid).map(|e| e.value().clone())
+        Ok(Self {
+            db_manager,
+            file_storage: Arc::new(file_storage),
+            config: Arc::new(config),
+            assets_base_dir: homedir::get_my_home().unwrap_or(Some(PathBuf::from("./assets_base_dir"))).unwrap(),
+        })
     }
 
-    pub fn put_user(&mut self, user: User) {
-        self.users.insert(user.id, user);
+    pub fn get_config(&self) -> &ServerConfig {
+        &self.config
     }
 
     pub fn get_assets_base_dir(&self) -> &PathBuf {
@@ -78,10 +78,31 @@ impl AppState {
     pub fn get_database(&self) -> Box<dyn Database> {
         self.db_manager.get_database().unwrap()
     }
+
+    pub fn get_file_storage(&self) -> Arc<StorageRegistry> {
+        self.file_storage.clone()
+    }
+
+    pub fn get_storage_context(&self) -> StorageContext {
+        StorageContext {
+            db: self.get_database(),
+            file_storage: self.get_file_storage(),
+        }
+    }
+}
+
+impl std::fmt::Debug for AppState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AppState")
+            .field("config", &self.config)
+            .field("db_manager", &self.db_manager)
+            .field("assets_base_dir", &self.assets_base_dir)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Default for AppState {
     fn default() -> Self {
-        Self::new()
+        Self::new(ServerConfig::default()).unwrap()
     }
 }
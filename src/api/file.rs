@@ -1,22 +1,70 @@
-use std::{net::SocketAddr, path::PathBuf};
+use std::{
+    io::SeekFrom,
+    net::SocketAddr,
+    ops::{Bound, RangeInclusive},
+};
 
 use crate::{
-    server::entity::User,
-    storage::{local_file_storage::LocalFileStorage, sqlite_database::SqliteDatabase, StorageContext},
-    transfer::transfer_task::TransferTask,
+    common::entity::{ConflictPolicy, FileVersion},
+    result::{ApiError, Result},
+    server::entity::{Credential, TokenScope, User},
+    storage::{async_file_reader::AsyncFileReader, storage_registry::BlobInUse},
+    transfer::{
+        spooled_upload::{SpooledUpload, UploadRejected, UploadedFile},
+        transfer_task::TransferTask,
+    },
 };
 
 use super::entity::AppState;
 use axum::{
-    extract::{ws::WebSocket, ConnectInfo, State, WebSocketUpgrade},
-    response::IntoResponse,
+    body::Body,
+    extract::{ws::WebSocket, ConnectInfo, Multipart, Path, Query, State, WebSocketUpgrade},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
 };
 use axum_extra::TypedHeader;
-use headers::UserAgent;
-use tracing::{debug, info};
+use futures::Stream;
+use headers::{AcceptRanges, ContentLength, ContentRange, ContentType, ETag, HeaderMapExt, IfRange, Range, UserAgent};
+use serde::Deserialize;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, error, info};
+
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+pub struct UploadParams {
+    #[serde(default)]
+    on_conflict: ConflictPolicy,
+}
+
+#[derive(Deserialize)]
+pub struct RestoreParams {
+    version: u32,
+}
+
+/// Splits a wildcard path like `docs/2024/report.pdf` into the stored dir `/docs/2024/` and the name `report.pdf`
+fn split_file_path(path: &str) -> Result<(String, String)> {
+    let path = path.trim_start_matches('/');
+    let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() {
+        return Err(ApiError::InvalidArgument("missing file name".to_string()));
+    }
+    Ok((to_file_dir(dir), name.to_string()))
+}
+
+/// The stored form of a dir path, `/` for the root and `/docs/2024/` below it
+fn to_file_dir(path: &str) -> String {
+    match path.trim_matches('/') {
+        "" => "/".to_string(),
+        dir => format!("/{dir}/"),
+    }
+}
 
 pub async fn ws_handler(
     user: User,
+    credential: Credential,
     state: State<AppState>,
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<UserAgent>>,
@@ -29,21 +77,14 @@ pub async fn ws_handler(
     };
     debug!("`{user_agent}` at {addr} connected.");
 
-    ws.on_upgrade(move |socket| handle_socket2(user, state, socket, addr))
+    ws.on_upgrade(move |socket| handle_socket2(user, credential, state, socket, addr))
 }
 
-async fn handle_socket2(user: User, state: State<AppState>, socket: WebSocket, addr: SocketAddr) {
+async fn handle_socket2(user: User, credential: Credential, state: State<AppState>, socket: WebSocket, addr: SocketAddr) {
     debug!(">>>>>>>>> haha handle_socket2: {user:?}");
-    let task = TransferTask::default();
-    let db = Box::new(SqliteDatabase::open("./test.db").unwrap());
-    let mut base_dir = PathBuf::new();
-    base_dir.push("./shared_files");
-    let local_file_storage = Box::new(LocalFileStorage::new(base_dir));
-    let storage_ctx = Box::new(StorageContext {
-        db,
-        file_storage: local_file_storage,
-    });
-    task.start(user.id, socket, storage_ctx);
+    let task = TransferTask::new(state.get_config().hash_algorithm);
+    let storage_ctx = Box::new(state.get_storage_context());
+    task.start(user.id, credential, socket, storage_ctx);
 
     // tokio::select! {
     //     rv_a = (&mut send_task) => {
@@ -64,3 +105,285 @@ async fn handle_socket2(user: User, state: State<AppState>, socket: WebSocket, a
 
     info!("connection ended: {addr}");
 }
+
+pub async fn download_file(
+    user: User,
+    credential: Credential,
+    state: State<AppState>,
+    Path(path): Path<String>,
+    range: Option<TypedHeader<Range>>,
+    if_range: Option<TypedHeader<IfRange>>,
+) -> Result<Response> {
+    if !credential.allows(TokenScope::Read) {
+        return Err(ApiError::PermissionDenied);
+    }
+
+    let (file_dir, file_name) = split_file_path(&path)?;
+    let mut storage_ctx = state.get_storage_context();
+    let file_info = match storage_ctx.db.query_file_info(user.id, &file_dir, &file_name) {
+        Some(file_info) if file_info.sync_size >= file_info.file_size => file_info,
+        _ => return Err(ApiError::NotFound),
+    };
+
+    let file_size = file_info.file_size as u64;
+    let etag = format!("\"{}\"", file_info.file_hash).parse::<ETag>().map_err(|e| {
+        error!("invalid etag for file hash:{}, {e}", file_info.file_hash);
+        ApiError::InternalError
+    })?;
+
+    // a stale If-Range means the client's partial copy is outdated, so the whole file is sent instead
+    let range = match (range, if_range) {
+        (Some(_), Some(TypedHeader(if_range))) if if_range.is_modified(Some(&etag), None) => None,
+        // multipart/byteranges is not served, so a multi-range request is ignored and the whole file is sent
+        (Some(TypedHeader(range)), _) if range.satisfiable_ranges(file_size).nth(1).is_some() => None,
+        (Some(TypedHeader(range)), _) => match satisfiable_range(&range, file_size) {
+            Some(range) => Some(range),
+            None => {
+                let mut response = StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+                response.headers_mut().typed_insert(ContentRange::unsatisfied_bytes(file_size));
+                return Ok(response);
+            }
+        },
+        _ => None,
+    };
+
+    let mut reader = AsyncFileReader::new(storage_ctx.open_reader(&file_info).await?);
+    let (status, start, len) = match &range {
+        Some(range) => (StatusCode::PARTIAL_CONTENT, *range.start(), range.end() - range.start() + 1),
+        None => (StatusCode::OK, 0, file_size),
+    };
+    reader.seek(SeekFrom::Start(start)).await.map_err(anyhow::Error::from)?;
+
+    debug!("downloading file:{}, start:{start}, len:{len}", file_info.file_hash);
+    let body = ReaderStream::with_capacity(reader.take(len), DOWNLOAD_CHUNK_SIZE);
+    let mut response = Response::new(Body::from_stream(body));
+    *response.status_mut() = status;
+
+    let headers = response.headers_mut();
+    headers.typed_insert(ContentLength(len));
+    headers.typed_insert(AcceptRanges::bytes());
+    headers.typed_insert(etag);
+    headers.typed_insert(ContentType::from(mime_guess::from_path(&file_name).first_or_octet_stream()));
+    if let Some(range) = range {
+        headers.typed_insert(ContentRange::bytes(range, file_size).map_err(|_| ApiError::InternalError)?);
+    }
+    if let Some(disposition) = content_disposition(&file_name) {
+        headers.insert(header::CONTENT_DISPOSITION, disposition);
+    }
+
+    Ok(response)
+}
+
+pub async fn upload_files(
+    user: User,
+    credential: Credential,
+    state: State<AppState>,
+    dir: Option<Path<String>>,
+    Query(params): Query<UploadParams>,
+    mut multipart: Multipart,
+) -> Result<Json<Vec<UploadedFile>>> {
+    if !credential.allows(TokenScope::Upload) {
+        return Err(ApiError::PermissionDenied);
+    }
+
+    let file_dir = to_file_dir(dir.as_ref().map_or("", |Path(dir)| dir));
+    let mut uploaded_files = Vec::new();
+    while let Some(field) = multipart.next_field().await.map_err(|e| ApiError::InvalidArgument(e.to_string()))? {
+        let file_name = match field.file_name().or(field.name()).filter(|name| !name.is_empty()) {
+            Some(file_name) => file_name.to_string(),
+            None => return Err(ApiError::InvalidArgument("missing file name".to_string())),
+        };
+
+        let spooled = spool(field, &state).await?;
+        let uploaded_file = store_spooled(
+            &state,
+            user.id,
+            &credential,
+            spooled,
+            file_dir.clone(),
+            file_name,
+            params.on_conflict,
+        )
+        .await?;
+        uploaded_files.push(uploaded_file);
+    }
+
+    Ok(Json(uploaded_files))
+}
+
+pub async fn put_file(
+    user: User,
+    credential: Credential,
+    state: State<AppState>,
+    Path(path): Path<String>,
+    Query(params): Query<UploadParams>,
+    body: Body,
+) -> Result<(StatusCode, Json<UploadedFile>)> {
+    if !credential.allows(TokenScope::Upload) {
+        return Err(ApiError::PermissionDenied);
+    }
+
+    let (file_dir, file_name) = split_file_path(&path)?;
+    let spooled = spool(body.into_data_stream(), &state).await?;
+    let uploaded_file = store_spooled(&state, user.id, &credential, spooled, file_dir, file_name, params.on_conflict).await?;
+    Ok((StatusCode::CREATED, Json(uploaded_file)))
+}
+
+pub async fn list_versions(
+    user: User,
+    credential: Credential,
+    state: State<AppState>,
+    Path(path): Path<String>,
+) -> Result<Json<Vec<FileVersion>>> {
+    if !credential.allows(TokenScope::Read) {
+        return Err(ApiError::PermissionDenied);
+    }
+
+    let (file_dir, file_name) = split_file_path(&path)?;
+    // a deleted file only has its versions left
+    let db = state.get_database();
+    let versions = db.query_file_versions(user.id, &file_dir, &file_name)?;
+    if versions.is_empty() && db.query_file_info(user.id, &file_dir, &file_name).is_none() {
+        return Err(ApiError::NotFound);
+    }
+    Ok(Json(versions))
+}
+
+pub async fn restore_version(
+    user: User,
+    credential: Credential,
+    state: State<AppState>,
+    Path(path): Path<String>,
+    Query(RestoreParams { version: version_id }): Query<RestoreParams>,
+) -> Result<Response> {
+    if !credential.allows(TokenScope::Upload) {
+        return Err(ApiError::PermissionDenied);
+    }
+
+    let (file_dir, file_name) = split_file_path(&path)?;
+    if !state
+        .get_database()
+        .restore_file_version(user.id, &file_dir, &file_name, version_id)?
+    {
+        return Err(ApiError::NotFound);
+    }
+
+    info!("restored version:{version_id} of:{file_dir}{file_name}, user:{}", user.id);
+    Ok(StatusCode::OK.into_response())
+}
+
+async fn spool<S, B, E>(stream: S, state: &AppState) -> Result<SpooledUpload>
+where
+    S: Stream<Item = core::result::Result<B, E>> + Unpin,
+    B: AsRef<[u8]>,
+    E: std::fmt::Display,
+{
+    let config = state.get_config();
+    SpooledUpload::spool(stream, config.max_upload_size, config.hash_algorithm)
+        .await
+        .map_err(|e| match e.downcast::<UploadRejected>() {
+            Ok(e) => {
+                info!("{e}");
+                match e {
+                    UploadRejected::TooLarge(_) => ApiError::PayloadTooLarge(e.to_string()),
+                    UploadRejected::Body(_) => ApiError::InvalidArgument(e.to_string()),
+                }
+            }
+            Err(e) => e.context("failed to spool upload").into(),
+        })
+}
+
+/// Applies the conflict policy if another file exists under the name, the same way uploads over websocket do
+async fn store_spooled(
+    state: &AppState,
+    user_id: u32,
+    credential: &Credential,
+    spooled: SpooledUpload,
+    file_dir: String,
+    mut file_name: String,
+    on_conflict: ConflictPolicy,
+) -> Result<UploadedFile> {
+    let mut storage_ctx = state.get_storage_context();
+    match storage_ctx.db.query_file_info(user_id, &file_dir, &file_name) {
+        Some(existing) if existing.file_hash != spooled.file_hash() || existing.file_size != spooled.file_size() => match on_conflict {
+            ConflictPolicy::Overwrite => {}
+            ConflictPolicy::KeepBoth => {
+                file_name = TransferTask::kept_copy_name(
+                    user_id,
+                    &storage_ctx,
+                    &file_dir,
+                    &file_name,
+                    spooled.file_hash(),
+                    spooled.file_size(),
+                )
+                .ok_or(ApiError::FileAlreadyExists)?;
+            }
+            ConflictPolicy::Reject => return Err(ApiError::FileAlreadyExists),
+        },
+        _ => {}
+    }
+
+    // the spooled content is written from the start, which would cut off an upload that stored part of it
+    let shared = storage_ctx.db.query_shared_file(spooled.file_hash());
+    if matches!(&shared, Some(shared) if shared.sync_size > 0 && shared.sync_size < shared.file_size) {
+        info!("content partially uploaded by another transfer:{}", spooled.file_hash());
+        return Err(ApiError::UploadInProgress);
+    }
+
+    let base_hash = storage_ctx
+        .db
+        .query_file_info(user_id, &file_dir, &file_name)
+        .map(|file_info| file_info.file_hash);
+    spooled
+        .store(
+            user_id,
+            credential.device(),
+            &mut storage_ctx,
+            &file_dir,
+            &file_name,
+            base_hash.as_deref(),
+        )
+        .await
+        .map_err(|e| match e.downcast::<BlobInUse>() {
+            Ok(e) => {
+                info!("{e}");
+                ApiError::UploadInProgress
+            }
+            Err(e) => e.into(),
+        })
+}
+
+/// Resolves the first range of the header against the file size
+fn satisfiable_range(range: &Range, file_size: u64) -> Option<RangeInclusive<u64>> {
+    let (start, end) = range.satisfiable_ranges(file_size).next()?;
+    let start = match start {
+        Bound::Included(start) => start,
+        Bound::Excluded(start) => start + 1,
+        Bound::Unbounded => 0,
+    };
+    let end = match end {
+        Bound::Included(end) => end.min(file_size.checked_sub(1)?),
+        Bound::Excluded(end) => end.min(file_size).checked_sub(1)?,
+        Bound::Unbounded => file_size.checked_sub(1)?,
+    };
+    (start <= end).then_some(start..=end)
+}
+
+fn content_disposition(file_name: &str) -> Option<HeaderValue> {
+    let ascii_name: String = file_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && c != '"' && c != '\\' || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let encoded_name: String = url::form_urlencoded::byte_serialize(file_name.as_bytes()).collect();
+    let value = format!(
+        "attachment; filename=\"{ascii_name}\"; filename*=UTF-8''{}",
+        encoded_name.replace('+', "%20")
+    );
+    HeaderValue::from_str(&value).ok()
+}
@@ -1,3 +1,4 @@
 pub mod auth;
 pub mod entity;
 pub mod file;
+pub mod token;
@@ -0,0 +1,87 @@
+use super::{
+    auth::{generate_token, hash_token},
+    entity::AppState,
+};
+use crate::{
+    result::{ApiError, Result},
+    server::entity::{ApiToken, Credential, TokenScope, User},
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+const API_TOKEN_PREFIX: &str = "rsd_";
+
+#[derive(Deserialize)]
+pub struct CreateTokenInfo {
+    name: String,
+    scopes: Vec<TokenScope>,
+    ttl_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct CreatedToken {
+    id: u32,
+    name: String,
+    scopes: Vec<TokenScope>,
+    /// only returned once, the server keeps nothing but its hash
+    token: String,
+}
+
+pub async fn create_token(
+    user: User,
+    credential: Credential,
+    state: State<AppState>,
+    token_info: Json<CreateTokenInfo>,
+) -> Result<Json<CreatedToken>> {
+    require_session(&credential)?;
+    if token_info.name.trim().is_empty() || token_info.scopes.is_empty() {
+        return Err(ApiError::InvalidArgument("name and scopes must not be empty".to_string()));
+    }
+
+    let token = format!("{API_TOKEN_PREFIX}{}", generate_token());
+    let api_token = ApiToken {
+        user_id: user.id,
+        name: token_info.name.trim().to_string(),
+        token_hash: hash_token(&token),
+        scopes: token_info.scopes.clone(),
+        ..Default::default()
+    };
+    let id = state.get_database().save_api_token(&api_token, token_info.ttl_secs)?;
+
+    info!("api token created, user:{}, id:{id}, scopes:{:?}", user.id, api_token.scopes);
+    Ok(Json(CreatedToken {
+        id,
+        name: api_token.name,
+        scopes: api_token.scopes,
+        token,
+    }))
+}
+
+pub async fn list_tokens(user: User, credential: Credential, state: State<AppState>) -> Result<Json<Vec<ApiToken>>> {
+    require_session(&credential)?;
+    Ok(Json(state.get_database().query_api_tokens(user.id)?))
+}
+
+pub async fn revoke_token(user: User, credential: Credential, state: State<AppState>, Path(id): Path<u32>) -> Result<Response> {
+    require_session(&credential)?;
+    if !state.get_database().delete_api_token(user.id, id)? {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+
+    info!("api token revoked, user:{}, id:{id}", user.id);
+    Ok(StatusCode::OK.into_response())
+}
+
+/// tokens can't mint or revoke other tokens or sessions, otherwise a leaked read-only token could escalate itself
+pub(crate) fn require_session(credential: &Credential) -> Result<()> {
+    match credential {
+        Credential::Session(_) => Ok(()),
+        Credential::ApiToken(_) => Err(ApiError::PermissionDenied),
+    }
+}
@@ -1,19 +1,20 @@
-use futures_util::{future, pin_mut, SinkExt, StreamExt};
-use rsdrive::common::entity::{TransferControlMessage, TransferRequest};
+use futures_util::{SinkExt, StreamExt};
+use rsdrive::common::{
+    entity::{ConflictPolicy, TransferControlMessage, TransferRequest},
+    frame::DataFrame,
+    hasher::HashAlgorithm,
+};
 use std::{
     env,
     fs::File,
     io::{BufReader, Read},
-    path::PathBuf,
-    sync::{Arc, Mutex},
 };
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncReadExt;
 use tokio_tungstenite::{
     connect_async,
     tungstenite::{http::Request, protocol::Message},
 };
 use tracing::{debug, info, Level};
-use tracing_subscriber::fmt::MakeWriter;
 
 #[tokio::main]
 async fn main() {
@@ -28,15 +29,16 @@ async fn main() {
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
     let addr = "ws://127.0.0.1:8080/api/ws";
+    let api_token = env::var("RSDRIVE_API_TOKEN").expect("RSDRIVE_API_TOKEN not set, create one with /api/tokens");
     let url = url::Url::parse(addr).unwrap();
 
-    let (stdin_tx, stdin_rx) = futures_channel::mpsc::unbounded();
+    let (stdin_tx, _stdin_rx) = futures_channel::mpsc::unbounded();
     tokio::spawn(read_stdin(stdin_tx));
 
     let request = Request::builder()
         .uri(addr)
         .header("Host", url.host_str().unwrap())
-        .header("Cookie", format!("auth-token={}", "1"))
+        .header("Authorization", format!("Bearer {api_token}"))
         .header("Upgrade", "websocket")
         .header("Connection", "Upgrade")
         .header("Sec-WebSocket-Key", "SomeKey") // Automatically generated by the library
@@ -54,10 +56,13 @@ async fn main() {
 
     info!(">>>>>>>>>> haha file:{}", f.metadata().unwrap().len());
     let transfer_request = TransferRequest {
-        file_hash: "TEST_FILE_HASH".to_string(),
+        stream_id: 1,
+        file_hash: HashAlgorithm::default().hash(&std::fs::read(filename).unwrap()),
         file_size: f.metadata().unwrap().len() as usize,
         file_name: "abc.jpg".to_string(),
         file_dir: "/sdcard/".to_string(),
+        file_meta: String::new(),
+        on_conflict: ConflictPolicy::default(),
     };
 
     sender.send(TransferControlMessage::Request(transfer_request).into()).await.unwrap();
@@ -67,6 +72,7 @@ async fn main() {
             info!(">>>>>>>>> haha recv text message:{msg}");
             let mut reader = BufReader::new(f);
             let mut buffer = [0u8; 4096];
+            let mut offset = 0u64;
 
             loop {
                 // Read a block from the file into the buffer
@@ -76,7 +82,11 @@ async fn main() {
                 }
                 std::thread::sleep(std::time::Duration::from_millis(1));
                 debug!(">>>>>>> sending:{bytes_read}");
-                sender.send(Message::binary(&buffer[..bytes_read])).await;
+                sender
+                    .send(Message::binary(DataFrame::encode(1, offset, &buffer[..bytes_read])))
+                    .await
+                    .unwrap();
+                offset += bytes_read as u64;
             }
         }
         e => {
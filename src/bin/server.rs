@@ -1,9 +1,10 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf};
 
 use axum::{
+    extract::DefaultBodyLimit,
     http::{Method, Request, Response, StatusCode, Uri},
     middleware,
-    routing::{get, get_service, post},
+    routing::{delete, get, get_service, post},
     Router,
 };
 use rsdrive::api::file;
@@ -12,7 +13,11 @@ use tower_cookies::CookieManagerLayer;
 use tower_http::services::ServeDir;
 use tracing::{info, Level};
 
-use rsdrive::api::{self, auth, entity::AppState};
+use rsdrive::{
+    api::{self, auth, entity::AppState, token},
+    server::config::ServerConfig,
+    storage::{garbage_collector::GarbageCollector, key_rotator::KeyRotator, tier_migrator::TierMigrator},
+};
 
 #[tokio::main]
 async fn main() {
@@ -26,15 +31,30 @@ async fn main() {
         .finish();
     tracing::subscriber::set_global_default(subscriber).unwrap();
 
-    let app_state = AppState::new();
-    let addr = "127.0.0.1:8080";
-    let router = Router::new()
+    let config = match std::env::args().nth(1) {
+        Some(path) => ServerConfig::load(path).unwrap(),
+        None => ServerConfig::default(),
+    };
+    let addr = config.addr.clone();
+    let static_dir = config.static_dir.clone();
+    let gc = GarbageCollector::new(config.gc.clone());
+    let migrator = TierMigrator::new(config.migration.clone());
+    let key_rotator = KeyRotator::new(config.key_rotation.clone());
+    let app_state = AppState::new(config).unwrap();
+    gc.start(app_state.get_storage_context());
+    migrator.start(app_state.get_storage_context());
+    key_rotator.start(app_state.get_storage_context());
+
+    let mut router = Router::new()
+        .route("/register", post(api::auth::register))
         .route("/login", post(api::auth::login))
         .nest("/api", api_router(app_state.clone()))
-        .layer(CookieManagerLayer::new())
-        .fallback_service(static_router())
-        .with_state(app_state);
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        .layer(CookieManagerLayer::new());
+    if let Some(static_dir) = static_dir {
+        router = router.fallback_service(static_router(static_dir));
+    }
+    let router = router.with_state(app_state);
+    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
     tracing::info!("Listening on {addr}");
 
     axum::serve(listener, router.into_make_service_with_connect_info::<SocketAddr>())
@@ -45,7 +65,19 @@ async fn main() {
 fn api_router(app_state: AppState) -> Router<AppState> {
     Router::new()
         .route("/hello", get(|| async { "hello" }))
+        .route("/logout", post(auth::logout))
+        .route("/logout_all", post(auth::logout_all))
+        .route("/tokens", get(token::list_tokens).post(token::create_token))
+        .route("/tokens/:id", delete(token::revoke_token))
         .route("/ws", get(file::ws_handler))
+        // file paths are wildcards, `/files/docs/2024/report.pdf` is report.pdf in the dir /docs/2024/
+        .route(
+            "/files/*path",
+            get(file::download_file).put(file::put_file).layer(DefaultBodyLimit::disable()),
+        )
+        .route("/versions/*path", get(file::list_versions).post(file::restore_version))
+        .route("/upload", post(file::upload_files).layer(DefaultBodyLimit::disable()))
+        .route("/upload/*dir", post(file::upload_files).layer(DefaultBodyLimit::disable()))
         .route_layer(
             ServiceBuilder::new()
                 .layer(middleware::from_fn_with_state(app_state.clone(), auth::user_resolver))
@@ -54,8 +86,8 @@ fn api_router(app_state: AppState) -> Router<AppState> {
         )
 }
 
-fn static_router() -> Router {
-    Router::new().nest_service("/", get_service(ServeDir::new("./")))
+fn static_router(static_dir: PathBuf) -> Router {
+    Router::new().nest_service("/", get_service(ServeDir::new(static_dir)))
 }
 
 async fn request_interceptor<Body>(uri: Uri, method: Method, request: Request<Body>) -> Result<Request<Body>, StatusCode> {
@@ -0,0 +1,279 @@
+use anyhow::{bail, Context, Result};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, SeekFrom},
+};
+
+use crate::common::{
+    crypto::{SecretKey, KEY_SIZE, NONCE_SIZE, SEAL_OVERHEAD},
+    hasher::HashAlgorithm,
+};
+
+/// Plaintext bytes per chunk, each chunk is sealed on its own so a transfer can resume at any chunk
+pub const E2E_CHUNK_SIZE: usize = 64 * 1024;
+const SEALED_CHUNK_SIZE: usize = E2E_CHUNK_SIZE + SEAL_OVERHEAD;
+
+/// Keys derived from the user's passphrase, none of them ever leaves the client. They cover the content of a file
+/// and its meta, the file's name and dir aren't encrypted.
+pub struct E2eKey {
+    /// keys the content hash, so equal content only dedups among files encrypted with the same passphrase
+    content_key: [u8; KEY_SIZE],
+    /// the data key of a file is derived from it and the file's content hash
+    file_key_seed: [u8; KEY_SIZE],
+    meta_key: SecretKey,
+}
+
+/// What the client needs to decrypt a file, it is stored on the server sealed with the user's meta key
+#[derive(Serialize, Deserialize, Debug)]
+pub struct E2eFileMeta {
+    /// keyed hash of the plaintext
+    pub content_hash: String,
+    /// size of the plaintext
+    pub file_size: usize,
+}
+
+impl E2eKey {
+    /// `salt` should be unique to the account, e.g. its user name. The same passphrase and salt always derive the same keys
+    pub fn derive(passphrase: &str, salt: &str) -> Result<Self> {
+        // argon2 wants a salt of at least 8 bytes
+        let salt = blake3::hash(salt.as_bytes());
+        let mut root = [0u8; KEY_SIZE];
+        if let Err(e) = Argon2::default().hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut root) {
+            bail!("failed to derive key: {e}");
+        }
+
+        Ok(Self {
+            content_key: blake3::derive_key("rsdrive e2e v1 content hash", &root),
+            file_key_seed: blake3::derive_key("rsdrive e2e v1 file key", &root),
+            meta_key: SecretKey::from_bytes(&blake3::derive_key("rsdrive e2e v1 file meta", &root))?,
+        })
+    }
+
+    /// Equal content gets the same key, which makes the ciphertext, and with it the blob, dedupable
+    fn file_key(&self, content_hash: &str) -> Result<SecretKey> {
+        SecretKey::from_bytes(blake3::keyed_hash(&self.file_key_seed, content_hash.as_bytes()).as_bytes())
+    }
+
+    /// The meta is bound to the blob, it can't be moved to another file
+    pub fn seal_meta(&self, meta: &E2eFileMeta, file_hash: &str) -> Result<String> {
+        let meta = serde_json::to_vec(meta)?;
+        Ok(hex::encode(self.meta_key.seal(&meta, file_hash.as_bytes())?))
+    }
+
+    pub fn open_meta(&self, file_meta: &str, file_hash: &str) -> Result<E2eFileMeta> {
+        let sealed = hex::decode(file_meta).context("file meta is not hex encoded")?;
+        let meta = self
+            .meta_key
+            .open(&sealed, file_hash.as_bytes())
+            .context("failed to open file meta, wrong passphrase?")?;
+        Ok(serde_json::from_slice(&meta)?)
+    }
+}
+
+impl std::fmt::Debug for E2eKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("E2eKey(..)")
+    }
+}
+
+fn chunk_count(file_size: usize) -> usize {
+    // an empty file still has one (empty) chunk, so a truncated blob can always be told apart
+    file_size.div_ceil(E2E_CHUNK_SIZE).max(1)
+}
+
+/// Size of the blob the server gets for a plaintext of `file_size`
+pub fn encrypted_size(file_size: usize) -> usize {
+    file_size + chunk_count(file_size) * SEAL_OVERHEAD
+}
+
+fn chunk_aad(index: usize, is_last: bool) -> [u8; 9] {
+    let mut aad = [0u8; 9];
+    aad[..8].copy_from_slice(&(index as u64).to_be_bytes());
+    aad[8] = is_last as u8;
+    aad
+}
+
+/// Deterministic so equal files encrypt to equal blobs, but derived from the plaintext as well, a file that changed
+/// while being read mustn't get a nonce that was already used for other content under the same key
+fn chunk_nonce(file_key: &SecretKey, index: usize, plaintext: &[u8]) -> Result<[u8; NONCE_SIZE]> {
+    let mut hasher = blake3::Hasher::new_keyed(file_key.as_bytes().try_into()?);
+    hasher.update(&(index as u64).to_be_bytes());
+    hasher.update(plaintext);
+
+    let mut nonce = [0u8; NONCE_SIZE];
+    hasher.finalize_xof().fill(&mut nonce);
+    Ok(nonce)
+}
+
+/// Reads a file as the sequence of its sealed chunks
+pub struct EncryptedFile {
+    file: File,
+    file_key: SecretKey,
+    meta: E2eFileMeta,
+    file_hash: String,
+    chunk: Vec<u8>,
+    chunk_pos: usize,
+    next_index: usize,
+}
+
+impl EncryptedFile {
+    /// Reads `path` twice, once for the content hash and once for the hash of the ciphertext the server will verify
+    pub async fn open(key: &E2eKey, path: &Path, hash_algorithm: HashAlgorithm) -> Result<Self> {
+        let mut file = File::open(path).await?;
+        let mut content_hasher = blake3::Hasher::new_keyed(&key.content_key);
+        let mut buf = vec![0u8; E2E_CHUNK_SIZE];
+        let mut file_size = 0;
+        loop {
+            let len = file.read(&mut buf).await?;
+            if len == 0 {
+                break;
+            }
+            content_hasher.update(&buf[..len]);
+            file_size += len;
+        }
+
+        let content_hash = content_hasher.finalize().to_hex().to_string();
+        let mut encrypted = Self {
+            file,
+            file_key: key.file_key(&content_hash)?,
+            meta: E2eFileMeta { content_hash, file_size },
+            file_hash: String::new(),
+            chunk: vec![],
+            chunk_pos: 0,
+            next_index: 0,
+        };
+
+        encrypted.seek(0).await?;
+        let mut hasher = hash_algorithm.hasher();
+        loop {
+            let len = encrypted.read(&mut buf).await?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+        }
+        encrypted.file_hash = hasher.finalize();
+        Ok(encrypted)
+    }
+
+    pub fn file_hash(&self) -> &str {
+        &self.file_hash
+    }
+
+    pub fn meta(&self) -> &E2eFileMeta {
+        &self.meta
+    }
+
+    pub fn encrypted_size(&self) -> usize {
+        encrypted_size(self.meta.file_size)
+    }
+
+    /// `offset` is into the ciphertext
+    pub async fn seek(&mut self, offset: usize) -> Result<()> {
+        let index = offset / SEALED_CHUNK_SIZE;
+        self.file.seek(SeekFrom::Start((index * E2E_CHUNK_SIZE) as u64)).await?;
+        self.next_index = index;
+        self.chunk.clear();
+        self.chunk_pos = 0;
+        if index < chunk_count(self.meta.file_size) {
+            self.read_chunk().await?;
+            self.chunk_pos = (offset % SEALED_CHUNK_SIZE).min(self.chunk.len());
+        }
+        Ok(())
+    }
+
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.chunk_pos == self.chunk.len() {
+            if self.next_index >= chunk_count(self.meta.file_size) {
+                return Ok(0);
+            }
+            self.read_chunk().await?;
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.chunk_pos);
+        buf[..len].copy_from_slice(&self.chunk[self.chunk_pos..self.chunk_pos + len]);
+        self.chunk_pos += len;
+        Ok(len)
+    }
+
+    async fn read_chunk(&mut self) -> Result<()> {
+        let index = self.next_index;
+        let len = E2E_CHUNK_SIZE.min(self.meta.file_size - index * E2E_CHUNK_SIZE);
+        let mut plaintext = vec![0u8; len];
+        if self.file.read_exact(&mut plaintext).await.is_err() {
+            bail!("file changed while encrypting, chunk:{index}");
+        }
+
+        let is_last = index + 1 == chunk_count(self.meta.file_size);
+        let nonce = chunk_nonce(&self.file_key, index, &plaintext)?;
+        self.chunk = self.file_key.seal_with_nonce(&nonce, &plaintext, &chunk_aad(index, is_last))?;
+        self.chunk_pos = 0;
+        self.next_index += 1;
+        Ok(())
+    }
+}
+
+/// Turns the sealed chunks of a download back into plaintext
+pub struct E2eDecryptor {
+    file_key: SecretKey,
+    file_size: usize,
+    next_index: usize,
+    pending: Vec<u8>,
+}
+
+impl E2eDecryptor {
+    pub fn new(key: &E2eKey, meta: &E2eFileMeta, start_index: usize) -> Result<Self> {
+        Ok(Self {
+            file_key: key.file_key(&meta.content_hash)?,
+            file_size: meta.file_size,
+            next_index: start_index,
+            pending: vec![],
+        })
+    }
+
+    /// Where a download has to resume to get the chunk `plaintext_size` falls into
+    pub fn resume_point(plaintext_size: usize) -> (usize, usize) {
+        let index = plaintext_size / E2E_CHUNK_SIZE;
+        (index, index * SEALED_CHUNK_SIZE)
+    }
+
+    /// Returns the plaintext of the chunks completed by `data`
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.pending.extend_from_slice(data);
+
+        let mut plaintext = vec![];
+        let chunk_count = chunk_count(self.file_size);
+        while self.next_index < chunk_count {
+            let index = self.next_index;
+            let sealed_len = E2E_CHUNK_SIZE.min(self.file_size - index * E2E_CHUNK_SIZE) + SEAL_OVERHEAD;
+            if self.pending.len() < sealed_len {
+                break;
+            }
+
+            let is_last = index + 1 == chunk_count;
+            let chunk = self
+                .file_key
+                .open(&self.pending[..sealed_len], &chunk_aad(index, is_last))
+                .context(format!("failed to decrypt chunk:{index}"))?;
+            plaintext.extend_from_slice(&chunk);
+            self.pending.drain(..sealed_len);
+            self.next_index += 1;
+        }
+
+        if self.next_index == chunk_count && !self.pending.is_empty() {
+            bail!("trailing data after the last chunk:{}", self.pending.len());
+        }
+        Ok(plaintext)
+    }
+
+    /// Fails unless every chunk up to the last one was decrypted
+    pub fn finish(&self) -> Result<()> {
+        if self.next_index != chunk_count(self.file_size) {
+            bail!("encrypted file is truncated, chunk:{}", self.next_index);
+        }
+        Ok(())
+    }
+}
@@ -1,26 +1,101 @@
 use anyhow::{bail, Context, Result};
 use futures_util::{SinkExt, StreamExt};
 use rs_utilities::log_and_bail;
-use tokio::net::TcpStream;
+use std::{ops::Range, path::Path};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom},
+    net::TcpStream,
+};
 use tokio_tungstenite::{
     connect_async,
     tungstenite::{http::Request, Message},
     MaybeTlsStream, WebSocketStream,
 };
-use tracing::error;
+use tracing::{debug, error};
+
+use super::e2e::{self, E2eDecryptor, E2eKey, EncryptedFile, E2E_CHUNK_SIZE};
+use crate::common::{
+    chunker::ChunkSplitter,
+    delta::{DeltaEncoder, DeltaOp},
+    entity::{
+        ChunkOffer, DeleteRequest, DeltaCopy, DownloadRequest, FileVersion, RestoreRequest, TransferControlMessage, TransferRequest,
+        VersionListRequest, WindowUpdate,
+    },
+    frame::{DataFrame, INITIAL_WINDOW_SIZE},
+    hasher::HashAlgorithm,
+};
 
-use crate::common::entity::{TransferRequest, TransferResponse};
+const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+const DELTA_READ_SIZE: usize = 1024 * 1024;
 
+type ClientStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Runs one transfer at a time on its connection. The server multiplexes streams, so a client may interleave
+/// transfers on one connection, but this uploader waits for each transfer to end before starting the next.
 pub struct FileUploader {
-    stream: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    stream: Option<ClientStream>,
+    last_stream_id: u32,
+    /// set in E2E mode, the server then only sees the ciphertext and sealed meta of a file, not its plaintext
+    e2e_key: Option<E2eKey>,
+    /// must match the server's, in E2E mode the uploader hashes the ciphertext itself
+    hash_algorithm: HashAlgorithm,
+}
+
+/// What an upload sends, the ciphertext of the file in E2E mode
+enum UploadSource {
+    Plain(File),
+    Encrypted(EncryptedFile),
+}
+
+impl UploadSource {
+    async fn seek(&mut self, offset: usize) -> Result<()> {
+        match self {
+            UploadSource::Plain(file) => {
+                file.seek(SeekFrom::Start(offset as u64)).await?;
+            }
+            UploadSource::Encrypted(file) => file.seek(offset).await?,
+        }
+        Ok(())
+    }
+
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(match self {
+            UploadSource::Plain(file) => file.read(buf).await?,
+            UploadSource::Encrypted(file) => file.read(buf).await?,
+        })
+    }
+}
+
+impl Default for FileUploader {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FileUploader {
     pub fn new() -> Self {
-        Self { stream: None }
+        Self {
+            stream: None,
+            last_stream_id: 0,
+            e2e_key: None,
+            hash_algorithm: HashAlgorithm::default(),
+        }
     }
 
-    pub async fn connect(&mut self, addr: &str) -> Result<()> {
+    /// Files are encrypted before they leave the client and decrypted after they are downloaded, files uploaded
+    /// with a different key (or none) can't be downloaded. Only the content is encrypted, file names and dirs are
+    /// sent as they are since the server lists, versions and resolves conflicts by path, and sizes are only padded
+    /// by the per-chunk overhead.
+    pub fn with_e2e(key: E2eKey, hash_algorithm: HashAlgorithm) -> Self {
+        Self {
+            e2e_key: Some(key),
+            hash_algorithm,
+            ..Self::new()
+        }
+    }
+
+    pub async fn connect(&mut self, addr: &str, api_token: &str) -> Result<()> {
         // let addr = "ws://127.0.0.1:8080/api/ws";
         let url = url::Url::parse(addr)?;
 
@@ -30,7 +105,7 @@ impl FileUploader {
         let request = Request::builder()
             .uri(addr)
             .header("Host", url.host_str().unwrap())
-            .header("Cookie", format!("auth-token={}", "123"))
+            .header("Authorization", format!("Bearer {api_token}"))
             .header("Upgrade", "websocket")
             .header("Connection", "Upgrade")
             .header("Sec-WebSocket-Key", "SomeKey") // Automatically generated by the library
@@ -45,37 +120,472 @@ impl FileUploader {
         Ok(())
     }
 
-    pub async fn upload(&mut self, req: TransferRequest) -> Result<()> {
-        if let Some(stream) = &mut self.stream {
-            let req_json = serde_json::to_string(&req)?;
-            stream.send(Message::Text(req_json)).await?;
-            let resp = match stream.next().await.with_context(|| "failed to receive from socket")? {
-                Ok(Message::Text(text)) => serde_json::from_str::<TransferResponse>(text.as_str())?,
+    /// Uploads `src`, resuming from whatever the server already has. The connection stays open for more files.
+    /// In E2E mode the hash, size and meta of `req` are replaced with those of the ciphertext
+    pub async fn upload(&mut self, mut req: TransferRequest, src: &Path) -> Result<()> {
+        let mut source = self.open_source(&mut req, src).await?;
+        let stream_id = self.next_stream_id();
+        let stream = self.stream.as_mut().context("not connected")?;
+        let file_size = req.file_size;
+        req.stream_id = stream_id;
+        stream.send(TransferControlMessage::Request(req).into()).await?;
+
+        let resp = match Self::recv_control_message(stream).await? {
+            TransferControlMessage::Response(resp) => resp,
+            TransferControlMessage::Exists(resp) => {
+                debug!("content already stored:{}", resp.file_hash);
+                return Ok(());
+            }
+            TransferControlMessage::Error(e) => {
+                log_and_bail!("upload rejected: {}", e.message);
+            }
+            msg => {
+                log_and_bail!("unexpected response: {msg:?}");
+            }
+        };
+
+        let mut window = INITIAL_WINDOW_SIZE;
+        let range = resp.sync_size..file_size;
+        Self::send_range(stream, stream_id, &mut source, range, &mut window)
+            .await
+            .with_context(|| format!("failed to upload:{src:?}"))?;
+        Self::recv_completed(stream, file_size).await
+    }
+
+    /// Uploads `src` as content-defined chunks, only the chunks the server doesn't have yet are sent, so a small
+    /// change to a large file costs about a chunk. In E2E mode any change re-keys the whole file, its chunks only
+    /// dedup with identical files.
+    pub async fn upload_chunked(&mut self, mut req: TransferRequest, src: &Path) -> Result<()> {
+        let mut source = self.open_source(&mut req, src).await?;
+        // the ciphertext was already read once to hash it
+        source.seek(0).await?;
+        let mut splitter = ChunkSplitter::new(self.hash_algorithm);
+        let mut buf = vec![0u8; UPLOAD_CHUNK_SIZE];
+        let mut read_size = 0;
+        loop {
+            let len = source.read(&mut buf).await?;
+            if len == 0 {
+                break;
+            }
+            splitter.update(&buf[..len]);
+            read_size += len;
+        }
+        if read_size != req.file_size {
+            bail!("file changed while uploading:{src:?}, {read_size}/{}", req.file_size);
+        }
+
+        let chunks = splitter.finish();
+        let stream_id = self.next_stream_id();
+        let stream = self.stream.as_mut().context("not connected")?;
+        let file_size = req.file_size;
+        req.stream_id = stream_id;
+        let offer = ChunkOffer {
+            file: req,
+            chunks: chunks.clone(),
+        };
+        stream.send(TransferControlMessage::ChunkOffer(offer).into()).await?;
+
+        let need = match Self::recv_control_message(stream).await? {
+            TransferControlMessage::ChunkNeed(need) => need,
+            TransferControlMessage::Exists(resp) => {
+                debug!("content already stored:{}", resp.file_hash);
+                return Ok(());
+            }
+            TransferControlMessage::Error(e) => {
+                log_and_bail!("upload rejected: {}", e.message);
+            }
+            msg => {
+                log_and_bail!("unexpected response: {msg:?}");
+            }
+        };
+        debug!("sending {} of {} chunks:{}", need.missing.len(), chunks.len(), need.file_hash);
+
+        let mut offsets = Vec::with_capacity(chunks.len());
+        let mut offset = 0;
+        for chunk in &chunks {
+            offsets.push(offset);
+            offset += chunk.size;
+        }
+
+        let mut window = INITIAL_WINDOW_SIZE;
+        for index in need.missing {
+            let Some(chunk) = chunks.get(index) else {
+                log_and_bail!("server asked for an unknown chunk:{index}");
+            };
+            let range = offsets[index]..offsets[index] + chunk.size;
+            Self::send_range(stream, stream_id, &mut source, range, &mut window)
+                .await
+                .with_context(|| format!("failed to upload:{src:?}"))?;
+        }
+        Self::recv_completed(stream, file_size).await
+    }
+
+    /// Updates the file at the destination of `req` by sending only what differs from the version the server has,
+    /// blocks found unchanged (even if moved) are copied on the server. A file the server doesn't have yet is sent
+    /// as a whole. In E2E mode any change re-keys the whole ciphertext, so nothing is saved.
+    pub async fn upload_delta(&mut self, mut req: TransferRequest, src: &Path) -> Result<()> {
+        let mut source = self.open_source(&mut req, src).await?;
+        // the ciphertext was already read once to hash it
+        source.seek(0).await?;
+        let stream_id = self.next_stream_id();
+        let stream = self.stream.as_mut().context("not connected")?;
+        let file_size = req.file_size;
+        req.stream_id = stream_id;
+        stream.send(TransferControlMessage::DeltaRequest(req).into()).await?;
+
+        let signatures = match Self::recv_control_message(stream).await? {
+            TransferControlMessage::DeltaSignatures(signatures) => signatures,
+            TransferControlMessage::Exists(resp) => {
+                debug!("content already stored:{}", resp.file_hash);
+                return Ok(());
+            }
+            TransferControlMessage::Error(e) => {
+                log_and_bail!("upload rejected: {}", e.message);
+            }
+            msg => {
+                log_and_bail!("unexpected response: {msg:?}");
+            }
+        };
+        if signatures.block_size == 0 {
+            log_and_bail!("invalid block size from server");
+        }
+        debug!(
+            "updating:{}, blocks:{}, block size:{}",
+            signatures.base_hash,
+            signatures.blocks.len(),
+            signatures.block_size
+        );
+
+        let block_size = signatures.block_size;
+        let mut encoder = DeltaEncoder::new(self.hash_algorithm, block_size, &signatures.blocks);
+        let mut window = INITIAL_WINDOW_SIZE;
+        let mut offset = 0;
+        let mut read_size = 0;
+        let mut buf = vec![0u8; DELTA_READ_SIZE];
+        loop {
+            let len = source.read(&mut buf).await?;
+            if len == 0 {
+                break;
+            }
+            read_size += len;
+            let ops = encoder.update(&buf[..len]);
+            Self::send_delta(stream, stream_id, block_size, ops, &mut offset, &mut window)
+                .await
+                .with_context(|| format!("failed to upload:{src:?}"))?;
+        }
+        Self::send_delta(stream, stream_id, block_size, encoder.finish(), &mut offset, &mut window)
+            .await
+            .with_context(|| format!("failed to upload:{src:?}"))?;
+        if read_size != file_size || offset != file_size {
+            bail!("file changed while uploading:{src:?}, {read_size}/{file_size}");
+        }
+        Self::recv_completed(stream, file_size).await
+    }
+
+    /// Block references go as control messages, they don't count against the window
+    async fn send_delta(
+        stream: &mut ClientStream,
+        stream_id: u32,
+        block_size: usize,
+        ops: Vec<DeltaOp>,
+        offset: &mut usize,
+        window: &mut usize,
+    ) -> Result<()> {
+        for op in ops {
+            match op {
+                DeltaOp::Copy { block_index, count } => {
+                    let copy = DeltaCopy {
+                        stream_id,
+                        offset: *offset,
+                        block_index,
+                        count,
+                    };
+                    stream.send(TransferControlMessage::DeltaCopy(copy).into()).await?;
+                    *offset += count * block_size;
+                }
+                DeltaOp::Literal(data) => {
+                    Self::send_data(stream, stream_id, *offset, &data, window).await?;
+                    *offset += data.len();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn open_source(&self, req: &mut TransferRequest, src: &Path) -> Result<UploadSource> {
+        Ok(match &self.e2e_key {
+            Some(key) => {
+                let encrypted = EncryptedFile::open(key, src, self.hash_algorithm).await?;
+                req.file_hash = encrypted.file_hash().to_string();
+                req.file_size = encrypted.encrypted_size();
+                req.file_meta = key.seal_meta(encrypted.meta(), &req.file_hash)?;
+                UploadSource::Encrypted(encrypted)
+            }
+            None => UploadSource::Plain(File::open(src).await?),
+        })
+    }
+
+    /// Sends `range` of the source, waiting for window updates whenever the window is used up. Frames never
+    /// cross the end of the range.
+    async fn send_range(
+        stream: &mut ClientStream,
+        stream_id: u32,
+        source: &mut UploadSource,
+        range: Range<usize>,
+        window: &mut usize,
+    ) -> Result<()> {
+        source.seek(range.start).await?;
+        let mut buf = vec![0u8; UPLOAD_CHUNK_SIZE];
+        let mut sent_size = range.start;
+        while sent_size < range.end {
+            Self::wait_window(stream, stream_id, window).await?;
+            let len = UPLOAD_CHUNK_SIZE.min(*window).min(range.end - sent_size);
+            let len = source.read(&mut buf[..len]).await?;
+            if len == 0 {
+                bail!("file changed while uploading, {sent_size}/{}", range.end);
+            }
+            stream
+                .send(Message::Binary(DataFrame::encode(stream_id, sent_size as u64, &buf[..len])))
+                .await?;
+            sent_size += len;
+            *window -= len;
+        }
+        Ok(())
+    }
+
+    /// Sends `data` as the content starting at `offset`, as the window allows
+    async fn send_data(stream: &mut ClientStream, stream_id: u32, offset: usize, data: &[u8], window: &mut usize) -> Result<()> {
+        let mut sent_size = 0;
+        while sent_size < data.len() {
+            Self::wait_window(stream, stream_id, window).await?;
+            let len = UPLOAD_CHUNK_SIZE.min(*window).min(data.len() - sent_size);
+            let frame = DataFrame::encode(stream_id, (offset + sent_size) as u64, &data[sent_size..sent_size + len]);
+            stream.send(Message::Binary(frame)).await?;
+            sent_size += len;
+            *window -= len;
+        }
+        Ok(())
+    }
+
+    async fn wait_window(stream: &mut ClientStream, stream_id: u32, window: &mut usize) -> Result<()> {
+        while *window == 0 {
+            match Self::recv_control_message(stream).await? {
+                TransferControlMessage::Window(update) if update.stream_id == stream_id => *window += update.size,
+                TransferControlMessage::Error(e) => {
+                    log_and_bail!("upload failed: {}", e.message);
+                }
+                msg => {
+                    log_and_bail!("unexpected response: {msg:?}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn recv_completed(stream: &mut ClientStream, file_size: usize) -> Result<()> {
+        loop {
+            match Self::recv_control_message(stream).await? {
+                TransferControlMessage::Window(_) => {}
+                TransferControlMessage::Completed(resp) => {
+                    debug!("upload completed:{}, {}/{file_size}", resp.file_hash, resp.sync_size);
+                    return Ok(());
+                }
+                TransferControlMessage::Error(e) => {
+                    log_and_bail!("upload failed: {}", e.message);
+                }
+                msg => {
+                    log_and_bail!("unexpected response: {msg:?}");
+                }
+            }
+        }
+    }
+
+    /// Downloads the file into `dest`, resuming after whatever `dest` already contains
+    pub async fn download(&mut self, file_dir: &str, file_name: &str, dest: &Path) -> Result<()> {
+        let stream_id = self.next_stream_id();
+        let stream = self.stream.as_mut().context("not connected")?;
+        let dest_size = tokio::fs::metadata(dest).await.map(|m| m.len() as usize).unwrap_or(0);
+        // ciphertext can only be decrypted in whole chunks, a partial chunk at the end of `dest` is fetched again
+        let (start_index, offset) = match self.e2e_key {
+            Some(_) => E2eDecryptor::resume_point(dest_size),
+            None => (0, dest_size),
+        };
+        let req = DownloadRequest {
+            stream_id,
+            file_dir: file_dir.to_string(),
+            file_name: file_name.to_string(),
+            offset,
+        };
+        stream.send(TransferControlMessage::Download(req).into()).await?;
+
+        let resp = match Self::recv_control_message(stream).await? {
+            TransferControlMessage::DownloadResponse(resp) => resp,
+            TransferControlMessage::Error(e) => {
+                log_and_bail!("download rejected: {}", e.message);
+            }
+            msg => {
+                log_and_bail!("unexpected response: {msg:?}");
+            }
+        };
+
+        let mut decryptor = match &self.e2e_key {
+            Some(key) => {
+                if resp.file_meta.is_empty() {
+                    log_and_bail!("file is not end-to-end encrypted:{file_dir}{file_name}");
+                }
+                let meta = key.open_meta(&resp.file_meta, &resp.file_hash)?;
+                if e2e::encrypted_size(meta.file_size) != resp.file_size {
+                    log_and_bail!("encrypted size mismatch:{}, {}/{}", resp.file_hash, meta.file_size, resp.file_size);
+                }
+                Some(E2eDecryptor::new(key, &meta, start_index)?)
+            }
+            None => None,
+        };
+
+        let mut file = OpenOptions::new().create(true).append(true).open(dest).await?;
+        if decryptor.is_some() {
+            file.set_len((start_index * E2E_CHUNK_SIZE) as u64).await?;
+        }
+        let mut recv_size = resp.offset;
+        let mut consumed = 0;
+        while recv_size < resp.file_size {
+            match stream.next().await.with_context(|| "connection closed while downloading")? {
+                Ok(Message::Binary(data)) => {
+                    let frame = DataFrame::decode(&data).context("malformed data frame")?;
+                    if frame.stream_id != stream_id || frame.offset != recv_size as u64 {
+                        log_and_bail!("unexpected frame, stream:{}, offset:{}", frame.stream_id, frame.offset);
+                    }
+                    match &mut decryptor {
+                        Some(decryptor) => file.write_all(&decryptor.push(frame.data)?).await?,
+                        None => file.write_all(frame.data).await?,
+                    }
+                    recv_size += frame.data.len();
+
+                    consumed += frame.data.len();
+                    if consumed >= INITIAL_WINDOW_SIZE / 2 {
+                        let update = WindowUpdate {
+                            stream_id,
+                            size: std::mem::take(&mut consumed),
+                        };
+                        stream.send(TransferControlMessage::Window(update).into()).await?;
+                    }
+                }
+                Ok(Message::Text(text)) => {
+                    log_and_bail!("unexpected message while downloading: {text}");
+                }
                 Err(e) => {
                     log_and_bail!("failed to receive message: {e}");
                 }
+                _ => {}
+            }
+        }
+        file.flush().await?;
+        if let Some(decryptor) = &decryptor {
+            decryptor.finish()?;
+        }
+
+        debug!("download completed:{}, {recv_size}/{}", resp.file_hash, resp.file_size);
+        Ok(())
+    }
+
+    /// Returns false if the server had no such file. Its content is kept as a version, see `restore_version`
+    pub async fn delete(&mut self, file_dir: &str, file_name: &str) -> Result<bool> {
+        let stream = self.stream.as_mut().context("not connected")?;
+        let req = DeleteRequest {
+            file_dir: file_dir.to_string(),
+            file_name: file_name.to_string(),
+        };
+        stream.send(TransferControlMessage::Delete(req).into()).await?;
+
+        match stream.next().await.with_context(|| "failed to receive from socket")? {
+            Ok(Message::Text(text)) => match TransferControlMessage::try_from(text.as_str()) {
+                Ok(TransferControlMessage::Deleted(resp)) => Ok(resp.deleted),
+                Ok(TransferControlMessage::Error(e)) => {
+                    log_and_bail!("delete rejected: {}", e.message);
+                }
                 _ => {
-                    log_and_bail!("unexpected respnose");
+                    log_and_bail!("unexpected response: {text}");
                 }
-            };
+            },
+            Err(e) => {
+                log_and_bail!("failed to receive message: {e}");
+            }
+            _ => {
+                log_and_bail!("unexpected response");
+            }
+        }
+    }
 
-            while let Some(message) = stream.next().await {
-                match message {
-                    Ok(Message::Text(text)) => {}
-                    Err(e) => {
-                        eprintln!("Error receiving message: {}", e);
-                        break;
+    /// The file's prior versions, newest first
+    pub async fn list_versions(&mut self, file_dir: &str, file_name: &str) -> Result<Vec<FileVersion>> {
+        let stream = self.stream.as_mut().context("not connected")?;
+        let req = VersionListRequest {
+            file_dir: file_dir.to_string(),
+            file_name: file_name.to_string(),
+        };
+        stream.send(TransferControlMessage::ListVersions(req).into()).await?;
+
+        match Self::recv_control_message(stream).await? {
+            TransferControlMessage::Versions(list) => Ok(list.versions),
+            TransferControlMessage::Error(e) => {
+                log_and_bail!("listing versions rejected: {}", e.message);
+            }
+            msg => {
+                log_and_bail!("unexpected response: {msg:?}");
+            }
+        }
+    }
+
+    /// Returns false if the file had no such version. In E2E mode the version keeps the meta it was sealed with,
+    /// so it downloads as it was uploaded.
+    pub async fn restore_version(&mut self, file_dir: &str, file_name: &str, version_id: u32) -> Result<bool> {
+        let stream = self.stream.as_mut().context("not connected")?;
+        let req = RestoreRequest {
+            file_dir: file_dir.to_string(),
+            file_name: file_name.to_string(),
+            version_id,
+        };
+        stream.send(TransferControlMessage::Restore(req).into()).await?;
+
+        match Self::recv_control_message(stream).await? {
+            TransferControlMessage::Restored(resp) => Ok(resp.restored),
+            TransferControlMessage::Error(e) => {
+                log_and_bail!("restore rejected: {}", e.message);
+            }
+            msg => {
+                log_and_bail!("unexpected response: {msg:?}");
+            }
+        }
+    }
+
+    fn next_stream_id(&mut self) -> u32 {
+        self.last_stream_id = self.last_stream_id.wrapping_add(1);
+        self.last_stream_id
+    }
+
+    async fn recv_control_message(stream: &mut ClientStream) -> Result<TransferControlMessage> {
+        loop {
+            match stream.next().await.with_context(|| "failed to receive from socket")? {
+                Ok(Message::Text(text)) => match TransferControlMessage::try_from(text.as_str()) {
+                    Ok(msg) => return Ok(msg),
+                    Err(_) => {
+                        log_and_bail!("invalid message: {text}");
                     }
-                    _ => {}
+                },
+                Ok(Message::Binary(data)) => {
+                    log_and_bail!("unexpected binary data:{}", data.len());
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log_and_bail!("failed to receive message: {e}");
                 }
             }
         }
-        Ok(())
     }
 
     pub async fn close(&mut self) {
         if let Some(stream) = &mut self.stream {
-            stream.close(None).await;
+            let _ = stream.close(None).await;
         }
     }
 }
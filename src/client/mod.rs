@@ -1 +1,2 @@
+pub mod e2e;
 pub mod file_uploader;
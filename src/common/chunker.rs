@@ -0,0 +1,175 @@
+use super::{entity::ChunkInfo, hasher::HashAlgorithm};
+
+/// Clients and the server have to cut chunks at the same boundaries for their chunks to dedup, changing any of
+/// these or the gear table makes new chunks miss all those stored before
+pub const MIN_CHUNK_SIZE: usize = 64 * 1024;
+pub const AVG_CHUNK_SIZE: usize = 256 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Cutting is harder before the average size and easier after it, which narrows the spread of chunk sizes
+const MASK_HARD: u64 = mask(AVG_CHUNK_SIZE.trailing_zeros() + 2);
+const MASK_EASY: u64 = mask(AVG_CHUNK_SIZE.trailing_zeros() - 2);
+const GEAR: [u64; 256] = gear_table();
+
+/// The top bits of the rolling hash depend on the most bytes, so those are the ones checked
+const fn mask(bits: u32) -> u64 {
+    ((1u64 << bits) - 1) << (64 - bits)
+}
+
+/// Random but fixed values, generated with splitmix64
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// FastCDC, returns the length of the chunk `data` starts with. `data` has to hold at least `MAX_CHUNK_SIZE` bytes
+/// unless it's the end of the stream, or the cut may not be where it would be with more data.
+pub fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let normal = end.min(AVG_CHUNK_SIZE);
+    let mut hash = 0u64;
+    for (i, byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let mask = if i < normal { MASK_HARD } else { MASK_EASY };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// Splits a stream into content-defined chunks as it's fed, hashing each of them
+pub struct ChunkSplitter {
+    hash_algorithm: HashAlgorithm,
+    buf: Vec<u8>,
+    chunks: Vec<ChunkInfo>,
+}
+
+impl ChunkSplitter {
+    pub fn new(hash_algorithm: HashAlgorithm) -> Self {
+        Self {
+            hash_algorithm,
+            buf: Vec::with_capacity(2 * MAX_CHUNK_SIZE),
+            chunks: vec![],
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        while self.buf.len() >= MAX_CHUNK_SIZE {
+            self.split_chunk();
+        }
+    }
+
+    /// Returns the chunks in stream order, an empty stream has none
+    pub fn finish(mut self) -> Vec<ChunkInfo> {
+        while !self.buf.is_empty() {
+            self.split_chunk();
+        }
+        self.chunks
+    }
+
+    fn split_chunk(&mut self) {
+        let len = cut_point(&self.buf);
+        self.chunks.push(ChunkInfo {
+            hash: self.hash_algorithm.hash(&self.buf[..len]),
+            size: len,
+        });
+        self.buf.drain(..len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic noise, so the cut points can be pinned
+    fn test_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn split(data: &[u8], piece_size: usize) -> Vec<ChunkInfo> {
+        let mut splitter = ChunkSplitter::new(HashAlgorithm::Sha256);
+        for piece in data.chunks(piece_size) {
+            splitter.update(piece);
+        }
+        splitter.finish()
+    }
+
+    #[test]
+    fn cut_points_are_pinned() {
+        // stored chunks only dedup with new ones as long as these stay the same
+        let chunks = split(&test_data(4 * 1024 * 1024, 1), MAX_CHUNK_SIZE);
+        let sizes: Vec<usize> = chunks.iter().map(|chunk| chunk.size).collect();
+        assert_eq!(
+            sizes,
+            [268282, 367960, 274157, 426495, 344399, 291115, 287099, 251174, 303042, 269920, 295658, 357793, 248160, 209050]
+        );
+    }
+
+    #[test]
+    fn chunks_cover_the_stream_within_the_size_bounds() {
+        let data = test_data(3 * 1024 * 1024 + 12345, 2);
+        let chunks = split(&data, 100_000);
+        assert_eq!(chunks.iter().map(|chunk| chunk.size).sum::<usize>(), data.len());
+        let (last, rest) = chunks.split_last().unwrap();
+        assert!(rest.iter().all(|chunk| (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&chunk.size)));
+        assert!(last.size <= MAX_CHUNK_SIZE);
+
+        let mut pos = 0;
+        for chunk in &chunks {
+            assert_eq!(chunk.hash, HashAlgorithm::Sha256.hash(&data[pos..pos + chunk.size]));
+            pos += chunk.size;
+        }
+    }
+
+    #[test]
+    fn cuts_dont_depend_on_how_the_stream_is_fed() {
+        let data = test_data(2 * 1024 * 1024 + 1, 3);
+        let whole = split(&data, data.len());
+        assert_eq!(split(&data, 4096), whole);
+        assert_eq!(split(&data, MAX_CHUNK_SIZE + 1), whole);
+    }
+
+    #[test]
+    fn an_insertion_only_changes_the_chunks_around_it() {
+        let data = test_data(4 * 1024 * 1024, 4);
+        let mut edited = data[..1000].to_vec();
+        edited.extend_from_slice(b"inserted");
+        edited.extend_from_slice(&data[1000..]);
+
+        let original = split(&data, MAX_CHUNK_SIZE);
+        let changed = split(&edited, MAX_CHUNK_SIZE);
+        assert_ne!(original[0], changed[0]);
+        assert_eq!(original[1..], changed[1..]);
+    }
+
+    #[test]
+    fn short_streams_are_a_single_chunk() {
+        assert!(split(&[], 10).is_empty());
+        assert_eq!(cut_point(&test_data(MIN_CHUNK_SIZE, 5)), MIN_CHUNK_SIZE);
+        assert_eq!(split(&test_data(1000, 5), 10).len(), 1);
+    }
+}
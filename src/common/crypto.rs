@@ -0,0 +1,77 @@
+use anyhow::{bail, Context, Result};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::{rngs::OsRng, RngCore};
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 24;
+pub const TAG_SIZE: usize = 16;
+/// Bytes `seal` adds to the plaintext, the random nonce in front and the tag at the end
+pub const SEAL_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+/// A XChaCha20-Poly1305 key, the nonces are random and long enough to never collide
+#[derive(Clone)]
+pub struct SecretKey([u8; KEY_SIZE]);
+
+impl SecretKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_SIZE];
+        OsRng.fill_bytes(&mut key);
+        Self(key)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let key: [u8; KEY_SIZE] = bytes.try_into().context(format!("key must be {KEY_SIZE} bytes"))?;
+        Ok(Self(key))
+    }
+
+    pub fn from_hex(hex_key: &str) -> Result<Self> {
+        Self::from_bytes(&hex::decode(hex_key.trim()).context("key is not hex encoded")?)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Encrypts `plaintext` bound to `aad`, returns nonce || ciphertext || tag
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        self.seal_with_nonce(&nonce, plaintext, aad)
+    }
+
+    /// Like `seal` but deterministic, the caller must never reuse a nonce for different plaintexts under the same key
+    pub fn seal_with_nonce(&self, nonce: &[u8; NONCE_SIZE], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let cipher = XChaCha20Poly1305::new(self.0.as_slice().into());
+        let Ok(ciphertext) = cipher.encrypt(XNonce::from_slice(nonce), Payload { msg: plaintext, aad }) else {
+            bail!("failed to encrypt");
+        };
+
+        let mut sealed = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Reverses `seal`, fails if the data or `aad` was tampered with or the key is wrong
+    pub fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < SEAL_OVERHEAD {
+            bail!("sealed data too short:{}", sealed.len());
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+        let cipher = XChaCha20Poly1305::new(self.0.as_slice().into());
+        let Ok(plaintext) = cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad }) else {
+            bail!("failed to decrypt, wrong key or corrupted data");
+        };
+        Ok(plaintext)
+    }
+}
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
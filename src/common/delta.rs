@@ -0,0 +1,323 @@
+use super::{entity::BlockSignature, hasher::HashAlgorithm};
+use std::collections::HashMap;
+
+const MIN_BLOCK_SIZE: usize = 2 * 1024;
+/// keeps the signatures of a huge file within a reasonably sized message
+const MAX_BLOCK_COUNT: usize = 64 * 1024;
+/// the new content is verified against its hash as a whole, so a shortened hash is enough to tell blocks apart
+const STRONG_HASH_LEN: usize = 32;
+/// literal data is handed out once this much is pending
+const MAX_LITERAL_SIZE: usize = 256 * 1024;
+
+/// Around the square root of the file size, which balances the size of the signatures against the literal data
+/// sent for each change, as rsync does
+pub fn block_size(file_size: usize) -> usize {
+    file_size.isqrt().max(file_size.div_ceil(MAX_BLOCK_COUNT)).max(MIN_BLOCK_SIZE)
+}
+
+pub fn signature(hash_algorithm: HashAlgorithm, block: &[u8]) -> BlockSignature {
+    BlockSignature {
+        weak: RollingChecksum::new(block).digest(),
+        strong: strong_hash(hash_algorithm, block),
+    }
+}
+
+fn strong_hash(hash_algorithm: HashAlgorithm, block: &[u8]) -> String {
+    let mut hash = hash_algorithm.hash(block);
+    hash.truncate(STRONG_HASH_LEN);
+    hash
+}
+
+/// rsync's weak checksum, it can be moved along the data a byte at a time
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn new(block: &[u8]) -> Self {
+        let mut checksum = Self {
+            a: 0,
+            b: 0,
+            len: block.len() as u32,
+        };
+        for (i, byte) in block.iter().enumerate() {
+            checksum.a = checksum.a.wrapping_add(*byte as u32);
+            checksum.b = checksum.b.wrapping_add(((block.len() - i) as u32).wrapping_mul(*byte as u32));
+        }
+        checksum
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+
+    /// Drops `out` from the front of the window and appends `next` to its end
+    fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DeltaOp {
+    /// `count` consecutive blocks of the current content, starting at `block_index`
+    Copy {
+        block_index: usize,
+        count: usize,
+    },
+    Literal(Vec<u8>),
+}
+
+/// Finds the blocks of the current content in the new content as it's fed, only whole blocks are matched
+pub struct DeltaEncoder {
+    hash_algorithm: HashAlgorithm,
+    block_size: usize,
+    /// block indexes by weak checksum
+    blocks: HashMap<u32, Vec<usize>>,
+    strong: Vec<String>,
+    buf: Vec<u8>,
+    /// where the window starts in `buf`
+    pos: usize,
+    /// where the literal data not handed out yet starts in `buf`, it runs up to `pos`
+    literal_start: usize,
+    /// checksum of the window, None after a match moved the window by a whole block
+    checksum: Option<RollingChecksum>,
+    /// a copy that may still be extended by the next block, it comes before the pending literal data
+    pending_copy: Option<(usize, usize)>,
+}
+
+impl DeltaEncoder {
+    pub fn new(hash_algorithm: HashAlgorithm, block_size: usize, signatures: &[BlockSignature]) -> Self {
+        let mut blocks: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (index, signature) in signatures.iter().enumerate() {
+            blocks.entry(signature.weak).or_default().push(index);
+        }
+
+        Self {
+            hash_algorithm,
+            block_size,
+            blocks,
+            strong: signatures.iter().map(|signature| signature.strong.clone()).collect(),
+            buf: Vec::new(),
+            pos: 0,
+            literal_start: 0,
+            checksum: None,
+            pending_copy: None,
+        }
+    }
+
+    /// Returns the ops for as much of the content fed so far as can be decided
+    pub fn update(&mut self, data: &[u8]) -> Vec<DeltaOp> {
+        self.buf.extend_from_slice(data);
+        let mut ops = vec![];
+        if self.strong.is_empty() {
+            self.pos = self.buf.len();
+            self.flush_literal(&mut ops);
+        }
+
+        while self.buf.len() - self.pos >= self.block_size {
+            let window = &self.buf[self.pos..self.pos + self.block_size];
+            let digest = self.checksum.get_or_insert_with(|| RollingChecksum::new(window)).digest();
+            if let Some(index) = self.find_block(digest) {
+                self.flush_literal(&mut ops);
+                self.pending_copy = match self.pending_copy.take() {
+                    Some((block_index, count)) if block_index + count == index => Some((block_index, count + 1)),
+                    pending => {
+                        ops.extend(pending.map(|(block_index, count)| DeltaOp::Copy { block_index, count }));
+                        Some((index, 1))
+                    }
+                };
+                self.pos += self.block_size;
+                self.literal_start = self.pos;
+                self.checksum = None;
+                continue;
+            }
+
+            // the window can only move on once the byte after it has arrived
+            if self.buf.len() - self.pos == self.block_size {
+                break;
+            }
+            if let Some(checksum) = &mut self.checksum {
+                checksum.roll(self.buf[self.pos], self.buf[self.pos + self.block_size]);
+            }
+            self.pos += 1;
+            if self.pos - self.literal_start >= MAX_LITERAL_SIZE {
+                self.flush_literal(&mut ops);
+            }
+        }
+
+        self.buf.drain(..self.literal_start);
+        self.pos -= self.literal_start;
+        self.literal_start = 0;
+        ops
+    }
+
+    /// The content that didn't make up a whole block at the end is sent as literal data
+    pub fn finish(mut self) -> Vec<DeltaOp> {
+        let mut ops = vec![];
+        self.pos = self.buf.len();
+        self.flush_literal(&mut ops);
+        ops.extend(
+            self.pending_copy
+                .take()
+                .map(|(block_index, count)| DeltaOp::Copy { block_index, count }),
+        );
+        ops
+    }
+
+    /// Blocks with equal content are interchangeable, the one continuing the pending copy is preferred
+    fn find_block(&self, digest: u32) -> Option<usize> {
+        let candidates = self.blocks.get(&digest)?;
+        let strong = strong_hash(self.hash_algorithm, &self.buf[self.pos..self.pos + self.block_size]);
+        let next = self.pending_copy.map(|(block_index, count)| block_index + count);
+        let mut found = None;
+        for index in candidates.iter().copied().filter(|index| self.strong[*index] == strong) {
+            if Some(index) == next {
+                return Some(index);
+            }
+            found.get_or_insert(index);
+        }
+        found
+    }
+
+    fn flush_literal(&mut self, ops: &mut Vec<DeltaOp>) {
+        if self.pos == self.literal_start {
+            return;
+        }
+        ops.extend(
+            self.pending_copy
+                .take()
+                .map(|(block_index, count)| DeltaOp::Copy { block_index, count }),
+        );
+        ops.push(DeltaOp::Literal(self.buf[self.literal_start..self.pos].to_vec()));
+        self.literal_start = self.pos;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    fn test_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// Only whole blocks of the current content are signed, as the server does
+    fn signatures(base: &[u8]) -> Vec<BlockSignature> {
+        base.chunks_exact(BLOCK_SIZE)
+            .map(|block| signature(HashAlgorithm::Sha256, block))
+            .collect()
+    }
+
+    fn encode(base: &[u8], new: &[u8], piece_size: usize) -> Vec<DeltaOp> {
+        let mut encoder = DeltaEncoder::new(HashAlgorithm::Sha256, BLOCK_SIZE, &signatures(base));
+        let mut ops = vec![];
+        for piece in new.chunks(piece_size) {
+            ops.extend(encoder.update(piece));
+        }
+        ops.extend(encoder.finish());
+        ops
+    }
+
+    fn apply(base: &[u8], ops: &[DeltaOp]) -> Vec<u8> {
+        let mut data = vec![];
+        for op in ops {
+            match op {
+                DeltaOp::Copy { block_index, count } => {
+                    data.extend_from_slice(&base[block_index * BLOCK_SIZE..(block_index + count) * BLOCK_SIZE])
+                }
+                DeltaOp::Literal(literal) => data.extend_from_slice(literal),
+            }
+        }
+        data
+    }
+
+    fn literal_size(ops: &[DeltaOp]) -> usize {
+        ops.iter()
+            .map(|op| match op {
+                DeltaOp::Literal(literal) => literal.len(),
+                DeltaOp::Copy { .. } => 0,
+            })
+            .sum()
+    }
+
+    #[test]
+    fn rolling_matches_a_fresh_checksum() {
+        let data = test_data(1000, 1);
+        let mut checksum = RollingChecksum::new(&data[..100]);
+        for pos in 0..900 {
+            checksum.roll(data[pos], data[pos + 100]);
+            assert_eq!(checksum.digest(), RollingChecksum::new(&data[pos + 1..pos + 101]).digest());
+        }
+    }
+
+    #[test]
+    fn unchanged_content_is_copied_in_one_op() {
+        let base = test_data(50 * BLOCK_SIZE + 100, 2);
+        let ops = encode(&base, &base, 10_000);
+        assert_eq!(
+            ops,
+            [
+                DeltaOp::Copy { block_index: 0, count: 50 },
+                DeltaOp::Literal(base[50 * BLOCK_SIZE..].to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn edits_round_trip_and_only_send_what_changed() {
+        let base = test_data(100 * BLOCK_SIZE, 3);
+        let mut new = base[..10 * BLOCK_SIZE + 7].to_vec();
+        // an insertion, a deletion and a block moved to the end
+        new.extend_from_slice(b"inserted");
+        new.extend_from_slice(&base[10 * BLOCK_SIZE + 7..40 * BLOCK_SIZE]);
+        new.extend_from_slice(&base[45 * BLOCK_SIZE..]);
+        new.extend_from_slice(&base[..BLOCK_SIZE]);
+        new[70 * BLOCK_SIZE] ^= 0xff;
+
+        let ops = encode(&base, &new, 3000);
+        assert_eq!(apply(&base, &ops), new);
+        // each edit costs about a block
+        assert!(literal_size(&ops) < 3 * BLOCK_SIZE, "literal size:{}", literal_size(&ops));
+    }
+
+    #[test]
+    fn ops_dont_depend_on_how_the_content_is_fed() {
+        let base = test_data(20 * BLOCK_SIZE, 4);
+        let mut new = test_data(1000, 5);
+        new.extend_from_slice(&base[3 * BLOCK_SIZE..]);
+        let whole = encode(&base, &new, new.len());
+        assert_eq!(encode(&base, &new, 1), whole);
+        assert_eq!(encode(&base, &new, BLOCK_SIZE + 1), whole);
+    }
+
+    #[test]
+    fn everything_is_literal_without_current_content() {
+        let new = test_data(MAX_LITERAL_SIZE + 1000, 6);
+        let ops = encode(&[], &new, 10_000);
+        assert!(ops
+            .iter()
+            .all(|op| matches!(op, DeltaOp::Literal(literal) if literal.len() <= MAX_LITERAL_SIZE)));
+        assert_eq!(apply(&[], &ops), new);
+    }
+
+    #[test]
+    fn block_size_grows_with_the_file() {
+        assert_eq!(block_size(0), MIN_BLOCK_SIZE);
+        assert_eq!(block_size(100 * 1024 * 1024), 10240);
+        let huge = 1 << 40;
+        assert!(huge / block_size(huge) <= MAX_BLOCK_COUNT);
+    }
+}
@@ -5,29 +5,238 @@ use tokio_tungstenite::tungstenite;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct TransferRequest {
+    pub stream_id: u32,
     pub file_hash: String,
     pub file_size: usize,
     pub file_name: String,
     pub file_dir: String,
+    /// opaque to the server, stored with the file and handed back on download
+    #[serde(default)]
+    pub file_meta: String,
+    /// what happens if another file exists under the name, delta requests always update the file
+    #[serde(default)]
+    pub on_conflict: ConflictPolicy,
+}
+
+/// How an upload treats another file (different content) already stored under its name
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// the file gets the new content once it's completely received, the old content is kept as a version
+    #[default]
+    Overwrite,
+    /// the new content is stored under the first free name like "report (1).pdf"
+    KeepBoth,
+    Reject,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ChunkInfo {
+    pub hash: String,
+    pub size: usize,
+}
+
+/// Offers a file as its content-defined chunks, in file order, the server asks for the ones it doesn't have yet
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ChunkOffer {
+    #[serde(flatten)]
+    pub file: TransferRequest,
+    pub chunks: Vec<ChunkInfo>,
+}
+
+/// The chunks to be sent, as indexes into the offered chunks and in the order they are expected. Each is sent
+/// as data frames at its offset in the file, a frame never crosses into the next chunk.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct ChunkNeed {
+    pub stream_id: u32,
+    pub file_hash: String,
+    pub missing: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct BlockSignature {
+    /// rolling checksum of the block
+    pub weak: u32,
+    /// truncated content hash of the block, only checked when the weak checksum matches
+    pub strong: String,
+}
+
+/// Signatures of the whole blocks of the file's current content, in file order. None are sent if there is no
+/// current content, the update then consists of literal data only.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct DeltaSignatures {
+    pub stream_id: u32,
+    /// the content being updated, the update fails if the file no longer has it once the new content is received
+    pub base_hash: String,
+    pub block_size: usize,
+    pub blocks: Vec<BlockSignature>,
+}
+
+/// Copies `count` blocks of the current content, starting at `block_index`, to `offset` of the new content.
+/// Literal data in between is sent as data frames.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct DeltaCopy {
+    pub stream_id: u32,
+    pub offset: usize,
+    pub block_index: usize,
+    pub count: usize,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct TransferResponse {
+    pub stream_id: u32,
     pub file_hash: String,
     pub sync_size: usize,
+    /// the name the file is stored under, not the requested one if both files were kept
+    #[serde(default)]
+    pub file_name: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct DownloadRequest {
+    pub stream_id: u32,
+    pub file_dir: String,
+    pub file_name: String,
+    /// resume point, bytes before it are skipped
+    #[serde(default)]
+    pub offset: usize,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct DownloadResponse {
+    pub stream_id: u32,
+    pub file_hash: String,
+    pub file_size: usize,
+    pub offset: usize,
+    #[serde(default)]
+    pub file_meta: String,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct DeleteRequest {
+    pub file_dir: String,
+    pub file_name: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct DeleteResponse {
+    pub file_dir: String,
+    pub file_name: String,
+    /// false if there was no such file
+    pub deleted: bool,
+}
+
+/// A prior content of a file, kept when the file's content was replaced or the file was deleted
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct FileVersion {
+    pub id: u32,
     pub file_hash: String,
+    pub file_size: usize,
+    pub file_meta: String,
+    /// what uploaded the content, the name of the API token used or "web" for a login session
+    pub device: String,
+    /// when the content was uploaded
+    pub create_time: String,
+    /// when it was replaced
+    pub archive_time: String,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct VersionListRequest {
+    pub file_dir: String,
+    pub file_name: String,
+}
+
+/// Newest first, the current content isn't listed
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct VersionList {
+    pub file_dir: String,
+    pub file_name: String,
+    pub versions: Vec<FileVersion>,
+}
+
+/// Makes a prior version the file's content again, the content it replaces becomes a version itself
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct RestoreRequest {
+    pub file_dir: String,
+    pub file_name: String,
+    pub version_id: u32,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct RestoreResponse {
+    pub file_dir: String,
+    pub file_name: String,
+    pub version_id: u32,
+    /// false if the file had no such version
+    pub restored: bool,
+}
+
+/// Grants the peer `size` more bytes of data frames on the stream
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct WindowUpdate {
+    pub stream_id: u32,
+    pub size: usize,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct TransferError {
+    /// absent for errors that aren't tied to a stream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_id: Option<u32>,
+    pub message: String,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub enum TransferControlMessage {
     Request(TransferRequest),
     Response(TransferResponse),
-    Delete(TransferResponse),
-    Error(String),
+    /// the content is already stored, nothing needs to be sent
+    Exists(TransferResponse),
+    /// all bytes of the file were received and verified
+    Completed(TransferResponse),
+    ChunkOffer(ChunkOffer),
+    ChunkNeed(ChunkNeed),
+    /// updates an existing file with only what differs from its current content
+    DeltaRequest(TransferRequest),
+    DeltaSignatures(DeltaSignatures),
+    DeltaCopy(DeltaCopy),
+    Download(DownloadRequest),
+    DownloadResponse(DownloadResponse),
+    Delete(DeleteRequest),
+    Deleted(DeleteResponse),
+    ListVersions(VersionListRequest),
+    Versions(VersionList),
+    Restore(RestoreRequest),
+    Restored(RestoreResponse),
+    Window(WindowUpdate),
+    Error(TransferError),
+}
+
+impl TransferControlMessage {
+    /// The stream the message belongs to, None for requests that aren't tied to a stream
+    pub fn stream_id(&self) -> Option<u32> {
+        match self {
+            TransferControlMessage::Request(req) | TransferControlMessage::DeltaRequest(req) => Some(req.stream_id),
+            TransferControlMessage::Response(resp) | TransferControlMessage::Exists(resp) | TransferControlMessage::Completed(resp) => {
+                Some(resp.stream_id)
+            }
+            TransferControlMessage::ChunkOffer(offer) => Some(offer.file.stream_id),
+            TransferControlMessage::ChunkNeed(need) => Some(need.stream_id),
+            TransferControlMessage::DeltaSignatures(signatures) => Some(signatures.stream_id),
+            TransferControlMessage::DeltaCopy(copy) => Some(copy.stream_id),
+            TransferControlMessage::Download(req) => Some(req.stream_id),
+            TransferControlMessage::DownloadResponse(resp) => Some(resp.stream_id),
+            TransferControlMessage::Window(update) => Some(update.stream_id),
+            TransferControlMessage::Error(e) => e.stream_id,
+            TransferControlMessage::Delete(_)
+            | TransferControlMessage::Deleted(_)
+            | TransferControlMessage::ListVersions(_)
+            | TransferControlMessage::Versions(_)
+            | TransferControlMessage::Restore(_)
+            | TransferControlMessage::Restored(_) => None,
+        }
+    }
 }
 
 impl From<TransferControlMessage> for ws::Message {
@@ -45,6 +254,6 @@ impl From<TransferControlMessage> for tungstenite::Message {
 impl TryFrom<&str> for TransferControlMessage {
     type Error = ();
     fn try_from(text: &str) -> Result<Self, ()> {
-        Ok(serde_json::from_str::<TransferControlMessage>(text).map_err(|e| ())?)
+        serde_json::from_str::<TransferControlMessage>(text).map_err(|_| ())
     }
 }
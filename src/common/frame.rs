@@ -0,0 +1,71 @@
+/// stream_id(u32) + offset(u64), big endian
+pub const FRAME_HEADER_SIZE: usize = 12;
+
+/// Credit every stream starts with, receivers hand out more with `Window` messages as they consume data
+pub const INITIAL_WINDOW_SIZE: usize = 1024 * 1024;
+
+/// Binary WebSocket messages carry a chunk of one stream at an explicit file offset, so chunks
+/// of concurrent transfers can interleave on the same connection
+#[derive(Debug, PartialEq)]
+pub struct DataFrame<'a> {
+    pub stream_id: u32,
+    pub offset: u64,
+    pub data: &'a [u8],
+}
+
+impl<'a> DataFrame<'a> {
+    pub fn encode(stream_id: u32, offset: u64, data: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(FRAME_HEADER_SIZE + data.len());
+        buf.extend_from_slice(&stream_id.to_be_bytes());
+        buf.extend_from_slice(&offset.to_be_bytes());
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    pub fn decode(buf: &'a [u8]) -> Option<Self> {
+        if buf.len() < FRAME_HEADER_SIZE {
+            return None;
+        }
+
+        let (stream_id, rest) = buf.split_at(4);
+        let (offset, data) = rest.split_at(8);
+        Some(Self {
+            stream_id: u32::from_be_bytes(stream_id.try_into().ok()?),
+            offset: u64::from_be_bytes(offset.try_into().ok()?),
+            data,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let buf = DataFrame::encode(7, 1 << 40, b"payload");
+        assert_eq!(buf.len(), FRAME_HEADER_SIZE + 7);
+        assert_eq!(
+            DataFrame::decode(&buf),
+            Some(DataFrame {
+                stream_id: 7,
+                offset: 1 << 40,
+                data: b"payload",
+            })
+        );
+    }
+
+    #[test]
+    fn header_is_big_endian() {
+        let buf = DataFrame::encode(0x01020304, 0x05, b"");
+        assert_eq!(buf, [1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 5]);
+    }
+
+    #[test]
+    fn an_empty_frame_is_valid_but_a_short_one_is_not() {
+        let buf = DataFrame::encode(1, 2, b"");
+        assert_eq!(DataFrame::decode(&buf).map(|frame| frame.data.len()), Some(0));
+        assert_eq!(DataFrame::decode(&buf[..FRAME_HEADER_SIZE - 1]), None);
+        assert_eq!(DataFrame::decode(&[]), None);
+    }
+}
@@ -1 +1,6 @@
+pub mod chunker;
+pub mod crypto;
+pub mod delta;
 pub mod entity;
+pub mod frame;
+pub mod hasher;
@@ -1,7 +1,6 @@
 use axum::{http::StatusCode, response::IntoResponse};
 use serde::Serialize;
-use serde_with::skip_serializing_none;
-use std::fmt::Display;
+use tracing::error;
 
 pub type Result<T> = core::result::Result<T, ApiError>;
 
@@ -9,22 +8,40 @@ pub type Result<T> = core::result::Result<T, ApiError>;
 pub enum ApiError {
     IncorrectCrecidentials,
     NotAuthenticated,
+    PermissionDenied,
+    NotFound,
+    InvalidArgument(String),
+    PayloadTooLarge(String),
+    UserAlreadyExists,
+    FileAlreadyExists,
+    /// the content is being uploaded by another transfer
+    UploadInProgress,
+    InternalError,
 }
 
 impl IntoResponse for ApiError {
     fn into_response(self) -> axum::response::Response {
         let mut response = match self {
-            ApiError::IncorrectCrecidentials | ApiError::NotAuthenticated => {
-                StatusCode::UNAUTHORIZED
-            }
-            _ => StatusCode::OK,
-        }
-        .into_response();
+            ApiError::IncorrectCrecidentials | ApiError::NotAuthenticated => StatusCode::UNAUTHORIZED.into_response(),
+            ApiError::PermissionDenied => StatusCode::FORBIDDEN.into_response(),
+            ApiError::NotFound => StatusCode::NOT_FOUND.into_response(),
+            ApiError::InvalidArgument(ref msg) => (StatusCode::BAD_REQUEST, msg.clone()).into_response(),
+            ApiError::PayloadTooLarge(ref msg) => (StatusCode::PAYLOAD_TOO_LARGE, msg.clone()).into_response(),
+            ApiError::UserAlreadyExists | ApiError::FileAlreadyExists | ApiError::UploadInProgress => StatusCode::CONFLICT.into_response(),
+            ApiError::InternalError => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
         response.extensions_mut().insert(self);
         response
     }
 }
 
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        error!("{e:?}");
+        ApiError::InternalError
+    }
+}
+
 // #[derive(Clone, Debug, Serialize, strum_macros::AsRefStr)]
 // pub enum ResultType {}
 
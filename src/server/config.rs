@@ -0,0 +1,60 @@
+use crate::{
+    common::hasher::HashAlgorithm,
+    storage::{
+        database_manager::DatabaseConfig, garbage_collector::GcConfig, key_rotator::KeyRotationConfig, storage_registry::StorageConfig,
+        tier_migrator::MigrationConfig,
+    },
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub addr: String,
+    pub database: DatabaseConfig,
+    pub storage_dir: PathBuf,
+    pub storage: StorageConfig,
+    pub max_upload_size: u64,
+    pub hash_algorithm: HashAlgorithm,
+    pub session_ttl_secs: u64,
+    /// Marks the session cookie Secure, for when clients reach the server over HTTPS, e.g. through a TLS terminating
+    /// proxy. The server itself only speaks plain HTTP, and browsers don't send Secure cookies over it.
+    pub secure_cookies: bool,
+    /// Directory served to unauthenticated clients for paths outside the API, nothing is served when unset. It must
+    /// not contain the database or storage_dir.
+    pub static_dir: Option<PathBuf>,
+    pub gc: GcConfig,
+    pub migration: MigrationConfig,
+    pub key_rotation: KeyRotationConfig,
+}
+
+impl ServerConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).context(format!("failed to read config:{path:?}"))?;
+        serde_json::from_str(&content).context(format!("failed to parse config:{path:?}"))
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:8080".to_string(),
+            database: DatabaseConfig {
+                uri: "./rsdrive.db".to_string(),
+            },
+            storage_dir: PathBuf::from("./shared_files"),
+            storage: StorageConfig::default(),
+            max_upload_size: 16 * 1024 * 1024 * 1024,
+            hash_algorithm: HashAlgorithm::default(),
+            session_ttl_secs: 7 * 24 * 3600,
+            secure_cookies: false,
+            static_dir: None,
+            gc: GcConfig::default(),
+            migration: MigrationConfig::default(),
+            key_rotation: KeyRotationConfig::default(),
+        }
+    }
+}
@@ -4,12 +4,90 @@ use serde::{Deserialize, Serialize};
 pub struct User {
     pub id: u32,
     pub username: String,
+    /// PHC-formatted argon2 hash, never the plaintext password
+    #[serde(skip_serializing)]
     pub password: String,
     pub phone_number: String,
     pub email: String,
     pub create_time: String,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Session {
+    pub token_hash: String,
+    pub user_id: u32,
+    pub create_time: String,
+    pub expire_time: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    Read,
+    Upload,
+    Delete,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct ApiToken {
+    pub id: u32,
+    pub user_id: u32,
+    pub name: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub scopes: Vec<TokenScope>,
+    pub create_time: String,
+    /// UTC, None if the token never expires
+    pub expire_time: Option<String>,
+}
+
+/// The token hash is left out, it's as good as the token for looking up its user
+impl std::fmt::Debug for ApiToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ApiToken")
+            .field("id", &self.id)
+            .field("user_id", &self.user_id)
+            .field("name", &self.name)
+            .field("scopes", &self.scopes)
+            .field("create_time", &self.create_time)
+            .field("expire_time", &self.expire_time)
+            .finish_non_exhaustive()
+    }
+}
+
+/// How the current request was authenticated, sessions are granted every scope
+#[derive(Clone)]
+pub enum Credential {
+    Session(String),
+    ApiToken(ApiToken),
+}
+
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Credential::Session(_) => f.write_str("Session(..)"),
+            Credential::ApiToken(token) => f.debug_tuple("ApiToken").field(token).finish(),
+        }
+    }
+}
+
+impl Credential {
+    pub fn allows(&self, scope: TokenScope) -> bool {
+        match self {
+            Credential::Session(_) => true,
+            Credential::ApiToken(token) => token.scopes.contains(&scope),
+        }
+    }
+
+    /// What uploads are attributed to in the version history, API tokens are usually issued per device
+    pub fn device(&self) -> &str {
+        match self {
+            Credential::Session(_) => "web",
+            Credential::ApiToken(token) => &token.name,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UploadEntity {
     pub file_pos: usize,
@@ -26,4 +104,32 @@ pub struct SyncFileInfo {
     pub file_name: String,
     pub file_dir: String,
     pub file_meta: String,
+    /// Name of the storage backend holding the blob
+    pub storage_backend: String,
+    /// How the blob is encoded by its backend, empty if it's stored as is
+    pub codec: String,
+    /// Stored as the chunks listed in its manifest rather than as a blob of its own
+    pub chunked: bool,
+    /// What uploaded the file's content, see `Credential::device`
+    pub device: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credential_debug_leaves_out_token_hashes() {
+        let session = Credential::Session("session-hash".to_string());
+        let token = Credential::ApiToken(ApiToken {
+            id: 3,
+            name: "laptop".to_string(),
+            token_hash: "token-hash".to_string(),
+            ..Default::default()
+        });
+        assert!(!format!("{session:?}").contains("session-hash"));
+        let token = format!("{token:?}");
+        assert!(!token.contains("token-hash"));
+        assert!(token.contains("laptop"));
+    }
 }
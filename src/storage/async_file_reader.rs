@@ -0,0 +1,126 @@
+use super::file_storage::FileReader;
+use futures::future::BoxFuture;
+use std::{
+    io::{self, SeekFrom},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The op owns the reader while it runs and hands it back when done
+type PendingOp<T> = BoxFuture<'static, (Box<dyn FileReader>, anyhow::Result<T>)>;
+
+enum State {
+    /// None only if a pending op was dropped halfway, which can't happen while the adapter is alive
+    Idle(Option<Box<dyn FileReader>>),
+    Reading(PendingOp<Vec<u8>>),
+    Seeking(PendingOp<u64>),
+}
+
+/// `AsyncRead`/`AsyncSeek` over a `FileReader`, so storage backends can be streamed with tokio's io utilities.
+/// The reader is expected to be at the start of the blob.
+pub struct AsyncFileReader {
+    state: State,
+    /// bytes read beyond what the caller's buffer could take
+    leftover: Vec<u8>,
+    pos: u64,
+    len: u64,
+}
+
+impl AsyncFileReader {
+    pub fn new(reader: Box<dyn FileReader>) -> Self {
+        Self {
+            len: reader.len(),
+            state: State::Idle(Some(reader)),
+            leftover: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub async fn close(mut self) {
+        if let Ok(mut reader) = self.take_reader() {
+            reader.close().await;
+        }
+    }
+
+    fn take_reader(&mut self) -> io::Result<Box<dyn FileReader>> {
+        match &mut self.state {
+            State::Idle(reader) => reader.take().ok_or_else(|| io::Error::other("reader is gone")),
+            _ => Err(io::Error::other("another operation is in progress")),
+        }
+    }
+
+    fn put_leftover(&mut self, buf: &mut ReadBuf<'_>) {
+        let len = self.leftover.len().min(buf.remaining());
+        buf.put_slice(&self.leftover[..len]);
+        self.leftover.drain(..len);
+        self.pos += len as u64;
+    }
+}
+
+impl AsyncRead for AsyncFileReader {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.state {
+                State::Idle(_) => {
+                    if !this.leftover.is_empty() || buf.remaining() == 0 {
+                        this.put_leftover(buf);
+                        return Poll::Ready(Ok(()));
+                    }
+
+                    let mut reader = this.take_reader()?;
+                    let len = buf.remaining().min(READ_CHUNK_SIZE);
+                    this.state = State::Reading(Box::pin(async move {
+                        let mut data = vec![0u8; len];
+                        let result = reader.read(&mut data).await.map(|n| {
+                            data.truncate(n);
+                            data
+                        });
+                        (reader, result)
+                    }));
+                }
+                State::Reading(op) => {
+                    let (reader, result) = ready!(op.as_mut().poll(cx));
+                    this.state = State::Idle(Some(reader));
+                    this.leftover = result.map_err(io::Error::other)?;
+                    this.put_leftover(buf);
+                    return Poll::Ready(Ok(()));
+                }
+                State::Seeking(_) => return Poll::Ready(Err(io::Error::other("seek in progress"))),
+            }
+        }
+    }
+}
+
+impl AsyncSeek for AsyncFileReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let pos = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => this.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => this.pos.checked_add_signed(delta),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek position"))?;
+
+        let mut reader = this.take_reader()?;
+        this.leftover.clear();
+        this.state = State::Seeking(Box::pin(async move {
+            let result = reader.seek(pos).await;
+            (reader, result)
+        }));
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        if let State::Seeking(op) = &mut this.state {
+            let (reader, result) = ready!(op.as_mut().poll(cx));
+            this.state = State::Idle(Some(reader));
+            this.pos = result.map_err(io::Error::other)?;
+        }
+        Poll::Ready(Ok(this.pos))
+    }
+}
@@ -0,0 +1,105 @@
+use super::{
+    file_storage::{FileReader, FileStorage},
+    storage_registry::StorageRegistry,
+};
+use crate::server::entity::SyncFileInfo;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// What a chunk is stored as. Chunk blobs are kept apart from whole-file blobs, a file that is a single chunk
+/// has the same hash as that chunk, but each of them is collected on its own.
+pub fn chunk_blob(chunk: &SyncFileInfo) -> SyncFileInfo {
+    SyncFileInfo {
+        file_hash: format!("{}.chunk", chunk.file_hash),
+        sync_size: chunk.sync_size,
+        file_size: chunk.file_size,
+        storage_backend: chunk.storage_backend.clone(),
+        codec: chunk.codec.clone(),
+        ..Default::default()
+    }
+}
+
+/// Reads a chunked file as if it was one blob, only the chunk being read is kept open
+pub struct ChunkedFileReader {
+    file_storage: Arc<StorageRegistry>,
+    chunks: Vec<SyncFileInfo>,
+    /// where each chunk starts in the file
+    offsets: Vec<u64>,
+    len: u64,
+    pos: u64,
+    current: Option<(usize, Box<dyn FileReader>)>,
+}
+
+impl ChunkedFileReader {
+    /// `chunks` are the chunks of the file's manifest, in file order
+    pub fn new(file_storage: Arc<StorageRegistry>, chunks: Vec<SyncFileInfo>) -> Self {
+        let mut offsets = Vec::with_capacity(chunks.len());
+        let mut len = 0;
+        for chunk in &chunks {
+            offsets.push(len);
+            len += chunk.file_size as u64;
+        }
+
+        Self {
+            file_storage,
+            chunks,
+            offsets,
+            len,
+            pos: 0,
+            current: None,
+        }
+    }
+
+    async fn chunk_reader(&mut self, index: usize) -> Result<&mut Box<dyn FileReader>> {
+        if let Some((current, mut reader)) = self.current.take() {
+            if current == index {
+                return Ok(&mut self.current.insert((current, reader)).1);
+            }
+            reader.close().await;
+        }
+
+        let reader = self.file_storage.open_reader(&chunk_blob(&self.chunks[index])).await?;
+        Ok(&mut self.current.insert((index, reader)).1)
+    }
+}
+
+#[async_trait]
+impl FileReader for ChunkedFileReader {
+    async fn read(&mut self, data: &mut [u8]) -> Result<usize> {
+        let len = self.read_at(self.pos, data).await?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    async fn seek(&mut self, pos: u64) -> Result<u64> {
+        self.pos = pos;
+        Ok(pos)
+    }
+
+    /// Reads at most up to the end of the chunk `pos` falls into
+    async fn read_at(&mut self, pos: u64, data: &mut [u8]) -> Result<usize> {
+        if pos >= self.len || data.is_empty() {
+            return Ok(0);
+        }
+
+        let index = self.offsets.partition_point(|offset| *offset <= pos) - 1;
+        let chunk_pos = pos - self.offsets[index];
+        let len = data.len().min((self.chunks[index].file_size as u64 - chunk_pos) as usize);
+        let read_len = self.chunk_reader(index).await?.read_at(chunk_pos, &mut data[..len]).await?;
+        if read_len == 0 {
+            bail!("chunk ends before:{chunk_pos}, chunk:{}", self.chunks[index].file_hash);
+        }
+        Ok(read_len)
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    async fn close(&mut self) {
+        if let Some((_, mut reader)) = self.current.take() {
+            reader.close().await;
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use super::{
+    file_storage::{read_stored, BlobDigest, FileReader, FileStorage, FileWriter},
+    local_file_storage::{self, StagedBlob},
+    storage_registry::{LocalConfig, StorageBackend},
+};
+use crate::{common::hasher::HashAlgorithm, server::entity::SyncFileInfo};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+pub const CODEC_ZSTD: &str = "zstd";
+
+const MAGIC: &[u8; 4] = b"RSZ1";
+/// magic, size of the original blob and the number of frames, followed by the compressed size of each frame
+const HEADER_SIZE: u64 = (MAGIC.len() + 8 + 4) as u64;
+/// Each chunk is compressed into a frame of its own, so any range can be decompressed on its own
+const CHUNK_SIZE: u64 = 128 * 1024;
+/// Keeps the staged uploads apart from those of the inner backend
+const STAGING_SUBDIR: &str = "compress";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CompressionConfig {
+    /// The backend holding the compressed blobs
+    pub backend: Box<StorageBackend>,
+    /// zstd compression level
+    pub level: i32,
+    /// A blob is compressed only if its first chunk shrinks to this fraction of its size or less
+    pub max_ratio: f64,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            backend: Box::new(StorageBackend::Local(LocalConfig::default())),
+            level: 3,
+            max_ratio: 0.9,
+        }
+    }
+}
+
+/// Compresses the blobs that are worth it before handing them to the inner backend. Uploads are staged as they are
+/// and only compressed on commit, so the sizes and offsets seen through it are always those of the original blob.
+pub struct CompressedFileStorage {
+    inner: Arc<dyn FileStorage>,
+    staging_dir: PathBuf,
+    level: i32,
+    max_ratio: f64,
+    hash_algorithm: HashAlgorithm,
+}
+
+pub struct CompressedFileWriter {
+    staged: StagedBlob,
+    inner: Arc<dyn FileStorage>,
+    file_hash: String,
+    storage_backend: String,
+    level: i32,
+    max_ratio: f64,
+    /// what `close` returned, until more is written
+    closed: Option<BlobDigest>,
+}
+
+pub struct CompressedFileReader {
+    inner: Box<dyn FileReader>,
+    /// where each frame starts in the inner blob, followed by where the last one ends
+    frame_offsets: Vec<u64>,
+    len: u64,
+    pos: u64,
+    chunk: Option<(u64, Vec<u8>)>,
+}
+
+fn chunk_len(index: u64, size: u64) -> u64 {
+    CHUNK_SIZE.min(size - index * CHUNK_SIZE)
+}
+
+async fn read_staged(staged: &mut StagedBlob, pos: u64, len: u64) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; len as usize];
+    let mut filled = 0;
+    while filled < data.len() {
+        let len = staged.read_at(pos + filled as u64, &mut data[filled..]).await?;
+        if len == 0 {
+            bail!("staged blob ends before:{}", pos + data.len() as u64);
+        }
+        filled += len;
+    }
+    Ok(data)
+}
+
+impl CompressedFileStorage {
+    /// `storage_dir` holds the staged uploads
+    pub fn new(inner: Arc<dyn FileStorage>, config: &CompressionConfig, storage_dir: &Path, hash_algorithm: HashAlgorithm) -> Result<Self> {
+        if !zstd::compression_level_range().contains(&config.level) {
+            bail!("invalid zstd compression level:{}", config.level);
+        }
+        if config.max_ratio <= 0.0 {
+            bail!("invalid max compression ratio:{}", config.max_ratio);
+        }
+
+        Ok(Self {
+            inner,
+            staging_dir: storage_dir.join(STAGING_SUBDIR),
+            level: config.level,
+            max_ratio: config.max_ratio,
+            hash_algorithm,
+        })
+    }
+}
+
+impl CompressedFileWriter {
+    /// Compresses a sample of the first chunk, a blob that barely shrinks is stored as it is
+    async fn choose_codec(&mut self, size: u64) -> Result<String> {
+        if size == 0 {
+            return Ok(String::new());
+        }
+
+        let sample = read_staged(&mut self.staged, 0, CHUNK_SIZE.min(size)).await?;
+        let compressed = zstd::bulk::compress(&sample, self.level)?;
+        let ratio = compressed.len() as f64 / sample.len() as f64;
+        debug!("compression ratio of blob:{}, {ratio:.3}", self.file_hash);
+        Ok(if ratio <= self.max_ratio {
+            CODEC_ZSTD.to_string()
+        } else {
+            String::new()
+        })
+    }
+
+    async fn compress_chunk(&mut self, index: u64, size: u64) -> Result<Vec<u8>> {
+        let chunk = read_staged(&mut self.staged, index * CHUNK_SIZE, chunk_len(index, size)).await?;
+        Ok(zstd::bulk::compress(&chunk, self.level)?)
+    }
+
+    /// The header lists the size of every frame, so the blob is compressed twice, once just to get those
+    async fn write_compressed(&mut self, writer: &mut Box<dyn FileWriter>, size: u64, frame_sizes: &[u32]) -> Result<()> {
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize + frame_sizes.len() * 4);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&size.to_be_bytes());
+        header.extend_from_slice(&(frame_sizes.len() as u32).to_be_bytes());
+        for frame_size in frame_sizes {
+            header.extend_from_slice(&frame_size.to_be_bytes());
+        }
+        writer.write(&header).await?;
+
+        for (index, frame_size) in frame_sizes.iter().enumerate() {
+            let frame = self.compress_chunk(index as u64, size).await?;
+            if frame.len() != *frame_size as usize {
+                bail!("staged blob changed while compressing:{}, frame:{index}", self.file_hash);
+            }
+            writer.write(&frame).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_plain(&mut self, writer: &mut Box<dyn FileWriter>, size: u64) -> Result<()> {
+        for index in 0..size.div_ceil(CHUNK_SIZE) {
+            let chunk = read_staged(&mut self.staged, index * CHUNK_SIZE, chunk_len(index, size)).await?;
+            writer.write(&chunk).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FileWriter for CompressedFileWriter {
+    async fn write(&mut self, data: &[u8]) -> Result<usize> {
+        self.closed = None;
+        self.staged.write(data).await
+    }
+
+    async fn read_at(&mut self, pos: u64, data: &mut [u8]) -> Result<usize> {
+        self.staged.read_at(pos, data).await
+    }
+
+    /// The codec is chosen on what has been staged so far, it's only final once the whole blob is there. Closing
+    /// again without writing in between returns the same result, so `commit` doesn't sample the blob once more.
+    async fn close(&mut self) -> Result<BlobDigest> {
+        if let Some(blob) = &self.closed {
+            return Ok(blob.clone());
+        }
+        let mut blob = self.staged.close().await?;
+        blob.codec = self.choose_codec(blob.size).await?;
+        self.closed = Some(blob.clone());
+        Ok(blob)
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        let blob = self.close().await?;
+        let mut frame_sizes = vec![];
+        if blob.codec == CODEC_ZSTD {
+            for index in 0..blob.size.div_ceil(CHUNK_SIZE) {
+                frame_sizes.push(self.compress_chunk(index, blob.size).await?.len() as u32);
+            }
+        }
+        let stored_size = if blob.codec == CODEC_ZSTD {
+            HEADER_SIZE + frame_sizes.len() as u64 * 4 + frame_sizes.iter().map(|size| *size as u64).sum::<u64>()
+        } else {
+            blob.size
+        };
+
+        let target = SyncFileInfo {
+            file_hash: self.file_hash.clone(),
+            file_size: stored_size as usize,
+            storage_backend: self.storage_backend.clone(),
+            ..Default::default()
+        };
+        let mut writer = self.inner.open_writer(&target).await?;
+        let written = async {
+            if blob.codec == CODEC_ZSTD {
+                self.write_compressed(&mut writer, blob.size, &frame_sizes).await?;
+            } else {
+                self.write_plain(&mut writer, blob.size).await?;
+            }
+            writer.close().await
+        }
+        .await;
+
+        match written {
+            Ok(stored) if stored.size == stored_size => {}
+            Ok(stored) => {
+                writer.discard().await?;
+                bail!("stored blob size mismatch:{}, {}/{stored_size}", self.file_hash, stored.size);
+            }
+            Err(e) => {
+                writer.discard().await?;
+                return Err(e);
+            }
+        }
+        writer.commit().await?;
+        debug!(
+            "stored blob:{}, codec:{}, size:{stored_size}/{}",
+            self.file_hash, blob.codec, blob.size
+        );
+        self.staged.discard().await
+    }
+
+    async fn discard(&mut self) -> Result<()> {
+        self.staged.discard().await
+    }
+}
+
+impl CompressedFileReader {
+    async fn load_chunk(&mut self, index: u64) -> Result<&[u8]> {
+        if !matches!(&self.chunk, Some((cached, _)) if *cached == index) {
+            let start = self.frame_offsets[index as usize];
+            let frame = read_stored(&mut self.inner, start, self.frame_offsets[index as usize + 1] - start).await?;
+            let chunk_len = chunk_len(index, self.len) as usize;
+            let chunk = zstd::bulk::decompress(&frame, chunk_len).context(format!("failed to decompress frame:{index}"))?;
+            if chunk.len() != chunk_len {
+                bail!("frame decompressed to an unexpected size:{index}, {}/{chunk_len}", chunk.len());
+            }
+            self.chunk = Some((index, chunk));
+        }
+        Ok(self.chunk.as_ref().map(|(_, data)| data.as_slice()).unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl FileReader for CompressedFileReader {
+    async fn read(&mut self, data: &mut [u8]) -> Result<usize> {
+        let len = self.read_at(self.pos, data).await?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    async fn seek(&mut self, pos: u64) -> Result<u64> {
+        self.pos = pos;
+        Ok(pos)
+    }
+
+    async fn read_at(&mut self, pos: u64, data: &mut [u8]) -> Result<usize> {
+        if pos >= self.len || data.is_empty() {
+            return Ok(0);
+        }
+        let chunk = self.load_chunk(pos / CHUNK_SIZE).await?;
+        let chunk = &chunk[(pos % CHUNK_SIZE) as usize..];
+        let len = chunk.len().min(data.len());
+        data[..len].copy_from_slice(&chunk[..len]);
+        Ok(len)
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    async fn close(&mut self) {
+        self.inner.close().await;
+    }
+}
+
+#[async_trait]
+impl FileStorage for CompressedFileStorage {
+    async fn open_writer(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileWriter>> {
+        let staging_path = local_file_storage::staging_path(&self.staging_dir, &file_info.file_hash);
+        let staged = StagedBlob::open(staging_path, file_info.sync_size as u64, self.hash_algorithm).await?;
+        Ok(Box::new(CompressedFileWriter {
+            staged,
+            inner: self.inner.clone(),
+            file_hash: file_info.file_hash.clone(),
+            storage_backend: file_info.storage_backend.clone(),
+            level: self.level,
+            max_ratio: self.max_ratio,
+            closed: None,
+        }))
+    }
+
+    async fn open_reader(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileReader>> {
+        let file_hash = &file_info.file_hash;
+        match file_info.codec.as_str() {
+            "" => return self.inner.open_reader(file_info).await,
+            CODEC_ZSTD => {}
+            codec => bail!("unsupported codec:{codec}, blob:{file_hash}"),
+        }
+
+        let mut inner = self.inner.open_reader(file_info).await?;
+        let header = read_stored(&mut inner, 0, HEADER_SIZE).await?;
+        if !header.starts_with(MAGIC) {
+            bail!("not a compressed blob:{file_hash}");
+        }
+        let len = u64::from_be_bytes(header[MAGIC.len()..MAGIC.len() + 8].try_into()?);
+        let frame_count = u32::from_be_bytes(header[MAGIC.len() + 8..].try_into()?) as u64;
+        if frame_count != len.div_ceil(CHUNK_SIZE) {
+            bail!("corrupted compressed blob:{file_hash}, frames:{frame_count}, size:{len}");
+        }
+
+        let frame_sizes = read_stored(&mut inner, HEADER_SIZE, frame_count * 4).await?;
+        let mut offset = HEADER_SIZE + frame_count * 4;
+        let mut frame_offsets = vec![offset];
+        for frame_size in frame_sizes.chunks_exact(4) {
+            offset += u32::from_be_bytes(frame_size.try_into()?) as u64;
+            frame_offsets.push(offset);
+        }
+        if offset != inner.len() {
+            bail!("compressed blob size mismatch:{file_hash}, {}/{offset}", inner.len());
+        }
+
+        Ok(Box::new(CompressedFileReader {
+            inner,
+            frame_offsets,
+            len,
+            pos: 0,
+            chunk: None,
+        }))
+    }
+
+    async fn delete_file(&self, file_info: &SyncFileInfo) -> Result<()> {
+        let staging_path = local_file_storage::staging_path(&self.staging_dir, &file_info.file_hash);
+        match tokio::fs::remove_file(&staging_path).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(format!("failed to delete file:{staging_path:?}")),
+        }
+        self.inner.delete_file(file_info).await
+    }
+
+    async fn rotate_key(&self, file_info: &SyncFileInfo) -> Result<bool> {
+        self.inner.rotate_key(file_info).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::local_file_storage::LocalFileStorage;
+
+    fn test_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("rsdrive-compressed-{}", rand::random::<u64>()))
+    }
+
+    fn storage(dir: &Path) -> CompressedFileStorage {
+        let inner = Arc::new(LocalFileStorage::new(dir.join("blobs"), HashAlgorithm::Sha256));
+        CompressedFileStorage::new(inner, &CompressionConfig::default(), dir, HashAlgorithm::Sha256).unwrap()
+    }
+
+    fn compressible_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i / 100 % 7) as u8).collect()
+    }
+
+    /// Stores `data`, returns what the database would record for it
+    async fn store(storage: &CompressedFileStorage, data: &[u8]) -> SyncFileInfo {
+        let mut file_info = SyncFileInfo {
+            file_hash: HashAlgorithm::Sha256.hash(data),
+            file_size: data.len(),
+            ..Default::default()
+        };
+        let mut writer = storage.open_writer(&file_info).await.unwrap();
+        writer.write(data).await.unwrap();
+        let blob = writer.close().await.unwrap();
+        assert_eq!(blob.digest, file_info.file_hash);
+        writer.commit().await.unwrap();
+        file_info.codec = blob.codec;
+        file_info
+    }
+
+    #[tokio::test]
+    async fn reads_ranges_across_frames() {
+        let dir = test_dir();
+        let storage = storage(&dir);
+        let data = compressible_data(3 * CHUNK_SIZE as usize + 1234);
+        let file_info = store(&storage, &data).await;
+        assert_eq!(file_info.codec, CODEC_ZSTD);
+
+        let mut reader = storage.open_reader(&file_info).await.unwrap();
+        assert_eq!(reader.len(), data.len() as u64);
+        for (pos, len) in [(0, 10), (CHUNK_SIZE - 5, 10), (2 * CHUNK_SIZE + 1, 300), (data.len() as u64 - 3, 3)] {
+            let read = read_stored(&mut reader, pos, len).await.unwrap();
+            assert_eq!(read, &data[pos as usize..(pos + len) as usize]);
+        }
+        // a single read stops at the end of its frame
+        let mut buf = vec![0u8; 10];
+        assert_eq!(reader.read_at(CHUNK_SIZE - 5, &mut buf).await.unwrap(), 5);
+        assert_eq!(reader.read_at(data.len() as u64 - 3, &mut buf).await.unwrap(), 3);
+        let mut buf = [0u8; 1];
+        assert_eq!(reader.read_at(data.len() as u64, &mut buf).await.unwrap(), 0);
+
+        reader.seek(CHUNK_SIZE + 7).await.unwrap();
+        let mut buf = vec![0u8; 100];
+        assert_eq!(reader.read(&mut buf).await.unwrap(), 100);
+        assert_eq!(buf, &data[CHUNK_SIZE as usize + 7..CHUNK_SIZE as usize + 107]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stores_incompressible_blobs_as_they_are() {
+        let dir = test_dir();
+        let storage = storage(&dir);
+        let data: Vec<u8> = (0..CHUNK_SIZE as usize + 10).map(|_| rand::random()).collect();
+        let file_info = store(&storage, &data).await;
+        assert_eq!(file_info.codec, "");
+
+        let mut reader = storage.open_reader(&file_info).await.unwrap();
+        assert_eq!(read_stored(&mut reader, 5, data.len() as u64 - 5).await.unwrap(), &data[5..]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn close_is_idempotent_until_more_is_written() {
+        let dir = test_dir();
+        let storage = storage(&dir);
+        let data = compressible_data(1000);
+        let file_info = SyncFileInfo {
+            file_hash: HashAlgorithm::Sha256.hash(&data),
+            file_size: data.len(),
+            ..Default::default()
+        };
+
+        let mut writer = storage.open_writer(&file_info).await.unwrap();
+        writer.write(&data[..500]).await.unwrap();
+        let first = writer.close().await.unwrap();
+        assert_eq!(writer.close().await.unwrap(), first);
+        writer.write(&data[500..]).await.unwrap();
+        let blob = writer.close().await.unwrap();
+        assert_eq!(blob.size, data.len() as u64);
+        assert_eq!(blob.digest, file_info.file_hash);
+        writer.discard().await.unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,68 @@
-use crate::server::entity::{SyncFileInfo, User};
+use crate::{
+    common::entity::FileVersion,
+    server::entity::{ApiToken, Session, SyncFileInfo, User},
+};
 use anyhow::Result;
 
 pub trait Database: Send {
-    fn save_user(&self, user: &User) -> Result<()>;
-    fn query_user(&self, username: &str, password: &str) -> Option<User>;
+    fn save_user(&self, user: &User) -> Result<bool>;
+    fn query_user(&self, username: &str) -> Option<User>;
+    fn save_session(&self, session: &Session, ttl_secs: u64) -> Result<()>;
+    fn query_session_user(&self, token_hash: &str) -> Option<User>;
+    fn delete_session(&self, token_hash: &str) -> Result<bool>;
+    fn delete_user_sessions(&self, user_id: u32) -> Result<usize>;
+    fn delete_expired_sessions(&self) -> Result<usize>;
+    fn save_api_token(&self, token: &ApiToken, ttl_secs: Option<u64>) -> Result<u32>;
+    fn query_api_tokens(&self, user_id: u32) -> Result<Vec<ApiToken>>;
+    fn query_api_token_user(&self, token_hash: &str) -> Option<(User, ApiToken)>;
+    fn delete_api_token(&self, user_id: u32, token_id: u32) -> Result<bool>;
     fn query_file_info(&self, user_id: u32, file_dir: &str, file_name: &str) -> Option<SyncFileInfo>;
+    fn query_shared_file(&self, file_hash: &str) -> Option<SyncFileInfo>;
     fn save_file_info(&self, user_id: u32, file_info: &SyncFileInfo) -> Result<()>;
+    /// The file's completely stored content is kept as a version of it, so a deleted file can be restored
     fn delete_file_info(&self, user_id: u32, file_info: &SyncFileInfo) -> Result<bool>;
+    /// Points the user's file at the completely stored content of `file_info`, as long as the file still has the
+    /// content `base_hash` (or doesn't exist if None). Returns false if it doesn't, the content is recorded either way.
+    fn replace_file_content(&self, user_id: u32, file_info: &SyncFileInfo, base_hash: Option<&str>) -> Result<bool>;
+    /// Records the blob of a file being overwritten, no file references it until it's complete
+    fn save_shared_file(&self, file_info: &SyncFileInfo) -> Result<()>;
+    /// Incomplete blobs are left to expire as abandoned uploads, the blob of an overwrite isn't referenced until
+    /// it's complete
+    fn query_unreferenced_files(&self, grace_secs: u64) -> Result<Vec<SyncFileInfo>>;
+    fn delete_unreferenced_file(&self, file_hash: &str, grace_secs: u64) -> Result<bool>;
+    fn query_abandoned_files(&self, ttl_secs: u64) -> Result<Vec<SyncFileInfo>>;
+    fn delete_abandoned_file(&self, file_hash: &str, ttl_secs: u64) -> Result<bool>;
+    /// Records the blob's progress, along with its codec once it's complete
     fn update_sync_size(&self, user_id: u32, file_info: &SyncFileInfo) -> Result<()>;
+    /// Keeps a blob that is still being uploaded from expiring as abandoned, its progress is only recorded once the
+    /// upload stops
+    fn touch_shared_file(&self, file_hash: &str) -> Result<()>;
+    /// Blobs recorded before backends had names belong to `storage_backend`
+    fn assign_storage_backend(&self, storage_backend: &str) -> Result<usize>;
+    /// Completed blobs in `storage_backend` that haven't been touched for `idle_secs`
+    fn query_cold_files(&self, storage_backend: &str, idle_secs: u64, limit: usize) -> Result<Vec<SyncFileInfo>>;
+    /// The blob may be encoded differently by the backend it's moved to
+    fn move_shared_file(&self, file_hash: &str, from_backend: &str, to_backend: &str, codec: &str) -> Result<bool>;
+    /// Completed blobs ordered by hash, starting after `after_hash`
+    fn query_stored_files(&self, after_hash: &str, limit: usize) -> Result<Vec<SyncFileInfo>>;
+    /// Chunks are returned as blobs of their own, keyed by the chunk hash
+    fn query_chunk(&self, chunk_hash: &str) -> Option<SyncFileInfo>;
+    /// A new chunk stays unreferenced until a manifest lists it
+    fn save_chunk(&self, chunk: &SyncFileInfo) -> Result<()>;
+    /// The chunks of a chunked file, in file order
+    fn query_file_chunks(&self, file_hash: &str) -> Result<Vec<SyncFileInfo>>;
+    /// Completes the file as the listed chunks, returns false if its blob is being stored otherwise or a chunk is gone
+    fn save_manifest(&self, file_hash: &str, chunk_hashes: &[String]) -> Result<bool>;
+    fn query_unreferenced_chunks(&self, grace_secs: u64) -> Result<Vec<SyncFileInfo>>;
+    fn delete_unreferenced_chunk(&self, chunk_hash: &str, grace_secs: u64) -> Result<bool>;
+    /// Referenced chunks ordered by hash, starting after `after_hash`
+    fn query_stored_chunks(&self, after_hash: &str, limit: usize) -> Result<Vec<SyncFileInfo>>;
+    /// The file's prior contents, newest first, including those of a file that has been deleted
+    fn query_file_versions(&self, user_id: u32, file_dir: &str, file_name: &str) -> Result<Vec<FileVersion>>;
+    /// Makes the version the file's content again, the content it replaces is kept as a version. A deleted file is
+    /// brought back. Returns false if the file has no such version
+    fn restore_file_version(&self, user_id: u32, file_dir: &str, file_name: &str, version_id: u32) -> Result<bool>;
+    /// Drops the versions of each file beyond the newest `keep_last`, except for the newest of each day within
+    /// `keep_daily_days` and the newest of each week within another `keep_weekly_weeks`
+    fn prune_file_versions(&self, keep_last: usize, keep_daily_days: u64, keep_weekly_weeks: u64) -> Result<usize>;
 }
@@ -1,8 +1,9 @@
 use super::{database::Database, sqlite_database::SqliteDatabase};
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DatabaseConfig {
     pub uri: String,
 }
@@ -0,0 +1,584 @@
+use std::{collections::HashMap, sync::Arc};
+
+use super::{
+    file_storage::{read_stored, BlobDigest, FileReader, FileStorage, FileWriter},
+    storage_registry::StorageBackend,
+};
+use crate::{
+    common::{
+        crypto::{SecretKey, KEY_SIZE, SEAL_OVERHEAD},
+        hasher::{ContentHasher, HashAlgorithm},
+    },
+    server::entity::SyncFileInfo,
+};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+const MAGIC: &[u8; 4] = b"RSE1";
+/// magic, master key id and the sealed data key
+const HEADER_SIZE: u64 = (MAGIC.len() + 4 + KEY_SIZE + SEAL_OVERHEAD) as u64;
+/// Plaintext is sealed in chunks of this size, so any range can be decrypted on its own
+const CHUNK_SIZE: u64 = 64 * 1024;
+const SEALED_CHUNK_SIZE: u64 = CHUNK_SIZE + SEAL_OVERHEAD as u64;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MasterKey {
+    pub id: u32,
+    /// Hex encoded 32 byte key
+    pub key: String,
+}
+
+impl std::fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MasterKey").field("id", &self.id).finish_non_exhaustive()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptionConfig {
+    /// The backend holding the encrypted blobs
+    pub backend: Box<StorageBackend>,
+    /// A rotated out key has to stay until no blob uses it anymore
+    pub master_keys: Vec<MasterKey>,
+    /// The master key new data keys are wrapped with
+    pub active_key_id: u32,
+}
+
+/// Encrypts blobs at rest with a data key of their own, the data key is stored in the blob's header wrapped
+/// by a master key. The size and digest seen through it are those of the plaintext.
+pub struct EncryptedFileStorage {
+    inner: Arc<dyn FileStorage>,
+    master_keys: HashMap<u32, SecretKey>,
+    active_key_id: u32,
+    hash_algorithm: HashAlgorithm,
+}
+
+pub struct EncryptedFileWriter {
+    inner: Box<dyn FileWriter>,
+    data_key: SecretKey,
+    file_hash: String,
+    file_size: u64,
+    hasher: ContentHasher,
+    size: u64,
+    chunk_index: u64,
+    pending: Vec<u8>,
+    closed: bool,
+}
+
+pub struct EncryptedFileReader {
+    inner: Box<dyn FileReader>,
+    data_key: SecretKey,
+    file_hash: String,
+    len: u64,
+    pos: u64,
+    chunk: Option<(u64, Vec<u8>)>,
+}
+
+fn chunk_offset(index: u64) -> u64 {
+    HEADER_SIZE + index * SEALED_CHUNK_SIZE
+}
+
+fn encrypted_size(size: u64) -> u64 {
+    let tail = size % CHUNK_SIZE;
+    chunk_offset(size / CHUNK_SIZE) + if tail > 0 { tail + SEAL_OVERHEAD as u64 } else { 0 }
+}
+
+fn plaintext_size(encrypted_size: u64) -> Result<u64> {
+    let Some(body) = encrypted_size.checked_sub(HEADER_SIZE) else {
+        bail!("encrypted blob too short:{encrypted_size}");
+    };
+    let tail = body % SEALED_CHUNK_SIZE;
+    if tail > 0 && tail <= SEAL_OVERHEAD as u64 {
+        bail!("encrypted blob truncated:{encrypted_size}");
+    }
+    Ok(body / SEALED_CHUNK_SIZE * CHUNK_SIZE + tail.saturating_sub(SEAL_OVERHEAD as u64))
+}
+
+/// Binds a chunk to its blob and position, flagging the last one so truncating whole chunks is detected
+fn chunk_aad(file_hash: &str, index: u64, chunk_len: u64, size: u64) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(file_hash.len() + 9);
+    aad.extend_from_slice(file_hash.as_bytes());
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.push((index * CHUNK_SIZE + chunk_len == size) as u8);
+    aad
+}
+
+fn header_aad(file_hash: &str, key_id: u32) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(MAGIC.len() + 4 + file_hash.len());
+    aad.extend_from_slice(MAGIC);
+    aad.extend_from_slice(&key_id.to_be_bytes());
+    aad.extend_from_slice(file_hash.as_bytes());
+    aad
+}
+
+async fn read_staged(writer: &mut Box<dyn FileWriter>, pos: u64, len: u64) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; len as usize];
+    let mut filled = 0;
+    while filled < data.len() {
+        let len = writer.read_at(pos + filled as u64, &mut data[filled..]).await?;
+        if len == 0 {
+            bail!("staged blob ends before:{}", pos + data.len() as u64);
+        }
+        filled += len;
+    }
+    Ok(data)
+}
+
+impl EncryptedFileStorage {
+    pub fn new(inner: Arc<dyn FileStorage>, config: &EncryptionConfig, hash_algorithm: HashAlgorithm) -> Result<Self> {
+        let mut master_keys = HashMap::new();
+        for master_key in &config.master_keys {
+            let key = SecretKey::from_hex(&master_key.key).context(format!("invalid master key:{}", master_key.id))?;
+            master_keys.insert(master_key.id, key);
+        }
+        if !master_keys.contains_key(&config.active_key_id) {
+            bail!("active master key not configured:{}", config.active_key_id);
+        }
+
+        Ok(Self {
+            inner,
+            master_keys,
+            active_key_id: config.active_key_id,
+            hash_algorithm,
+        })
+    }
+
+    fn seal_header(&self, file_hash: &str, data_key: &SecretKey) -> Result<Vec<u8>> {
+        let master_key = &self.master_keys[&self.active_key_id];
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&self.active_key_id.to_be_bytes());
+        header.extend_from_slice(&master_key.seal(data_key.as_bytes(), &header_aad(file_hash, self.active_key_id))?);
+        Ok(header)
+    }
+
+    /// Returns the id of the master key the data key was wrapped with, along with the data key
+    fn open_header(&self, file_hash: &str, header: &[u8]) -> Result<(u32, SecretKey)> {
+        if !header.starts_with(MAGIC) {
+            bail!("not an encrypted blob:{file_hash}");
+        }
+        let key_id = u32::from_be_bytes(header[MAGIC.len()..MAGIC.len() + 4].try_into()?);
+        let Some(master_key) = self.master_keys.get(&key_id) else {
+            bail!("master key not configured:{key_id}, blob:{file_hash}");
+        };
+        let data_key = master_key.open(&header[MAGIC.len() + 4..], &header_aad(file_hash, key_id))?;
+        Ok((key_id, SecretKey::from_bytes(&data_key)?))
+    }
+
+    /// What the inner storage is asked for, it only ever sees the encrypted blob
+    fn inner_info(file_info: &SyncFileInfo, sync_size: u64) -> SyncFileInfo {
+        SyncFileInfo {
+            file_hash: file_info.file_hash.clone(),
+            sync_size: sync_size as usize,
+            file_size: encrypted_size(file_info.file_size as u64) as usize,
+            storage_backend: file_info.storage_backend.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+impl EncryptedFileWriter {
+    async fn seal_chunk(&mut self, len: usize) -> Result<()> {
+        let aad = chunk_aad(&self.file_hash, self.chunk_index, len as u64, self.file_size);
+        let sealed = self.data_key.seal(&self.pending[..len], &aad)?;
+        self.inner.write(&sealed).await?;
+        self.pending.drain(..len);
+        self.chunk_index += 1;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl FileWriter for EncryptedFileWriter {
+    async fn write(&mut self, data: &[u8]) -> Result<usize> {
+        if self.closed {
+            bail!("writer already closed:{}", self.file_hash);
+        }
+
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+        self.pending.extend_from_slice(data);
+        while self.pending.len() as u64 >= CHUNK_SIZE {
+            self.seal_chunk(CHUNK_SIZE as usize).await?;
+        }
+        Ok(data.len())
+    }
+
+    async fn read_at(&mut self, _pos: u64, _data: &mut [u8]) -> Result<usize> {
+        bail!("reading back an encrypted blob before it's committed is not supported");
+    }
+
+    /// A partial chunk left over is sealed as it is, resuming opens it again and seals it once more when full
+    async fn close(&mut self) -> Result<BlobDigest> {
+        if !self.closed && !self.pending.is_empty() {
+            self.seal_chunk(self.pending.len()).await?;
+        }
+        self.closed = true;
+        self.inner.close().await?;
+        Ok(BlobDigest {
+            digest: self.hasher.clone().finalize(),
+            size: self.size,
+            codec: String::new(),
+        })
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        self.close().await?;
+        self.inner.commit().await
+    }
+
+    async fn discard(&mut self) -> Result<()> {
+        self.inner.discard().await
+    }
+}
+
+impl EncryptedFileReader {
+    async fn load_chunk(&mut self, index: u64) -> Result<&[u8]> {
+        if !matches!(&self.chunk, Some((cached, _)) if *cached == index) {
+            let chunk_len = CHUNK_SIZE.min(self.len - index * CHUNK_SIZE);
+            let sealed = read_stored(&mut self.inner, chunk_offset(index), chunk_len + SEAL_OVERHEAD as u64).await?;
+            let aad = chunk_aad(&self.file_hash, index, chunk_len, self.len);
+            self.chunk = Some((index, self.data_key.open(&sealed, &aad)?));
+        }
+        Ok(self.chunk.as_ref().map(|(_, data)| data.as_slice()).unwrap_or_default())
+    }
+}
+
+#[async_trait]
+impl FileReader for EncryptedFileReader {
+    async fn read(&mut self, data: &mut [u8]) -> Result<usize> {
+        let len = self.read_at(self.pos, data).await?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    async fn seek(&mut self, pos: u64) -> Result<u64> {
+        self.pos = pos;
+        Ok(pos)
+    }
+
+    async fn read_at(&mut self, pos: u64, data: &mut [u8]) -> Result<usize> {
+        if pos >= self.len || data.is_empty() {
+            return Ok(0);
+        }
+        let chunk = self.load_chunk(pos / CHUNK_SIZE).await?;
+        let chunk = &chunk[(pos % CHUNK_SIZE) as usize..];
+        let len = chunk.len().min(data.len());
+        data[..len].copy_from_slice(&chunk[..len]);
+        Ok(len)
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    async fn close(&mut self) {
+        self.inner.close().await;
+    }
+}
+
+#[async_trait]
+impl FileStorage for EncryptedFileStorage {
+    async fn open_writer(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileWriter>> {
+        let file_hash = &file_info.file_hash;
+        let offset = file_info.sync_size as u64;
+        // nothing is staged yet for a new blob, not even the header
+        let staged_size = if offset == 0 { 0 } else { encrypted_size(offset) };
+        let mut writer = EncryptedFileWriter {
+            inner: self.inner.open_writer(&Self::inner_info(file_info, staged_size)).await?,
+            data_key: SecretKey::generate(),
+            file_hash: file_hash.clone(),
+            file_size: file_info.file_size as u64,
+            hasher: self.hash_algorithm.hasher(),
+            size: offset,
+            chunk_index: 0,
+            pending: Vec::new(),
+            closed: false,
+        };
+
+        if offset == 0 {
+            let header = self.seal_header(file_hash, &writer.data_key)?;
+            writer.inner.write(&header).await?;
+            return Ok(Box::new(writer));
+        }
+
+        // resuming, the plaintext before `offset` is hashed again, which also verifies it
+        let header = read_staged(&mut writer.inner, 0, HEADER_SIZE).await?;
+        writer.data_key = self.open_header(file_hash, &header)?.1;
+        let full_chunks = offset / CHUNK_SIZE;
+        let tail = offset % CHUNK_SIZE;
+        for index in 0..=full_chunks {
+            let chunk_len = if index < full_chunks { CHUNK_SIZE } else { tail };
+            if chunk_len == 0 {
+                break;
+            }
+            let sealed = read_staged(&mut writer.inner, chunk_offset(index), chunk_len + SEAL_OVERHEAD as u64).await?;
+            let chunk = writer
+                .data_key
+                .open(&sealed, &chunk_aad(file_hash, index, chunk_len, writer.file_size))?;
+            writer.hasher.update(&chunk);
+            if index == full_chunks {
+                writer.pending = chunk;
+            }
+        }
+
+        if tail > 0 {
+            // the partial chunk is sealed again once it has been filled up, so it's cut off the staged blob
+            debug!("reopening partial chunk:{full_chunks}, blob:{file_hash}");
+            writer.inner = self
+                .inner
+                .open_writer(&Self::inner_info(file_info, chunk_offset(full_chunks)))
+                .await?;
+        }
+        writer.chunk_index = full_chunks;
+        Ok(Box::new(writer))
+    }
+
+    async fn open_reader(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileReader>> {
+        let mut inner = self.inner.open_reader(file_info).await?;
+        let header = read_stored(&mut inner, 0, HEADER_SIZE).await?;
+        let (_, data_key) = self.open_header(&file_info.file_hash, &header)?;
+        Ok(Box::new(EncryptedFileReader {
+            len: plaintext_size(inner.len())?,
+            inner,
+            data_key,
+            file_hash: file_info.file_hash.clone(),
+            pos: 0,
+            chunk: None,
+        }))
+    }
+
+    async fn delete_file(&self, file_info: &SyncFileInfo) -> Result<()> {
+        self.inner.delete_file(file_info).await
+    }
+
+    /// Only the header changes, the chunks are copied over as they are to a copy staged apart from any upload
+    async fn rotate_key(&self, file_info: &SyncFileInfo) -> Result<bool> {
+        let file_hash = &file_info.file_hash;
+        let mut reader = self.inner.open_reader(file_info).await?;
+        let header = read_stored(&mut reader, 0, HEADER_SIZE).await?;
+        let (key_id, data_key) = self.open_header(file_hash, &header)?;
+        if key_id == self.active_key_id {
+            reader.close().await;
+            return Ok(false);
+        }
+
+        // the stored size is taken as it is, the blob may hold e.g. compressed data rather than `file_size` bytes
+        let len = reader.len();
+        let target = SyncFileInfo {
+            file_size: len as usize,
+            ..Self::inner_info(file_info, 0)
+        };
+        let mut writer = self.inner.open_rewriter(&target).await?;
+        let copied = async {
+            writer.write(&self.seal_header(file_hash, &data_key)?).await?;
+            let mut buf = vec![0u8; SEALED_CHUNK_SIZE as usize];
+            reader.seek(HEADER_SIZE).await?;
+            loop {
+                let len = reader.read(&mut buf).await?;
+                if len == 0 {
+                    break;
+                }
+                writer.write(&buf[..len]).await?;
+            }
+            writer.close().await
+        }
+        .await;
+        reader.close().await;
+
+        match copied {
+            Ok(blob) if blob.size == len => {}
+            Ok(blob) => {
+                writer.discard().await?;
+                bail!("blob changed while rotating its key:{file_hash}, {}/{len}", blob.size);
+            }
+            Err(e) => {
+                writer.discard().await?;
+                return Err(e);
+            }
+        }
+        writer.commit().await?;
+        debug!("rotated key of blob:{file_hash}, {key_id} -> {}", self.active_key_id);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::local_file_storage::{self, LocalFileStorage};
+    use std::path::{Path, PathBuf};
+
+    const KEY_1: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+    const KEY_2: &str = "0202020202020202020202020202020202020202020202020202020202020202";
+
+    fn test_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("rsdrive-encrypted-{}", rand::random::<u64>()))
+    }
+
+    fn storage(dir: &Path, keys: &[(u32, &str)], active_key_id: u32) -> EncryptedFileStorage {
+        let config = EncryptionConfig {
+            backend: Box::new(StorageBackend::Memory),
+            master_keys: keys
+                .iter()
+                .map(|(id, key)| MasterKey {
+                    id: *id,
+                    key: key.to_string(),
+                })
+                .collect(),
+            active_key_id,
+        };
+        let inner = Arc::new(LocalFileStorage::new(dir.to_path_buf(), HashAlgorithm::Sha256));
+        EncryptedFileStorage::new(inner, &config, HashAlgorithm::Sha256).unwrap()
+    }
+
+    fn blob_info(data: &[u8], sync_size: usize) -> SyncFileInfo {
+        SyncFileInfo {
+            file_hash: HashAlgorithm::Sha256.hash(data),
+            sync_size,
+            file_size: data.len(),
+            ..Default::default()
+        }
+    }
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    async fn read_all(storage: &EncryptedFileStorage, file_info: &SyncFileInfo) -> Vec<u8> {
+        let mut reader = storage.open_reader(file_info).await.unwrap();
+        let mut data = vec![];
+        let mut buf = vec![0u8; 10_000];
+        loop {
+            let len = reader.read(&mut buf).await.unwrap();
+            if len == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..len]);
+        }
+        data
+    }
+
+    async fn store(storage: &EncryptedFileStorage, data: &[u8]) -> SyncFileInfo {
+        let file_info = blob_info(data, 0);
+        let mut writer = storage.open_writer(&file_info).await.unwrap();
+        writer.write(data).await.unwrap();
+        writer.commit().await.unwrap();
+        file_info
+    }
+
+    #[test]
+    fn encrypted_size_round_trips() {
+        for size in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE + 17] {
+            assert_eq!(plaintext_size(encrypted_size(size)).unwrap(), size);
+        }
+        assert!(plaintext_size(HEADER_SIZE - 1).is_err());
+        assert!(plaintext_size(HEADER_SIZE + SEAL_OVERHEAD as u64).is_err());
+    }
+
+    #[tokio::test]
+    async fn resumes_in_the_middle_of_a_chunk() {
+        let dir = test_dir();
+        let storage = storage(&dir, &[(1, KEY_1)], 1);
+        let data = test_data(2 * CHUNK_SIZE as usize + 1000);
+        let split = CHUNK_SIZE as usize + 300;
+
+        let mut writer = storage.open_writer(&blob_info(&data, 0)).await.unwrap();
+        writer.write(&data[..split]).await.unwrap();
+        writer.close().await.unwrap();
+        drop(writer);
+
+        let file_info = blob_info(&data, split);
+        let mut writer = storage.open_writer(&file_info).await.unwrap();
+        writer.write(&data[split..]).await.unwrap();
+        let blob = writer.close().await.unwrap();
+        assert_eq!(blob.digest, file_info.file_hash);
+        assert_eq!(blob.size, data.len() as u64);
+        writer.commit().await.unwrap();
+
+        assert_eq!(read_all(&storage, &file_info).await, data);
+        let mut reader = storage.open_reader(&file_info).await.unwrap();
+        let mut buf = vec![0u8; 500];
+        let pos = CHUNK_SIZE as usize - 100;
+        assert_eq!(reader.read_at(pos as u64, &mut buf).await.unwrap(), 100);
+        assert_eq!(&buf[..100], &data[pos..pos + 100]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resuming_with_a_corrupted_prefix_fails() {
+        let dir = test_dir();
+        let storage = storage(&dir, &[(1, KEY_1)], 1);
+        let data = test_data(CHUNK_SIZE as usize + 10);
+
+        let file_info = blob_info(&data, 0);
+        let mut writer = storage.open_writer(&file_info).await.unwrap();
+        writer.write(&data[..CHUNK_SIZE as usize]).await.unwrap();
+        writer.close().await.unwrap();
+        drop(writer);
+
+        let staging_path = local_file_storage::staging_path(&dir, &file_info.file_hash);
+        let mut staged = std::fs::read(&staging_path).unwrap();
+        staged[HEADER_SIZE as usize + 100] ^= 1;
+        std::fs::write(&staging_path, staged).unwrap();
+        assert!(storage.open_writer(&blob_info(&data, CHUNK_SIZE as usize)).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotates_to_the_active_key() {
+        let dir = test_dir();
+        let data = test_data(CHUNK_SIZE as usize + 1000);
+        let file_info = store(&storage(&dir, &[(1, KEY_1)], 1), &data).await;
+
+        let rotating = storage(&dir, &[(1, KEY_1), (2, KEY_2)], 2);
+        assert!(rotating.rotate_key(&file_info).await.unwrap());
+        assert!(!rotating.rotate_key(&file_info).await.unwrap());
+
+        let rotated = storage(&dir, &[(2, KEY_2)], 2);
+        assert_eq!(read_all(&rotated, &file_info).await, data);
+        assert!(storage(&dir, &[(1, KEY_1)], 1).open_reader(&file_info).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotation_leaves_a_staged_upload_alone() {
+        let dir = test_dir();
+        let data = test_data(3000);
+        let old = storage(&dir, &[(1, KEY_1)], 1);
+        let file_info = store(&old, &data).await;
+
+        // the same content being uploaded again, e.g. to another backend
+        let mut writer = old.open_writer(&file_info).await.unwrap();
+        writer.write(&data[..1000]).await.unwrap();
+        writer.close().await.unwrap();
+        drop(writer);
+        let staging_path = local_file_storage::staging_path(&dir, &file_info.file_hash);
+        let staged = std::fs::read(&staging_path).unwrap();
+
+        let rotating = storage(&dir, &[(1, KEY_1), (2, KEY_2)], 2);
+        assert!(rotating.rotate_key(&file_info).await.unwrap());
+        assert_eq!(std::fs::read(&staging_path).unwrap(), staged);
+        assert_eq!(read_all(&rotating, &file_info).await, data);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rotates_a_blob_stored_smaller_than_its_file_size() {
+        let dir = test_dir();
+        let data = test_data(5000);
+        let file_info = store(&storage(&dir, &[(1, KEY_1)], 1), &data).await;
+
+        // what the compressed backend passes down, the logical size of the blob rather than what's stored
+        let logical = SyncFileInfo {
+            file_size: 50_000,
+            ..blob_info(&data, 0)
+        };
+        let rotating = storage(&dir, &[(1, KEY_1), (2, KEY_2)], 2);
+        assert!(rotating.rotate_key(&logical).await.unwrap());
+        assert_eq!(read_all(&rotating, &file_info).await, data);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
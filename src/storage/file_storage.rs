@@ -1,18 +1,68 @@
 use crate::server::entity::SyncFileInfo;
-use anyhow::Result;
+use anyhow::{bail, Result};
+use async_trait::async_trait;
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlobDigest {
+    pub digest: String,
+    pub size: u64,
+    /// How the backend is going to encode the blob, to be recorded as the blob's codec
+    pub codec: String,
+}
+
+/// Writes are staged away from the readable blob, it only becomes visible once committed
+#[async_trait]
 pub trait FileWriter: Send {
-    fn write(&mut self, data: &[u8]) -> Result<usize>;
-    fn close(&mut self);
+    async fn write(&mut self, data: &[u8]) -> Result<usize>;
+    /// Reads back what was staged so far, writing continues at the end regardless
+    async fn read_at(&mut self, pos: u64, data: &mut [u8]) -> Result<usize>;
+    /// Flushes everything written so far to stable storage, returns the digest and size of the whole blob
+    async fn close(&mut self) -> Result<BlobDigest>;
+    /// Publishes the staged blob under its hash, to be called only after its digest was verified
+    async fn commit(&mut self) -> Result<()>;
+    async fn discard(&mut self) -> Result<()>;
 }
 
+#[async_trait]
 pub trait FileReader: Send {
-    fn read(&mut self, data: &mut [u8]) -> Result<usize>;
-    fn close(&mut self);
+    /// Returns the number of bytes actually read, 0 once the end of the blob is reached
+    async fn read(&mut self, data: &mut [u8]) -> Result<usize>;
+    async fn seek(&mut self, pos: u64) -> Result<u64>;
+    /// Reads at `pos` without moving the position used by `read`
+    async fn read_at(&mut self, pos: u64, data: &mut [u8]) -> Result<usize>;
+    fn len(&self) -> u64;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    async fn close(&mut self);
+}
+
+#[async_trait]
+pub trait FileStorage: Send + Sync {
+    /// The writer continues after the first `file_info.sync_size` bytes of the blob
+    async fn open_writer(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileWriter>>;
+    /// Writes a new copy of a committed blob, staged apart from any upload of it so neither truncates the other
+    async fn open_rewriter(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileWriter>> {
+        bail!("rewriting blobs isn't supported by this backend:{}", file_info.storage_backend);
+    }
+    async fn open_reader(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileReader>>;
+    async fn delete_file(&self, file_info: &SyncFileInfo) -> Result<()>;
+    /// Re-wraps the blob's data key with the current master key, returns false if there was nothing to do
+    async fn rotate_key(&self, _file_info: &SyncFileInfo) -> Result<bool> {
+        Ok(false)
+    }
 }
 
-pub trait FileStorage: Send {
-    fn open_writer(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileWriter>>;
-    fn open_reader(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileReader>>;
-    fn delete_file(&self, file_hash: &str) -> Result<()>;
+/// Reads exactly `len` bytes at `pos`, failing if the blob ends before
+pub(crate) async fn read_stored(reader: &mut Box<dyn FileReader>, pos: u64, len: u64) -> Result<Vec<u8>> {
+    let mut data = vec![0u8; len as usize];
+    let mut filled = 0;
+    while filled < data.len() {
+        let len = reader.read_at(pos + filled as u64, &mut data[filled..]).await?;
+        if len == 0 {
+            bail!("blob ends before:{}", pos + data.len() as u64);
+        }
+        filled += len;
+    }
+    Ok(data)
 }
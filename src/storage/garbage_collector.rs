@@ -0,0 +1,138 @@
+use super::{chunked_file_reader::chunk_blob, file_storage::FileStorage, StorageContext};
+use crate::server::entity::SyncFileInfo;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GcConfig {
+    pub interval_secs: u64,
+    /// how long a blob stays around after its last reference is gone
+    pub grace_secs: u64,
+    /// partial uploads that haven't progressed for this long are dropped
+    pub partial_upload_ttl_secs: u64,
+    pub versions: VersionRetention,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 3600,
+            grace_secs: 24 * 3600,
+            partial_upload_ttl_secs: 7 * 24 * 3600,
+            versions: VersionRetention::default(),
+        }
+    }
+}
+
+/// Which prior versions of a file are kept, a version any of the rules keeps stays. Thinning only (no recent
+/// versions kept as such) is `keep_last` 0, keeping a fixed number is `keep_daily_days` and `keep_weekly_weeks` 0.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct VersionRetention {
+    /// the newest versions, whatever their age
+    pub keep_last: usize,
+    /// the newest version of each day, for this many days
+    pub keep_daily_days: u64,
+    /// the newest version of each week, for this many weeks after the daily ones
+    pub keep_weekly_weeks: u64,
+}
+
+impl Default for VersionRetention {
+    fn default() -> Self {
+        Self {
+            keep_last: 10,
+            keep_daily_days: 7,
+            keep_weekly_weeks: 4,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct GcStats {
+    pub pruned_versions: usize,
+    pub unreferenced_files: usize,
+    pub abandoned_files: usize,
+    pub unreferenced_chunks: usize,
+}
+
+pub struct GarbageCollector {
+    config: GcConfig,
+}
+
+impl GarbageCollector {
+    pub fn new(config: GcConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn start(self, storage_ctx: StorageContext) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+            let mut storage_ctx = storage_ctx;
+            loop {
+                interval.tick().await;
+
+                match self.collect(&mut storage_ctx).await {
+                    Ok(stats) => info!("gc completed:{stats:?}"),
+                    Err(e) => error!("gc failed: {e:?}"),
+                }
+            }
+        });
+    }
+
+    pub async fn collect(&self, storage_ctx: &mut StorageContext) -> Result<GcStats> {
+        let mut stats = GcStats::default();
+
+        // the blobs of pruned versions are collected below once their grace period is over
+        let retention = &self.config.versions;
+        stats.pruned_versions =
+            storage_ctx
+                .db
+                .prune_file_versions(retention.keep_last, retention.keep_daily_days, retention.keep_weekly_weeks)?;
+
+        for file_info in storage_ctx.db.query_unreferenced_files(self.config.grace_secs)? {
+            if storage_ctx
+                .db
+                .delete_unreferenced_file(&file_info.file_hash, self.config.grace_secs)?
+            {
+                // a chunked file has no blob, its chunks are collected below once no other file lists them
+                if !file_info.chunked {
+                    Self::delete_blob(storage_ctx.file_storage.as_ref(), &file_info).await;
+                }
+                stats.unreferenced_files += 1;
+            }
+        }
+
+        for file_info in storage_ctx.db.query_abandoned_files(self.config.partial_upload_ttl_secs)? {
+            if storage_ctx
+                .db
+                .delete_abandoned_file(&file_info.file_hash, self.config.partial_upload_ttl_secs)?
+            {
+                Self::delete_blob(storage_ctx.file_storage.as_ref(), &file_info).await;
+                stats.abandoned_files += 1;
+            }
+        }
+
+        // a chunk isn't referenced before the upload it belongs to is finished, so it's kept as long as a partial upload
+        let chunk_grace_secs = self.config.grace_secs.max(self.config.partial_upload_ttl_secs);
+        for chunk in storage_ctx.db.query_unreferenced_chunks(chunk_grace_secs)? {
+            if storage_ctx.db.delete_unreferenced_chunk(&chunk.file_hash, chunk_grace_secs)? {
+                Self::delete_blob(storage_ctx.file_storage.as_ref(), &chunk_blob(&chunk)).await;
+                stats.unreferenced_chunks += 1;
+            }
+        }
+
+        Ok(stats)
+    }
+
+    async fn delete_blob(file_storage: &dyn FileStorage, file_info: &SyncFileInfo) {
+        let file_hash = &file_info.file_hash;
+        debug!("deleting blob:{file_hash}, backend:{}", file_info.storage_backend);
+        if let Err(e) = file_storage.delete_file(file_info).await {
+            // the blob may never have been written, its record is gone either way
+            warn!("failed to delete blob:{file_hash}, {e}");
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use super::{chunked_file_reader::chunk_blob, file_storage::FileStorage, StorageContext};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct KeyRotationConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    /// how many blobs are looked at per database query
+    pub batch_size: usize,
+}
+
+impl Default for KeyRotationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 24 * 3600,
+            batch_size: 100,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct KeyRotationStats {
+    pub rotated_files: usize,
+    pub failed_files: usize,
+    pub rotated_chunks: usize,
+    pub failed_chunks: usize,
+}
+
+/// Re-wraps the data keys of encrypted blobs still using a master key that has been rotated out, once it's
+/// done the old key can be removed from the config
+pub struct KeyRotator {
+    config: KeyRotationConfig,
+}
+
+impl KeyRotator {
+    pub fn new(config: KeyRotationConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn start(self, storage_ctx: StorageContext) {
+        if !self.config.enabled {
+            return;
+        }
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+            let mut storage_ctx = storage_ctx;
+            loop {
+                interval.tick().await;
+
+                match self.rotate(&mut storage_ctx).await {
+                    Ok(stats) => info!("key rotation completed:{stats:?}"),
+                    Err(e) => error!("key rotation failed: {e:?}"),
+                }
+            }
+        });
+    }
+
+    pub async fn rotate(&self, storage_ctx: &mut StorageContext) -> Result<KeyRotationStats> {
+        let mut stats = KeyRotationStats::default();
+        let mut after_hash = String::new();
+        loop {
+            let files = storage_ctx.db.query_stored_files(&after_hash, self.config.batch_size)?;
+            let Some(last) = files.last() else {
+                break;
+            };
+            after_hash = last.file_hash.clone();
+
+            for file_info in files {
+                match storage_ctx.file_storage.rotate_key(&file_info).await {
+                    Ok(true) => stats.rotated_files += 1,
+                    Ok(false) => {}
+                    Err(e) => {
+                        warn!("failed to rotate key of blob:{}, {e:?}", file_info.file_hash);
+                        stats.failed_files += 1;
+                    }
+                }
+            }
+        }
+
+        let mut after_hash = String::new();
+        loop {
+            let chunks = storage_ctx.db.query_stored_chunks(&after_hash, self.config.batch_size)?;
+            let Some(last) = chunks.last() else {
+                break;
+            };
+            after_hash = last.file_hash.clone();
+
+            for chunk in chunks {
+                match storage_ctx.file_storage.rotate_key(&chunk_blob(&chunk)).await {
+                    Ok(true) => stats.rotated_chunks += 1,
+                    Ok(false) => {}
+                    Err(e) => {
+                        warn!("failed to rotate key of chunk:{}, {e:?}", chunk.file_hash);
+                        stats.failed_chunks += 1;
+                    }
+                }
+            }
+        }
+        Ok(stats)
+    }
+}
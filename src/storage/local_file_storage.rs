@@ -1,83 +1,252 @@
 use std::{
-    fs::{self, File},
-    io::{Read, Write},
-    path::PathBuf,
+    io::{ErrorKind, SeekFrom},
+    path::{Path, PathBuf},
 };
 
-use super::file_storage::{FileReader, FileStorage, FileWriter};
-use crate::server::entity::SyncFileInfo;
-use anyhow::{Context, Result};
+use super::file_storage::{BlobDigest, FileReader, FileStorage, FileWriter};
+use crate::{
+    common::hasher::{ContentHasher, HashAlgorithm},
+    server::entity::SyncFileInfo,
+};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter},
+};
+
+const WRITE_BUFFER_SIZE: usize = 256 * 1024;
+const STAGING_DIR: &str = "tmp";
 
 pub struct LocalFileStorage {
     base_dir: PathBuf,
+    hash_algorithm: HashAlgorithm,
+}
+
+/// A blob being written to the local staging dir, shared by the backends that stage uploads locally
+pub(crate) struct StagedBlob {
+    file: BufWriter<File>,
+    hasher: ContentHasher,
+    size: u64,
+    path: PathBuf,
 }
 
 pub struct LocalFileWriter {
-    file: File,
+    staged: StagedBlob,
+    blob_path: PathBuf,
 }
 pub struct LocalFileReader {
     file: File,
+    len: u64,
 }
 
+impl StagedBlob {
+    /// Opens the staged blob positioned at `offset` for resuming, anything written past it was never acknowledged
+    /// and is dropped. The bytes before `offset` are hashed again, so the digest covers the whole blob.
+    pub(crate) async fn open(path: PathBuf, offset: u64, hash_algorithm: HashAlgorithm) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await.context(format!("failed to create dir:{dir:?}"))?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await
+            .context(format!("failed to open file:{path:?}"))?;
+
+        let len = file.metadata().await?.len();
+        if len < offset {
+            bail!("blob is shorter than the resume offset:{path:?}, {len}/{offset}");
+        }
+        file.set_len(offset).await?;
+
+        let mut hasher = hash_algorithm.hasher();
+        let mut buf = vec![0u8; WRITE_BUFFER_SIZE];
+        loop {
+            let len = file.read(&mut buf).await?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+        }
+
+        Ok(Self {
+            file: BufWriter::with_capacity(WRITE_BUFFER_SIZE, file),
+            hasher,
+            size: offset,
+            path,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) async fn write(&mut self, data: &[u8]) -> Result<usize> {
+        self.file.write_all(data).await?;
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+        Ok(data.len())
+    }
+
+    pub(crate) async fn read_at(&mut self, pos: u64, data: &mut [u8]) -> Result<usize> {
+        self.file.flush().await?;
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(pos)).await?;
+        let result = file.read(data).await;
+        file.seek(SeekFrom::End(0)).await?;
+        Ok(result?)
+    }
+
+    pub(crate) async fn close(&mut self) -> Result<BlobDigest> {
+        self.file.flush().await?;
+        self.file.get_ref().sync_all().await?;
+        Ok(BlobDigest {
+            digest: self.hasher.clone().finalize(),
+            size: self.size,
+            codec: String::new(),
+        })
+    }
+
+    pub(crate) async fn discard(&mut self) -> Result<()> {
+        Ok(fs::remove_file(&self.path).await?)
+    }
+}
+
+#[async_trait]
 impl FileWriter for LocalFileWriter {
-    fn write(&mut self, data: &[u8]) -> Result<usize> {
-        self.file.write_all(data).map(|_| Ok(data.len()))?
+    async fn write(&mut self, data: &[u8]) -> Result<usize> {
+        self.staged.write(data).await
     }
 
-    fn close(&mut self) {
-        // do nothing
+    async fn read_at(&mut self, pos: u64, data: &mut [u8]) -> Result<usize> {
+        self.staged.read_at(pos, data).await
+    }
+
+    async fn close(&mut self) -> Result<BlobDigest> {
+        self.staged.close().await
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        self.staged.close().await?;
+        let staging_path = self.staged.path();
+        let dir = self.blob_path.parent().context("blob path without parent")?;
+        fs::create_dir_all(dir).await.context(format!("failed to create dir:{dir:?}"))?;
+        fs::rename(staging_path, &self.blob_path)
+            .await
+            .context(format!("failed to move {:?} to {:?}", staging_path, self.blob_path))?;
+        // the rename itself only survives a crash once the directory is synced
+        File::open(dir).await?.sync_all().await?;
+        Ok(())
+    }
+
+    async fn discard(&mut self) -> Result<()> {
+        self.staged.discard().await
     }
 }
 
+#[async_trait]
 impl FileReader for LocalFileReader {
-    fn read(&mut self, data: &mut [u8]) -> Result<usize> {
-        self.file.read(data).map(|_| Ok(data.len()))?
+    async fn read(&mut self, data: &mut [u8]) -> Result<usize> {
+        Ok(self.file.read(data).await?)
+    }
+
+    async fn seek(&mut self, pos: u64) -> Result<u64> {
+        Ok(self.file.seek(SeekFrom::Start(pos)).await?)
+    }
+
+    async fn read_at(&mut self, pos: u64, data: &mut [u8]) -> Result<usize> {
+        let current = self.file.stream_position().await?;
+        self.file.seek(SeekFrom::Start(pos)).await?;
+        let result = self.file.read(data).await;
+        self.file.seek(SeekFrom::Start(current)).await?;
+        Ok(result?)
+    }
+
+    fn len(&self) -> u64 {
+        self.len
     }
 
-    fn close(&mut self) {
+    async fn close(&mut self) {
         // do nothing
     }
 }
 
 impl LocalFileStorage {
-    pub fn new(base_dir: PathBuf) -> Self {
-        Self { base_dir }
+    pub fn new(base_dir: PathBuf, hash_algorithm: HashAlgorithm) -> Self {
+        Self { base_dir, hash_algorithm }
     }
 }
 
 impl LocalFileStorage {
-    fn open_file(&self, file_hash: &str) -> Result<File> {
+    fn blob_path(&self, file_hash: &str) -> PathBuf {
         let mut path = PathBuf::new();
         path.push(&self.base_dir);
         path.push(&file_hash[..2]);
+        path.push(&file_hash[2..]);
+        path
+    }
 
-        if !path.exists() {
-            fs::create_dir_all(&path).context(format!("failed to create dir:{path:?}"))?;
-        }
+    fn staging_path(&self, file_hash: &str) -> PathBuf {
+        staging_path(&self.base_dir, file_hash)
+    }
 
-        path.push(&file_hash[2..]);
-        Ok(File::create(&path).context(format!("failed to create file:{path:?}"))?)
+    async fn open_staged(&self, staging_path: PathBuf, file_info: &SyncFileInfo) -> Result<Box<dyn FileWriter>> {
+        let staged = StagedBlob::open(staging_path, file_info.sync_size as u64, self.hash_algorithm).await?;
+        Ok(Box::new(LocalFileWriter {
+            staged,
+            blob_path: self.blob_path(&file_info.file_hash),
+        }))
     }
 }
 
+/// Where uploads of `file_hash` are staged before being committed. Any session can resume from it, the storage
+/// registry makes sure only one writer holds it at a time.
+pub(crate) fn staging_path(base_dir: &Path, file_hash: &str) -> PathBuf {
+    base_dir.join(STAGING_DIR).join(file_hash)
+}
+
+/// Where a committed blob is staged while being rewritten, e.g. with a new key, apart from any upload of it
+pub(crate) fn rewrite_staging_path(base_dir: &Path, file_hash: &str) -> PathBuf {
+    base_dir.join(STAGING_DIR).join(format!("{file_hash}.rewrite"))
+}
+
+#[async_trait]
 impl FileStorage for LocalFileStorage {
-    fn open_writer(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileWriter>> {
-        Ok(Box::new(LocalFileWriter {
-            file: self.open_file(&file_info.file_hash)?,
-        }))
+    async fn open_writer(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileWriter>> {
+        self.open_staged(self.staging_path(&file_info.file_hash), file_info).await
     }
 
-    fn open_reader(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileReader>> {
-        Ok(Box::new(LocalFileReader {
-            file: self.open_file(&file_info.file_hash)?,
-        }))
+    async fn open_rewriter(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileWriter>> {
+        self.open_staged(rewrite_staging_path(&self.base_dir, &file_info.file_hash), file_info)
+            .await
     }
 
-    fn delete_file(&self, file_hash: &str) -> Result<()> {
-        let mut path = PathBuf::new();
-        path.push(&self.base_dir);
-        path.push(&file_hash[..2]);
-        path.push(&file_hash[2..]);
-        Ok(fs::remove_file(path)?)
+    async fn open_reader(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileReader>> {
+        let path = self.blob_path(&file_info.file_hash);
+        let file = File::open(&path).await.context(format!("failed to open file:{path:?}"))?;
+        let len = file.metadata().await?.len();
+        Ok(Box::new(LocalFileReader { file, len }))
+    }
+
+    /// Removes the blob whether it's committed or still staged
+    async fn delete_file(&self, file_info: &SyncFileInfo) -> Result<()> {
+        let file_hash = &file_info.file_hash;
+        let mut deleted = false;
+        for path in [self.blob_path(file_hash), self.staging_path(file_hash)] {
+            match fs::remove_file(&path).await {
+                Ok(_) => deleted = true,
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e).context(format!("failed to delete file:{path:?}")),
+            }
+        }
+
+        if !deleted {
+            bail!("no such blob:{file_hash}");
+        }
+        Ok(())
     }
 }
@@ -1,12 +1,41 @@
+pub mod async_file_reader;
+pub mod chunked_file_reader;
+pub mod compressed_file_storage;
 pub mod database;
 pub mod database_manager;
+pub mod encrypted_file_storage;
 pub mod file_storage;
+pub mod garbage_collector;
+pub mod key_rotator;
 pub mod local_file_storage;
+pub mod s3_file_storage;
 pub mod sqlite_database;
+pub mod storage_registry;
+pub mod tier_migrator;
 
-use self::{database::Database, file_storage::FileStorage};
+use self::{
+    chunked_file_reader::ChunkedFileReader,
+    database::Database,
+    file_storage::{FileReader, FileStorage},
+    storage_registry::StorageRegistry,
+};
+use crate::server::entity::SyncFileInfo;
+use anyhow::Result;
+use std::sync::Arc;
 
 pub struct StorageContext {
     pub db: Box<dyn Database>,
-    pub file_storage: Box<dyn FileStorage>,
+    pub file_storage: Arc<StorageRegistry>,
+}
+
+impl StorageContext {
+    /// Opens the file's content, whether it's stored as a blob or as the chunks of its manifest
+    pub async fn open_reader(&mut self, file_info: &SyncFileInfo) -> Result<Box<dyn FileReader>> {
+        if !file_info.chunked {
+            return self.file_storage.open_reader(file_info).await;
+        }
+
+        let chunks = self.db.query_file_chunks(&file_info.file_hash)?;
+        Ok(Box::new(ChunkedFileReader::new(self.file_storage.clone(), chunks)))
+    }
 }
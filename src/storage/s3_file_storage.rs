@@ -0,0 +1,389 @@
+use std::{io::ErrorKind, path::PathBuf, sync::Arc};
+
+use super::{
+    file_storage::{BlobDigest, FileReader, FileStorage, FileWriter},
+    local_file_storage::{self, StagedBlob},
+};
+use crate::{common::hasher::HashAlgorithm, server::entity::SyncFileInfo};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use object_store::{aws::AmazonS3Builder, memory::InMemory, path::Path, ObjectStore, PutPayload};
+use serde::{Deserialize, Serialize};
+use tokio::{fs::File, io::AsyncReadExt};
+use tracing::{debug, warn};
+
+/// S3 requires every part except the last one to be at least 5MiB
+const MULTIPART_PART_SIZE: usize = 8 * 1024 * 1024;
+/// Ranged GETs fetch at least this much, so small reads don't each cost a request
+const READ_AHEAD_SIZE: usize = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Set for S3-compatible services such as MinIO, e.g. `http://127.0.0.1:9000`
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    /// Credentials fall back to the usual `AWS_*` environment variables
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    /// Key prefix the blobs are stored under
+    pub prefix: String,
+    pub allow_http: bool,
+}
+
+/// Stores blobs as objects, uploads are staged in `staging_dir` and only sent to the bucket once committed
+pub struct S3FileStorage {
+    store: Arc<dyn ObjectStore>,
+    prefix: String,
+    staging_dir: PathBuf,
+    hash_algorithm: HashAlgorithm,
+}
+
+pub struct S3FileWriter {
+    staged: StagedBlob,
+    store: Arc<dyn ObjectStore>,
+    location: Path,
+}
+
+pub struct S3FileReader {
+    store: Arc<dyn ObjectStore>,
+    location: Path,
+    len: u64,
+    pos: u64,
+    buf: Vec<u8>,
+    buf_start: u64,
+}
+
+impl S3FileStorage {
+    pub fn new(store: Arc<dyn ObjectStore>, prefix: &str, staging_dir: PathBuf, hash_algorithm: HashAlgorithm) -> Self {
+        Self {
+            store,
+            prefix: prefix.to_string(),
+            staging_dir,
+            hash_algorithm,
+        }
+    }
+
+    pub fn from_config(config: &S3Config, staging_dir: PathBuf, hash_algorithm: HashAlgorithm) -> Result<Self> {
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(&config.bucket)
+            .with_allow_http(config.allow_http);
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder.with_endpoint(endpoint);
+        }
+        if let Some(region) = &config.region {
+            builder = builder.with_region(region);
+        }
+        if let Some(access_key_id) = &config.access_key_id {
+            builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Some(secret_access_key) = &config.secret_access_key {
+            builder = builder.with_secret_access_key(secret_access_key);
+        }
+
+        let store = builder
+            .build()
+            .context(format!("failed to create s3 client, bucket:{}", config.bucket))?;
+        Ok(Self::new(Arc::new(store), &config.prefix, staging_dir, hash_algorithm))
+    }
+
+    /// Keeps the objects in memory, behaves like a bucket without needing one
+    pub fn in_memory(staging_dir: PathBuf, hash_algorithm: HashAlgorithm) -> Self {
+        Self::new(Arc::new(InMemory::new()), "", staging_dir, hash_algorithm)
+    }
+
+    fn location(&self, file_hash: &str) -> Path {
+        Path::from(format!("{}/{}/{}", self.prefix, &file_hash[..2], &file_hash[2..]))
+    }
+
+    async fn open_staged(&self, staging_path: PathBuf, file_info: &SyncFileInfo) -> Result<Box<dyn FileWriter>> {
+        let staged = StagedBlob::open(staging_path, file_info.sync_size as u64, self.hash_algorithm).await?;
+        Ok(Box::new(S3FileWriter {
+            staged,
+            store: self.store.clone(),
+            location: self.location(&file_info.file_hash),
+        }))
+    }
+}
+
+impl S3FileWriter {
+    async fn upload(&mut self, size: u64) -> Result<()> {
+        let mut file = File::open(self.staged.path()).await?;
+        if size <= MULTIPART_PART_SIZE as u64 {
+            let mut data = Vec::with_capacity(size as usize);
+            file.read_to_end(&mut data).await?;
+            self.store.put(&self.location, PutPayload::from(data)).await?;
+            return Ok(());
+        }
+
+        let mut upload = self.store.put_multipart(&self.location).await?;
+        let result = async {
+            loop {
+                let mut part = Vec::with_capacity(MULTIPART_PART_SIZE);
+                (&mut file).take(MULTIPART_PART_SIZE as u64).read_to_end(&mut part).await?;
+                if part.is_empty() {
+                    break;
+                }
+                upload.put_part(PutPayload::from(part)).await?;
+            }
+            upload.complete().await?;
+            anyhow::Ok(())
+        }
+        .await;
+
+        if result.is_err() {
+            if let Err(e) = upload.abort().await {
+                warn!("failed to abort multipart upload:{}, error:{e}", self.location);
+            }
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl FileWriter for S3FileWriter {
+    async fn write(&mut self, data: &[u8]) -> Result<usize> {
+        self.staged.write(data).await
+    }
+
+    async fn read_at(&mut self, pos: u64, data: &mut [u8]) -> Result<usize> {
+        self.staged.read_at(pos, data).await
+    }
+
+    async fn close(&mut self) -> Result<BlobDigest> {
+        self.staged.close().await
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        let digest = self.staged.close().await?;
+        self.upload(digest.size)
+            .await
+            .context(format!("failed to upload blob:{}", self.location))?;
+        debug!("uploaded blob:{}, size:{}", self.location, digest.size);
+        self.staged.discard().await
+    }
+
+    async fn discard(&mut self) -> Result<()> {
+        self.staged.discard().await
+    }
+}
+
+impl S3FileReader {
+    /// Serves `pos` from the read-ahead buffer, fetching the range starting there first if needed
+    async fn fill_buf(&mut self, pos: u64, wanted: usize) -> Result<&[u8]> {
+        let buf_end = self.buf_start + self.buf.len() as u64;
+        if pos < self.buf_start || pos >= buf_end {
+            let end = self.len.min(pos + wanted.max(READ_AHEAD_SIZE) as u64);
+            self.buf = self.store.get_range(&self.location, pos..end).await?.to_vec();
+            self.buf_start = pos;
+        }
+        Ok(&self.buf[(pos - self.buf_start) as usize..])
+    }
+}
+
+#[async_trait]
+impl FileReader for S3FileReader {
+    async fn read(&mut self, data: &mut [u8]) -> Result<usize> {
+        let len = self.read_at(self.pos, data).await?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    async fn seek(&mut self, pos: u64) -> Result<u64> {
+        self.pos = pos;
+        Ok(pos)
+    }
+
+    async fn read_at(&mut self, pos: u64, data: &mut [u8]) -> Result<usize> {
+        if pos >= self.len || data.is_empty() {
+            return Ok(0);
+        }
+        let buf = self.fill_buf(pos, data.len()).await?;
+        let len = buf.len().min(data.len());
+        data[..len].copy_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    fn len(&self) -> u64 {
+        self.len
+    }
+
+    async fn close(&mut self) {
+        self.buf = Vec::new();
+    }
+}
+
+#[async_trait]
+impl FileStorage for S3FileStorage {
+    async fn open_writer(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileWriter>> {
+        let staging_path = local_file_storage::staging_path(&self.staging_dir, &file_info.file_hash);
+        self.open_staged(staging_path, file_info).await
+    }
+
+    async fn open_rewriter(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileWriter>> {
+        let staging_path = local_file_storage::rewrite_staging_path(&self.staging_dir, &file_info.file_hash);
+        self.open_staged(staging_path, file_info).await
+    }
+
+    async fn open_reader(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileReader>> {
+        let location = self.location(&file_info.file_hash);
+        let meta = self
+            .store
+            .head(&location)
+            .await
+            .context(format!("failed to open blob:{location}"))?;
+        Ok(Box::new(S3FileReader {
+            store: self.store.clone(),
+            location,
+            len: meta.size,
+            pos: 0,
+            buf: Vec::new(),
+            buf_start: 0,
+        }))
+    }
+
+    /// Removes the blob whether it's committed or still staged
+    async fn delete_file(&self, file_info: &SyncFileInfo) -> Result<()> {
+        let file_hash = &file_info.file_hash;
+        let location = self.location(file_hash);
+        // S3 and the in-memory store report success for missing keys, so deleting a missing blob isn't an error here
+        let mut deleted = match self.store.delete(&location).await {
+            Ok(_) => true,
+            Err(object_store::Error::NotFound { .. }) => false,
+            Err(e) => return Err(e).context(format!("failed to delete blob:{location}")),
+        };
+
+        let staging_path = local_file_storage::staging_path(&self.staging_dir, file_hash);
+        match tokio::fs::remove_file(&staging_path).await {
+            Ok(_) => deleted = true,
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(format!("failed to delete file:{staging_path:?}")),
+        }
+
+        if !deleted {
+            bail!("no such blob:{file_hash}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("rsdrive-s3-{}", rand::random::<u64>()))
+    }
+
+    fn blob_info(data: &[u8], sync_size: usize) -> SyncFileInfo {
+        SyncFileInfo {
+            file_hash: HashAlgorithm::Sha256.hash(data),
+            sync_size,
+            file_size: data.len(),
+            ..Default::default()
+        }
+    }
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 253) as u8).collect()
+    }
+
+    async fn store(storage: &S3FileStorage, data: &[u8]) -> SyncFileInfo {
+        let file_info = blob_info(data, 0);
+        let mut writer = storage.open_writer(&file_info).await.unwrap();
+        writer.write(data).await.unwrap();
+        assert_eq!(writer.close().await.unwrap().digest, file_info.file_hash);
+        writer.commit().await.unwrap();
+        file_info
+    }
+
+    #[tokio::test]
+    async fn commits_to_the_bucket_and_reads_ranges() {
+        let dir = test_dir();
+        let bucket = Arc::new(InMemory::new());
+        let storage = S3FileStorage::new(bucket.clone(), "blobs", dir.clone(), HashAlgorithm::Sha256);
+        let data = test_data(READ_AHEAD_SIZE + 5000);
+        let file_info = store(&storage, &data).await;
+
+        let location = storage.location(&file_info.file_hash);
+        assert!(location.as_ref().starts_with("blobs/"));
+        assert_eq!(bucket.head(&location).await.unwrap().size, data.len() as u64);
+        assert!(!local_file_storage::staging_path(&dir, &file_info.file_hash).exists());
+
+        let mut reader = storage.open_reader(&file_info).await.unwrap();
+        assert_eq!(reader.len(), data.len() as u64);
+        let mut buf = vec![0u8; 100];
+        for pos in [0, 10, READ_AHEAD_SIZE - 50, READ_AHEAD_SIZE + 4950] {
+            let len = reader.read_at(pos as u64, &mut buf).await.unwrap();
+            assert_eq!(&buf[..len], &data[pos..pos + len]);
+        }
+        assert_eq!(reader.read_at(data.len() as u64, &mut buf).await.unwrap(), 0);
+
+        reader.seek(7).await.unwrap();
+        assert_eq!(reader.read(&mut buf).await.unwrap(), 100);
+        assert_eq!(buf, &data[7..107]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn resumes_a_staged_upload() {
+        let dir = test_dir();
+        let storage = S3FileStorage::in_memory(dir.clone(), HashAlgorithm::Sha256);
+        let data = test_data(10_000);
+
+        let mut writer = storage.open_writer(&blob_info(&data, 0)).await.unwrap();
+        // written past what was acknowledged, dropped on resume
+        writer.write(&test_data(6000)).await.unwrap();
+        writer.close().await.unwrap();
+        drop(writer);
+
+        let file_info = blob_info(&data, 4000);
+        let mut writer = storage.open_writer(&file_info).await.unwrap();
+        writer.write(&data[4000..]).await.unwrap();
+        let blob = writer.close().await.unwrap();
+        assert_eq!((blob.digest.as_str(), blob.size), (file_info.file_hash.as_str(), data.len() as u64));
+        writer.commit().await.unwrap();
+
+        let mut reader = storage.open_reader(&file_info).await.unwrap();
+        let mut buf = vec![0u8; data.len()];
+        assert_eq!(reader.read_at(0, &mut buf).await.unwrap(), data.len());
+        assert_eq!(buf, data);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn uploads_large_blobs_in_parts() {
+        let dir = test_dir();
+        let storage = S3FileStorage::in_memory(dir.clone(), HashAlgorithm::Sha256);
+        let data = test_data(2 * MULTIPART_PART_SIZE + 100);
+        let file_info = store(&storage, &data).await;
+
+        let mut reader = storage.open_reader(&file_info).await.unwrap();
+        assert_eq!(reader.len(), data.len() as u64);
+        let mut buf = vec![0u8; 200];
+        let pos = MULTIPART_PART_SIZE - 100;
+        assert_eq!(reader.read_at(pos as u64, &mut buf).await.unwrap(), 200);
+        assert_eq!(buf, &data[pos..pos + 200]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn deletes_committed_and_staged_blobs() {
+        let dir = test_dir();
+        let storage = S3FileStorage::in_memory(dir.clone(), HashAlgorithm::Sha256);
+        let committed = store(&storage, &test_data(100)).await;
+        storage.delete_file(&committed).await.unwrap();
+        assert!(storage.open_reader(&committed).await.is_err());
+        storage.delete_file(&committed).await.unwrap();
+
+        let data = test_data(200);
+        let mut writer = storage.open_writer(&blob_info(&data, 0)).await.unwrap();
+        writer.write(&data[..50]).await.unwrap();
+        writer.close().await.unwrap();
+        drop(writer);
+        storage.delete_file(&blob_info(&data, 0)).await.unwrap();
+        assert!(!local_file_storage::staging_path(&dir, &blob_info(&data, 0).file_hash).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,4 +1,7 @@
 use super::database::Database;
+use crate::common::entity::FileVersion;
+use crate::server::entity::ApiToken;
+use crate::server::entity::Session;
 use crate::server::entity::SyncFileInfo;
 use crate::server::entity::User;
 use anyhow::bail;
@@ -6,13 +9,23 @@ use anyhow::Result;
 use rs_utilities::log_and_bail;
 use rusqlite::Connection;
 use std::path::Path;
+use std::time::Duration;
 use tracing::debug;
 use tracing::error;
+use tracing::warn;
 
 pub struct SqliteDatabase {
     conn: Connection,
 }
 
+/// A missing row is an ordinary miss, only other failures are errors
+fn log_query_error(e: rusqlite::Error) {
+    match e {
+        rusqlite::Error::QueryReturnedNoRows => debug!("{e}"),
+        e => error!("{e}"),
+    }
+}
+
 impl SqliteDatabase {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
         Ok(Self { conn: Self::init(path)? })
@@ -20,6 +33,8 @@ impl SqliteDatabase {
 
     fn init<P: AsRef<Path>>(path: P) -> Result<Connection> {
         let conn = Connection::open(path.as_ref())?;
+        // every request opens its own connection and the gc keeps one too, so wait for locks instead of failing
+        conn.busy_timeout(Duration::from_secs(5))?;
         let sql = "
             CREATE TABLE IF NOT EXISTS user (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -30,15 +45,40 @@ impl SqliteDatabase {
                 create_time DATETIME NOT NULL DEFAULT (datetime(CURRENT_TIMESTAMP, 'localtime'))
             );
 
+            CREATE TABLE IF NOT EXISTS session (
+                token_hash TEXT PRIMARY KEY,
+                user_id INTEGER NOT NULL,
+                create_time DATETIME NOT NULL DEFAULT (datetime(CURRENT_TIMESTAMP, 'localtime')),
+                expire_time DATETIME NOT NULL
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_session_user ON session (user_id);
+
+            CREATE TABLE IF NOT EXISTS api_token (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                token_hash TEXT NOT NULL UNIQUE,
+                scopes TEXT NOT NULL,
+                expire_time DATETIME,
+                create_time DATETIME NOT NULL DEFAULT (datetime(CURRENT_TIMESTAMP, 'localtime'))
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_api_token_user ON api_token (user_id);
+
             CREATE TABLE IF NOT EXISTS shared_file (
                 file_hash TEXT PRIMARY KEY,
                 ref_count INTEGER NOT NULL,
                 file_size INTEGER NOT NULL,
                 sync_size INTEGER NOT NULL,
                 sync_completed INTEGER NOT NULL DEFAULT 0 CHECK (sync_completed IN (0, 1)),
-                create_time DATETIME NOT NULL DEFAULT (datetime(CURRENT_TIMESTAMP, 'localtime'))
+                storage_backend TEXT NOT NULL DEFAULT '',
+                codec TEXT NOT NULL DEFAULT '',
+                chunked INTEGER NOT NULL DEFAULT 0 CHECK (chunked IN (0, 1)),
+                create_time DATETIME NOT NULL DEFAULT (datetime(CURRENT_TIMESTAMP, 'localtime')),
+                update_time DATETIME
             );
-            
+
             CREATE TABLE IF NOT EXISTS user_file (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 user_id INTEGER NOT NULL,
@@ -46,13 +86,46 @@ impl SqliteDatabase {
                 file_dir TEXT NOT NULL,
                 file_name TEXT NOT NULL,
                 file_meta TEXT NOT NULL DEFAULT '',
+                device TEXT NOT NULL DEFAULT '',
                 file_create_time DATETIME NOT NULL DEFAULT (datetime(CURRENT_TIMESTAMP, 'localtime')),
                 record_create_time DATETIME NOT NULL DEFAULT (datetime(CURRENT_TIMESTAMP, 'localtime')),
+                update_time DATETIME,
                 UNIQUE (user_id, file_name, file_dir)
             );
 
             CREATE INDEX IF NOT EXISTS idx_user_file ON user_file (user_id, file_dir, file_name);
 
+            CREATE TABLE IF NOT EXISTS file_version (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id INTEGER NOT NULL,
+                file_dir TEXT NOT NULL,
+                file_name TEXT NOT NULL,
+                file_hash TEXT NOT NULL,
+                file_meta TEXT NOT NULL DEFAULT '',
+                device TEXT NOT NULL DEFAULT '',
+                create_time DATETIME NOT NULL,
+                archive_time DATETIME NOT NULL DEFAULT (datetime(CURRENT_TIMESTAMP, 'localtime'))
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_file_version ON file_version (user_id, file_dir, file_name);
+
+            CREATE TABLE IF NOT EXISTS chunk (
+                chunk_hash TEXT PRIMARY KEY,
+                chunk_size INTEGER NOT NULL,
+                storage_backend TEXT NOT NULL,
+                codec TEXT NOT NULL DEFAULT '',
+                ref_count INTEGER NOT NULL DEFAULT 0,
+                create_time DATETIME NOT NULL DEFAULT (datetime(CURRENT_TIMESTAMP, 'localtime')),
+                update_time DATETIME
+            );
+
+            CREATE TABLE IF NOT EXISTS file_chunk (
+                file_hash TEXT NOT NULL,
+                chunk_index INTEGER NOT NULL,
+                chunk_hash TEXT NOT NULL,
+                PRIMARY KEY (file_hash, chunk_index)
+            );
+
             ";
 
         match conn.execute_batch(sql) {
@@ -63,27 +136,205 @@ impl SqliteDatabase {
             }
         }
 
+        // columns added after the table was first created
+        Self::add_column_if_missing(&conn, "shared_file", "update_time", "DATETIME")?;
+        Self::add_column_if_missing(&conn, "shared_file", "storage_backend", "TEXT NOT NULL DEFAULT ''")?;
+        Self::add_column_if_missing(&conn, "shared_file", "codec", "TEXT NOT NULL DEFAULT ''")?;
+        Self::add_column_if_missing(&conn, "shared_file", "chunked", "INTEGER NOT NULL DEFAULT 0")?;
+        Self::add_column_if_missing(&conn, "user_file", "device", "TEXT NOT NULL DEFAULT ''")?;
+        Self::add_column_if_missing(&conn, "user_file", "update_time", "DATETIME")?;
+
+        // usernames weren't unique before, the old test login added a user on every login
+        Self::merge_duplicate_users(&conn)?;
+        conn.execute_batch("CREATE UNIQUE INDEX IF NOT EXISTS idx_user_username ON user (username)")?;
+
         Ok(conn)
     }
+
+    /// Keeps the oldest user of each username, which is the one logins resolved to, and moves whatever the others
+    /// own over to it. A file at a path the kept user already has is dropped along with its reference.
+    fn merge_duplicate_users(conn: &Connection) -> Result<()> {
+        let sql = "
+            SELECT u.id, k.id FROM user AS u
+            JOIN (SELECT username, MIN(id) AS id FROM user GROUP BY username) AS k ON u.username = k.username
+            WHERE u.id != k.id";
+        let mut stmt = conn.prepare(sql)?;
+        let duplicates = stmt
+            .query_map([], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, u32>(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        drop(stmt);
+        if duplicates.is_empty() {
+            return Ok(());
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        for (duplicate_id, user_id) in &duplicates {
+            for table in ["session", "api_token", "file_version"] {
+                tx.execute(
+                    &format!("UPDATE {table} SET user_id = ? WHERE user_id = ?"),
+                    rusqlite::params![user_id, duplicate_id],
+                )?;
+            }
+            tx.execute(
+                "UPDATE OR IGNORE user_file SET user_id = ? WHERE user_id = ?",
+                rusqlite::params![user_id, duplicate_id],
+            )?;
+
+            let mut stmt = tx.prepare("SELECT file_hash FROM user_file WHERE user_id = ?")?;
+            let file_hashes = stmt
+                .query_map(rusqlite::params![duplicate_id], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            drop(stmt);
+            for file_hash in &file_hashes {
+                Self::release_shared_file(&tx, file_hash)?;
+            }
+            tx.execute("DELETE FROM user_file WHERE user_id = ?", rusqlite::params![duplicate_id])?;
+            tx.execute("DELETE FROM user WHERE id = ?", rusqlite::params![duplicate_id])?;
+            warn!("merged duplicate user:{duplicate_id} into user:{user_id}");
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<()> {
+        let sql = "SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?";
+        let exists: bool = conn.query_row(sql, rusqlite::params![table, column], |row| row.get(0))?;
+        if !exists {
+            debug!("adding column {table}.{column}");
+            conn.execute_batch(&format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"))?;
+        }
+        Ok(())
+    }
+
+    /// Drops one reference to the blob, the blob itself is left for the garbage collector
+    fn release_shared_file(conn: &Connection, file_hash: &str) -> Result<()> {
+        let sql = "
+            UPDATE shared_file
+            SET ref_count = ref_count - 1, update_time = datetime(CURRENT_TIMESTAMP, 'localtime')
+            WHERE file_hash = ? AND ref_count > 0";
+        conn.execute(sql, rusqlite::params![file_hash])?;
+        Ok(())
+    }
+
+    /// Turns the file's current content into a version of the file, the reference the file holds moves along.
+    /// Content that was never completely stored isn't worth keeping, its reference is dropped instead.
+    fn archive_file_content(conn: &Connection, user_id: u32, file_dir: &str, file_name: &str) -> Result<()> {
+        let sql = "
+            INSERT INTO file_version (user_id, file_dir, file_name, file_hash, file_meta, device, create_time)
+            SELECT u.user_id, u.file_dir, u.file_name, u.file_hash, u.file_meta, u.device,
+                COALESCE(u.update_time, u.record_create_time)
+            FROM user_file AS u
+            JOIN shared_file AS s ON u.file_hash = s.file_hash
+            WHERE u.user_id = ? AND u.file_dir = ? AND u.file_name = ? AND s.sync_completed = 1";
+        if conn.execute(sql, rusqlite::params![user_id, file_dir, file_name])? == 0 {
+            if let Some(file_hash) = Self::query_user_file_hash(conn, user_id, file_dir, file_name) {
+                Self::release_shared_file(conn, &file_hash)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Points the file at `file_hash`, the reference on it is up to the caller
+    fn set_file_content(
+        conn: &Connection,
+        user_id: u32,
+        file_dir: &str,
+        file_name: &str,
+        file_hash: &str,
+        file_meta: &str,
+        device: &str,
+    ) -> Result<()> {
+        let sql = "
+            INSERT INTO user_file (user_id, file_hash, file_dir, file_name, file_meta, device, update_time)
+            VALUES (?, ?, ?, ?, ?, ?, datetime(CURRENT_TIMESTAMP, 'localtime'))
+            ON CONFLICT(user_id, file_dir, file_name)
+            DO UPDATE SET file_hash = excluded.file_hash, file_meta = excluded.file_meta, device = excluded.device,
+                update_time = excluded.update_time";
+        conn.execute(sql, rusqlite::params![user_id, file_hash, file_dir, file_name, file_meta, device])?;
+        Ok(())
+    }
+
+    fn map_shared_file(row: &rusqlite::Row) -> rusqlite::Result<SyncFileInfo> {
+        Ok(SyncFileInfo {
+            file_hash: row.get(0)?,
+            sync_size: row.get(1)?,
+            file_size: row.get(2)?,
+            storage_backend: row.get(3)?,
+            codec: row.get(4)?,
+            chunked: row.get(5)?,
+            ..Default::default()
+        })
+    }
+
+    fn map_chunk(row: &rusqlite::Row) -> rusqlite::Result<SyncFileInfo> {
+        Ok(SyncFileInfo {
+            file_hash: row.get(0)?,
+            sync_size: row.get(1)?,
+            file_size: row.get(1)?,
+            storage_backend: row.get(2)?,
+            codec: row.get(3)?,
+            ..Default::default()
+        })
+    }
+
+    fn query_user_file_hash(conn: &Connection, user_id: u32, file_dir: &str, file_name: &str) -> Option<String> {
+        let sql = "SELECT file_hash FROM user_file WHERE user_id = ? AND file_dir = ? AND file_name = ?";
+        conn.query_row(sql, rusqlite::params![user_id, file_dir, file_name], |row| row.get(0))
+            .ok()
+    }
 }
 
 impl Database for SqliteDatabase {
-    fn save_user(&self, user: &User) -> Result<()> {
+    fn save_user(&self, user: &User) -> Result<bool> {
         let sql = "
             INSERT OR IGNORE INTO user (username, password, phone_number, email)
             VALUES (?, ?, ?, ?)";
-        self.conn
+        let rows_affected = self
+            .conn
             .execute(sql, rusqlite::params![user.username, user.password, user.phone_number, user.email])?;
-        Ok(())
+        Ok(rows_affected > 0)
     }
 
-    fn query_user(&self, username: &str, password: &str) -> Option<User> {
+    fn query_user(&self, username: &str) -> Option<User> {
         let sql = "
             SELECT id, username, password, phone_number, email, create_time
-            FROM user WHERE username = ? AND password = ?";
+            FROM user WHERE username = ?";
+
+        self.conn
+            .query_row(sql, rusqlite::params![username], |row| {
+                Ok(User {
+                    id: row.get(0)?,
+                    username: row.get(1)?,
+                    password: row.get(2)?,
+                    phone_number: row.get(3)?,
+                    email: row.get(4)?,
+                    create_time: row.get(5)?,
+                })
+            })
+            .map_err(log_query_error)
+            .ok()
+    }
+
+    fn save_session(&self, session: &Session, ttl_secs: u64) -> Result<()> {
+        let sql = "
+            INSERT INTO session (token_hash, user_id, expire_time)
+            VALUES (?, ?, datetime(CURRENT_TIMESTAMP, ?))";
+        // expiry is kept in UTC, local time would shift it across DST changes
+        let ttl = format!("+{ttl_secs} seconds");
+        self.conn
+            .execute(sql, rusqlite::params![session.token_hash, session.user_id, ttl])?;
+        Ok(())
+    }
+
+    fn query_session_user(&self, token_hash: &str) -> Option<User> {
+        let sql = "
+            SELECT u.id, u.username, u.password, u.phone_number, u.email, u.create_time
+            FROM session AS s
+            JOIN user AS u ON s.user_id = u.id
+            WHERE s.token_hash = ? AND s.expire_time > datetime(CURRENT_TIMESTAMP)";
 
         self.conn
-            .query_row(sql, rusqlite::params![username, password], |row| {
+            .query_row(sql, rusqlite::params![token_hash], |row| {
                 Ok(User {
                     id: row.get(0)?,
                     username: row.get(1)?,
@@ -93,13 +344,102 @@ impl Database for SqliteDatabase {
                     create_time: row.get(5)?,
                 })
             })
-            .map_err(|e| error!("{e}"))
+            .map_err(log_query_error)
             .ok()
     }
 
+    fn delete_session(&self, token_hash: &str) -> Result<bool> {
+        let sql = "DELETE FROM session WHERE token_hash = ?";
+        Ok(self.conn.execute(sql, rusqlite::params![token_hash])? > 0)
+    }
+
+    fn delete_user_sessions(&self, user_id: u32) -> Result<usize> {
+        let sql = "DELETE FROM session WHERE user_id = ?";
+        Ok(self.conn.execute(sql, rusqlite::params![user_id])?)
+    }
+
+    fn delete_expired_sessions(&self) -> Result<usize> {
+        let sql = "DELETE FROM session WHERE expire_time <= datetime(CURRENT_TIMESTAMP)";
+        Ok(self.conn.execute(sql, [])?)
+    }
+
+    fn save_api_token(&self, token: &ApiToken, ttl_secs: Option<u64>) -> Result<u32> {
+        let sql = "
+            INSERT INTO api_token (user_id, name, token_hash, scopes, expire_time)
+            VALUES (?, ?, ?, ?, datetime(CURRENT_TIMESTAMP, ?))";
+        // in UTC like session expiry. datetime() yields NULL for a NULL modifier, which means the token never expires
+        let ttl = ttl_secs.map(|ttl_secs| format!("+{ttl_secs} seconds"));
+        let scopes = serde_json::to_string(&token.scopes)?;
+        self.conn
+            .execute(sql, rusqlite::params![token.user_id, token.name, token.token_hash, scopes, ttl])?;
+        Ok(self.conn.last_insert_rowid() as u32)
+    }
+
+    fn query_api_tokens(&self, user_id: u32) -> Result<Vec<ApiToken>> {
+        let sql = "
+            SELECT id, user_id, name, scopes, create_time, expire_time
+            FROM api_token WHERE user_id = ? ORDER BY id";
+
+        let mut stmt = self.conn.prepare(sql)?;
+        let tokens = stmt
+            .query_map(rusqlite::params![user_id], |row| {
+                Ok(ApiToken {
+                    id: row.get(0)?,
+                    user_id: row.get(1)?,
+                    name: row.get(2)?,
+                    scopes: serde_json::from_str(&row.get::<_, String>(3)?).unwrap_or_default(),
+                    create_time: row.get(4)?,
+                    expire_time: row.get(5)?,
+                    ..Default::default()
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(tokens)
+    }
+
+    fn query_api_token_user(&self, token_hash: &str) -> Option<(User, ApiToken)> {
+        let sql = "
+            SELECT u.id, u.username, u.password, u.phone_number, u.email, u.create_time,
+                t.id, t.name, t.scopes, t.create_time, t.expire_time
+            FROM api_token AS t
+            JOIN user AS u ON t.user_id = u.id
+            WHERE t.token_hash = ?
+                AND (t.expire_time IS NULL OR t.expire_time > datetime(CURRENT_TIMESTAMP))";
+
+        self.conn
+            .query_row(sql, rusqlite::params![token_hash], |row| {
+                let user = User {
+                    id: row.get(0)?,
+                    username: row.get(1)?,
+                    password: row.get(2)?,
+                    phone_number: row.get(3)?,
+                    email: row.get(4)?,
+                    create_time: row.get(5)?,
+                };
+                let token = ApiToken {
+                    id: row.get(6)?,
+                    user_id: user.id,
+                    name: row.get(7)?,
+                    token_hash: token_hash.to_string(),
+                    scopes: serde_json::from_str(&row.get::<_, String>(8)?).unwrap_or_default(),
+                    create_time: row.get(9)?,
+                    expire_time: row.get(10)?,
+                };
+                Ok((user, token))
+            })
+            .map_err(log_query_error)
+            .ok()
+    }
+
+    fn delete_api_token(&self, user_id: u32, token_id: u32) -> Result<bool> {
+        let sql = "DELETE FROM api_token WHERE user_id = ? AND id = ?";
+        Ok(self.conn.execute(sql, rusqlite::params![user_id, token_id])? > 0)
+    }
+
     fn query_file_info(&self, user_id: u32, file_dir: &str, file_name: &str) -> Option<SyncFileInfo> {
         let sql = "
-            SELECT u.file_dir, u.file_name, u.file_meta, s.file_hash, s.sync_size, s.file_size
+            SELECT u.file_dir, u.file_name, u.file_meta, s.file_hash, s.sync_size, s.file_size, s.storage_backend, s.codec, s.chunked,
+                u.device
             FROM user_file AS u
             JOIN shared_file AS s ON u.file_hash = s.file_hash
             WHERE u.user_id = ? AND u.file_dir = ? AND u.file_name = ?";
@@ -113,51 +453,691 @@ impl Database for SqliteDatabase {
                     file_hash: row.get(3)?,
                     sync_size: row.get(4)?,
                     file_size: row.get(5)?,
+                    storage_backend: row.get(6)?,
+                    codec: row.get(7)?,
+                    chunked: row.get(8)?,
+                    device: row.get(9)?,
                 })
             })
-            .map_err(|e| error!("{e}"))
+            .map_err(log_query_error)
+            .ok()
+    }
+
+    fn query_shared_file(&self, file_hash: &str) -> Option<SyncFileInfo> {
+        let sql = "SELECT file_hash, sync_size, file_size, storage_backend, codec, chunked FROM shared_file WHERE file_hash = ?";
+
+        self.conn
+            .query_row(sql, rusqlite::params![file_hash], Self::map_shared_file)
+            .map_err(log_query_error)
             .ok()
     }
 
     fn save_file_info(&self, user_id: u32, file_info: &SyncFileInfo) -> Result<()> {
         let i = &file_info;
+        let tx = self.conn.unchecked_transaction()?;
 
         let sql = "
-            INSERT INTO user_file (user_id, file_hash, file_dir, file_name, file_meta)
-            VALUES (?, ?, ?, ?, ?)
+            INSERT INTO user_file (user_id, file_hash, file_dir, file_name, file_meta, device)
+            VALUES (?, ?, ?, ?, ?, ?)
             ON CONFLICT(user_id, file_dir, file_name)
             DO NOTHING";
-        let rows_affected = self
-            .conn
-            .execute(sql, rusqlite::params![user_id, i.file_hash, i.file_dir, i.file_name, i.file_meta])?;
+        let params = rusqlite::params![user_id, i.file_hash, i.file_dir, i.file_name, i.file_meta, i.device];
+        let rows_affected = tx.execute(sql, params)?;
 
         if rows_affected > 0 {
+            // an existing blob keeps its sync progress and backend, it just gains another reference. The size of a
+            // blob without any stored bytes (e.g. left behind by a rejected upload) is taken from the new upload
             let sql = "
-                INSERT OR REPLACE INTO shared_file (file_hash, sync_size, file_size, ref_count)
-                VALUES (?, ?, ?, COALESCE((SELECT ref_count + 1 FROM shared_file WHERE file_hash = ?), 1))";
+                INSERT INTO shared_file (file_hash, sync_size, file_size, storage_backend, ref_count, update_time)
+                VALUES (?, ?, ?, ?, 1, datetime(CURRENT_TIMESTAMP, 'localtime'))
+                ON CONFLICT(file_hash)
+                DO UPDATE SET ref_count = ref_count + 1, update_time = excluded.update_time,
+                    file_size = CASE WHEN sync_size = 0 THEN excluded.file_size ELSE file_size END";
 
             debug!("will insert new record:{}", i.file_hash);
-            self.conn
-                .execute(sql, rusqlite::params![i.file_hash, i.sync_size, i.file_size, i.file_hash])?;
+            tx.execute(sql, rusqlite::params![i.file_hash, i.sync_size, i.file_size, i.storage_backend])?;
         }
 
+        tx.commit()?;
         Ok(())
     }
 
     fn delete_file_info(&self, user_id: u32, file_info: &SyncFileInfo) -> Result<bool> {
+        let tx = self.conn.unchecked_transaction()?;
+        if Self::query_user_file_hash(&tx, user_id, &file_info.file_dir, &file_info.file_name).is_none() {
+            debug!("deleting record:{file_info:?}, deleted:false");
+            return Ok(false);
+        }
+
+        // the deleted content becomes a version like overwritten content does, the history is left to pruning
+        Self::archive_file_content(&tx, user_id, &file_info.file_dir, &file_info.file_name)?;
         let sql = "
             DELETE FROM user_file WHERE user_id = ? AND file_dir = ? AND file_name = ?";
-        let params = rusqlite::params![user_id, file_info.file_dir, file_info.file_name];
-        let deleted = self.conn.execute(sql, params)? > 0;
-        debug!("deleting record:{file_info:?}, deleted:{deleted}");
+        tx.execute(sql, rusqlite::params![user_id, file_info.file_dir, file_info.file_name])?;
+        tx.commit()?;
+
+        debug!("deleting record:{file_info:?}, deleted:true");
+        Ok(true)
+    }
+
+    fn save_shared_file(&self, file_info: &SyncFileInfo) -> Result<()> {
+        let i = &file_info;
+        let sql = "
+            INSERT INTO shared_file (file_hash, sync_size, file_size, storage_backend, ref_count, update_time)
+            VALUES (?, ?, ?, ?, 0, datetime(CURRENT_TIMESTAMP, 'localtime'))
+            ON CONFLICT(file_hash)
+            DO UPDATE SET update_time = excluded.update_time,
+                file_size = CASE WHEN sync_size = 0 THEN excluded.file_size ELSE file_size END";
+        self.conn
+            .execute(sql, rusqlite::params![i.file_hash, i.sync_size, i.file_size, i.storage_backend])?;
+        Ok(())
+    }
+
+    fn replace_file_content(&self, user_id: u32, file_info: &SyncFileInfo, base_hash: Option<&str>) -> Result<bool> {
+        let i = &file_info;
+        let tx = self.conn.unchecked_transaction()?;
+
+        // the blob is recorded even if the file changed meanwhile, so the garbage collector can find it
+        let sql = "
+            INSERT INTO shared_file (file_hash, sync_size, file_size, storage_backend, codec, sync_completed, ref_count, update_time)
+            VALUES (?, ?, ?, ?, ?, 1, 0, datetime(CURRENT_TIMESTAMP, 'localtime'))
+            ON CONFLICT(file_hash)
+            DO UPDATE SET sync_size = excluded.sync_size, sync_completed = 1, codec = excluded.codec,
+                update_time = excluded.update_time
+            WHERE sync_completed = 0";
+        tx.execute(
+            sql,
+            rusqlite::params![i.file_hash, i.sync_size, i.file_size, i.storage_backend, i.codec],
+        )?;
+
+        let current_hash = Self::query_user_file_hash(&tx, user_id, &i.file_dir, &i.file_name);
+        if current_hash.as_deref() != base_hash {
+            tx.commit()?;
+            debug!("file changed before its content was replaced:{file_info:?}, base:{base_hash:?}");
+            return Ok(false);
+        }
+
+        match &current_hash {
+            // the same content, only its meta changes
+            Some(current_hash) if *current_hash == i.file_hash => Self::release_shared_file(&tx, current_hash)?,
+            Some(_) => Self::archive_file_content(&tx, user_id, &i.file_dir, &i.file_name)?,
+            None => {}
+        }
+        Self::set_file_content(&tx, user_id, &i.file_dir, &i.file_name, &i.file_hash, &i.file_meta, &i.device)?;
+
+        let sql = "
+            UPDATE shared_file SET ref_count = ref_count + 1, update_time = datetime(CURRENT_TIMESTAMP, 'localtime')
+            WHERE file_hash = ?";
+        tx.execute(sql, rusqlite::params![i.file_hash])?;
+        tx.commit()?;
+
+        debug!("replaced content of:{}{}, base:{base_hash:?}", i.file_dir, i.file_name);
+        Ok(true)
+    }
+
+    fn query_unreferenced_files(&self, grace_secs: u64) -> Result<Vec<SyncFileInfo>> {
+        let sql = "
+            SELECT file_hash, sync_size, file_size, storage_backend, codec, chunked FROM shared_file
+            WHERE ref_count <= 0 AND sync_completed = 1
+                AND COALESCE(update_time, create_time) <= datetime(CURRENT_TIMESTAMP, 'localtime', ?)";
+        let mut stmt = self.conn.prepare(sql)?;
+        let files = stmt
+            .query_map(rusqlite::params![format!("-{grace_secs} seconds")], Self::map_shared_file)?
+            .collect::<rusqlite::Result<Vec<SyncFileInfo>>>()?;
+        Ok(files)
+    }
+
+    fn delete_unreferenced_file(&self, file_hash: &str, grace_secs: u64) -> Result<bool> {
+        let tx = self.conn.unchecked_transaction()?;
+        // conditions are checked again in case the blob was referenced after it was queried
+        let sql = "
+            DELETE FROM shared_file
+            WHERE file_hash = ? AND ref_count <= 0 AND sync_completed = 1
+                AND COALESCE(update_time, create_time) <= datetime(CURRENT_TIMESTAMP, 'localtime', ?)";
+        let deleted = tx.execute(sql, rusqlite::params![file_hash, format!("-{grace_secs} seconds")])? > 0;
+        if deleted {
+            // a chunked file has no blob of its own, its chunks lose the references of its manifest instead
+            let sql = "
+                UPDATE chunk
+                SET ref_count = ref_count - (
+                        SELECT COUNT(*) FROM file_chunk AS f WHERE f.file_hash = ?1 AND f.chunk_hash = chunk.chunk_hash),
+                    update_time = datetime(CURRENT_TIMESTAMP, 'localtime')
+                WHERE chunk_hash IN (SELECT chunk_hash FROM file_chunk WHERE file_hash = ?1)";
+            tx.execute(sql, rusqlite::params![file_hash])?;
+            tx.execute("DELETE FROM file_chunk WHERE file_hash = ?", rusqlite::params![file_hash])?;
+        }
+        tx.commit()?;
         Ok(deleted)
     }
 
-    fn update_sync_size(&self, user_id: u32, file_info: &SyncFileInfo) -> Result<()> {
-        let sql = "UPDATE shared_file SET sync_size = ?, sync_completed = ? WHERE file_hash = ?";
-        let sync_completed = file_info.sync_size >= file_info.file_size;
+    fn query_abandoned_files(&self, ttl_secs: u64) -> Result<Vec<SyncFileInfo>> {
+        let sql = "
+            SELECT file_hash, sync_size, file_size, storage_backend, codec, chunked FROM shared_file
+            WHERE sync_completed = 0
+                AND COALESCE(update_time, create_time) <= datetime(CURRENT_TIMESTAMP, 'localtime', ?)";
+        let mut stmt = self.conn.prepare(sql)?;
+        let files = stmt
+            .query_map(rusqlite::params![format!("-{ttl_secs} seconds")], Self::map_shared_file)?
+            .collect::<rusqlite::Result<Vec<SyncFileInfo>>>()?;
+        Ok(files)
+    }
+
+    fn delete_abandoned_file(&self, file_hash: &str, ttl_secs: u64) -> Result<bool> {
+        let tx = self.conn.unchecked_transaction()?;
+        let sql = "
+            DELETE FROM shared_file
+            WHERE file_hash = ? AND sync_completed = 0
+                AND COALESCE(update_time, create_time) <= datetime(CURRENT_TIMESTAMP, 'localtime', ?)";
+        let deleted = tx.execute(sql, rusqlite::params![file_hash, format!("-{ttl_secs} seconds")])? > 0;
+        if deleted {
+            // only files still waiting for the dropped blob go with it, any other file has complete content
+            let sql = "
+                DELETE FROM user_file
+                WHERE file_hash = ?1 AND NOT EXISTS (SELECT 1 FROM shared_file WHERE shared_file.file_hash = ?1)";
+            tx.execute(sql, rusqlite::params![file_hash])?;
+        }
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn update_sync_size(&self, _user_id: u32, file_info: &SyncFileInfo) -> Result<()> {
+        let sql = "
+            UPDATE shared_file
+            SET sync_size = ?, sync_completed = ?, codec = ?, update_time = datetime(CURRENT_TIMESTAMP, 'localtime')
+            WHERE file_hash = ?";
+        let i = &file_info;
+        let sync_completed = i.sync_size >= i.file_size;
         self.conn
-            .execute(sql, rusqlite::params![file_info.sync_size, sync_completed, file_info.file_hash])?;
+            .execute(sql, rusqlite::params![i.sync_size, sync_completed, i.codec, i.file_hash])?;
         Ok(())
     }
+
+    fn touch_shared_file(&self, file_hash: &str) -> Result<()> {
+        let sql = "
+            UPDATE shared_file SET update_time = datetime(CURRENT_TIMESTAMP, 'localtime')
+            WHERE file_hash = ? AND sync_completed = 0";
+        self.conn.execute(sql, rusqlite::params![file_hash])?;
+        Ok(())
+    }
+
+    fn assign_storage_backend(&self, storage_backend: &str) -> Result<usize> {
+        let sql = "UPDATE shared_file SET storage_backend = ? WHERE storage_backend = ''";
+        Ok(self.conn.execute(sql, rusqlite::params![storage_backend])?)
+    }
+
+    fn query_cold_files(&self, storage_backend: &str, idle_secs: u64, limit: usize) -> Result<Vec<SyncFileInfo>> {
+        let sql = "
+            SELECT file_hash, sync_size, file_size, storage_backend, codec, chunked FROM shared_file
+            WHERE storage_backend = ? AND sync_completed = 1 AND chunked = 0 AND ref_count > 0
+                AND COALESCE(update_time, create_time) <= datetime(CURRENT_TIMESTAMP, 'localtime', ?)
+            LIMIT ?";
+        let mut stmt = self.conn.prepare(sql)?;
+        let params = rusqlite::params![storage_backend, format!("-{idle_secs} seconds"), limit];
+        let files = stmt
+            .query_map(params, Self::map_shared_file)?
+            .collect::<rusqlite::Result<Vec<SyncFileInfo>>>()?;
+        Ok(files)
+    }
+
+    fn move_shared_file(&self, file_hash: &str, from_backend: &str, to_backend: &str, codec: &str) -> Result<bool> {
+        // update_time is left alone, moving a blob doesn't make it any less cold
+        let sql = "
+            UPDATE shared_file SET storage_backend = ?, codec = ?
+            WHERE file_hash = ? AND storage_backend = ? AND sync_completed = 1";
+        let moved = self
+            .conn
+            .execute(sql, rusqlite::params![to_backend, codec, file_hash, from_backend])?;
+        Ok(moved > 0)
+    }
+
+    fn query_stored_files(&self, after_hash: &str, limit: usize) -> Result<Vec<SyncFileInfo>> {
+        let sql = "
+            SELECT file_hash, sync_size, file_size, storage_backend, codec, chunked FROM shared_file
+            WHERE file_hash > ? AND sync_completed = 1 AND chunked = 0 AND ref_count > 0
+            ORDER BY file_hash
+            LIMIT ?";
+        let mut stmt = self.conn.prepare(sql)?;
+        let files = stmt
+            .query_map(rusqlite::params![after_hash, limit], Self::map_shared_file)?
+            .collect::<rusqlite::Result<Vec<SyncFileInfo>>>()?;
+        Ok(files)
+    }
+
+    fn query_chunk(&self, chunk_hash: &str) -> Option<SyncFileInfo> {
+        let sql = "SELECT chunk_hash, chunk_size, storage_backend, codec FROM chunk WHERE chunk_hash = ?";
+        self.conn.query_row(sql, rusqlite::params![chunk_hash], Self::map_chunk).ok()
+    }
+
+    fn save_chunk(&self, chunk: &SyncFileInfo) -> Result<()> {
+        // a chunk stored again by a concurrent upload is the same content, only its grace period starts over
+        let sql = "
+            INSERT INTO chunk (chunk_hash, chunk_size, storage_backend, codec, update_time)
+            VALUES (?, ?, ?, ?, datetime(CURRENT_TIMESTAMP, 'localtime'))
+            ON CONFLICT(chunk_hash)
+            DO UPDATE SET update_time = excluded.update_time";
+        let c = &chunk;
+        self.conn
+            .execute(sql, rusqlite::params![c.file_hash, c.file_size, c.storage_backend, c.codec])?;
+        Ok(())
+    }
+
+    fn query_file_chunks(&self, file_hash: &str) -> Result<Vec<SyncFileInfo>> {
+        let sql = "
+            SELECT c.chunk_hash, c.chunk_size, c.storage_backend, c.codec
+            FROM file_chunk AS f
+            JOIN chunk AS c ON f.chunk_hash = c.chunk_hash
+            WHERE f.file_hash = ?
+            ORDER BY f.chunk_index";
+        let mut stmt = self.conn.prepare(sql)?;
+        let chunks = stmt
+            .query_map(rusqlite::params![file_hash], Self::map_chunk)?
+            .collect::<rusqlite::Result<Vec<SyncFileInfo>>>()?;
+        Ok(chunks)
+    }
+
+    fn save_manifest(&self, file_hash: &str, chunk_hashes: &[String]) -> Result<bool> {
+        let tx = self.conn.unchecked_transaction()?;
+        let sql = "
+            UPDATE shared_file
+            SET chunked = 1, sync_size = file_size, sync_completed = 1, codec = '',
+                update_time = datetime(CURRENT_TIMESTAMP, 'localtime')
+            WHERE file_hash = ? AND sync_completed = 0 AND sync_size = 0";
+        if tx.execute(sql, rusqlite::params![file_hash])? == 0 {
+            debug!("file already stored otherwise:{file_hash}");
+            return Ok(false);
+        }
+
+        {
+            let sql = "INSERT INTO file_chunk (file_hash, chunk_index, chunk_hash) VALUES (?, ?, ?)";
+            let mut stmt = tx.prepare(sql)?;
+            for (index, chunk_hash) in chunk_hashes.iter().enumerate() {
+                stmt.execute(rusqlite::params![file_hash, index, chunk_hash])?;
+            }
+        }
+
+        // the garbage collector may have dropped an unreferenced chunk since it was found
+        let sql = "
+            SELECT COUNT(*) FROM file_chunk AS f
+            LEFT JOIN chunk AS c ON f.chunk_hash = c.chunk_hash
+            WHERE f.file_hash = ? AND c.chunk_hash IS NULL";
+        let missing: usize = tx.query_row(sql, rusqlite::params![file_hash], |row| row.get(0))?;
+        if missing > 0 {
+            debug!("chunks gone before the manifest was saved:{file_hash}, missing:{missing}");
+            return Ok(false);
+        }
+
+        let sql = "
+            UPDATE chunk
+            SET ref_count = ref_count + (
+                    SELECT COUNT(*) FROM file_chunk AS f WHERE f.file_hash = ?1 AND f.chunk_hash = chunk.chunk_hash),
+                update_time = datetime(CURRENT_TIMESTAMP, 'localtime')
+            WHERE chunk_hash IN (SELECT chunk_hash FROM file_chunk WHERE file_hash = ?1)";
+        tx.execute(sql, rusqlite::params![file_hash])?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn query_unreferenced_chunks(&self, grace_secs: u64) -> Result<Vec<SyncFileInfo>> {
+        let sql = "
+            SELECT chunk_hash, chunk_size, storage_backend, codec FROM chunk
+            WHERE ref_count <= 0
+                AND COALESCE(update_time, create_time) <= datetime(CURRENT_TIMESTAMP, 'localtime', ?)";
+        let mut stmt = self.conn.prepare(sql)?;
+        let chunks = stmt
+            .query_map(rusqlite::params![format!("-{grace_secs} seconds")], Self::map_chunk)?
+            .collect::<rusqlite::Result<Vec<SyncFileInfo>>>()?;
+        Ok(chunks)
+    }
+
+    fn delete_unreferenced_chunk(&self, chunk_hash: &str, grace_secs: u64) -> Result<bool> {
+        let sql = "
+            DELETE FROM chunk
+            WHERE chunk_hash = ? AND ref_count <= 0
+                AND COALESCE(update_time, create_time) <= datetime(CURRENT_TIMESTAMP, 'localtime', ?)";
+        let deleted = self
+            .conn
+            .execute(sql, rusqlite::params![chunk_hash, format!("-{grace_secs} seconds")])?;
+        Ok(deleted > 0)
+    }
+
+    fn query_stored_chunks(&self, after_hash: &str, limit: usize) -> Result<Vec<SyncFileInfo>> {
+        let sql = "
+            SELECT chunk_hash, chunk_size, storage_backend, codec FROM chunk
+            WHERE chunk_hash > ? AND ref_count > 0
+            ORDER BY chunk_hash
+            LIMIT ?";
+        let mut stmt = self.conn.prepare(sql)?;
+        let chunks = stmt
+            .query_map(rusqlite::params![after_hash, limit], Self::map_chunk)?
+            .collect::<rusqlite::Result<Vec<SyncFileInfo>>>()?;
+        Ok(chunks)
+    }
+
+    fn query_file_versions(&self, user_id: u32, file_dir: &str, file_name: &str) -> Result<Vec<FileVersion>> {
+        let sql = "
+            SELECT v.id, v.file_hash, s.file_size, v.file_meta, v.device, v.create_time, v.archive_time
+            FROM file_version AS v
+            JOIN shared_file AS s ON v.file_hash = s.file_hash
+            WHERE v.user_id = ? AND v.file_dir = ? AND v.file_name = ?
+            ORDER BY v.create_time DESC, v.id DESC";
+        let mut stmt = self.conn.prepare(sql)?;
+        let versions = stmt
+            .query_map(rusqlite::params![user_id, file_dir, file_name], |row| {
+                Ok(FileVersion {
+                    id: row.get(0)?,
+                    file_hash: row.get(1)?,
+                    file_size: row.get(2)?,
+                    file_meta: row.get(3)?,
+                    device: row.get(4)?,
+                    create_time: row.get(5)?,
+                    archive_time: row.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(versions)
+    }
+
+    fn restore_file_version(&self, user_id: u32, file_dir: &str, file_name: &str, version_id: u32) -> Result<bool> {
+        let tx = self.conn.unchecked_transaction()?;
+        let sql = "
+            SELECT file_hash, file_meta, device FROM file_version
+            WHERE id = ? AND user_id = ? AND file_dir = ? AND file_name = ?";
+        let version = tx.query_row(sql, rusqlite::params![version_id, user_id, file_dir, file_name], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+        });
+        let Ok((file_hash, file_meta, device)) = version else {
+            debug!("no version:{version_id} of:{file_dir}{file_name}");
+            return Ok(false);
+        };
+
+        // the reference the version holds moves to the file
+        tx.execute("DELETE FROM file_version WHERE id = ?", rusqlite::params![version_id])?;
+        if Self::query_user_file_hash(&tx, user_id, file_dir, file_name).is_some() {
+            Self::archive_file_content(&tx, user_id, file_dir, file_name)?;
+        }
+        Self::set_file_content(&tx, user_id, file_dir, file_name, &file_hash, &file_meta, &device)?;
+        tx.commit()?;
+
+        debug!("restored version:{version_id} of:{file_dir}{file_name}, hash:{file_hash}");
+        Ok(true)
+    }
+
+    fn prune_file_versions(&self, keep_last: usize, keep_daily_days: u64, keep_weekly_weeks: u64) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        let sql = "
+            WITH version AS (
+                SELECT id, file_hash,
+                    ROW_NUMBER() OVER (PARTITION BY user_id, file_dir, file_name ORDER BY create_time DESC, id DESC) AS newest,
+                    ROW_NUMBER() OVER (PARTITION BY user_id, file_dir, file_name, date(create_time)
+                        ORDER BY create_time DESC, id DESC) AS newest_of_day,
+                    ROW_NUMBER() OVER (PARTITION BY user_id, file_dir, file_name, strftime('%Y-%W', create_time)
+                        ORDER BY create_time DESC, id DESC) AS newest_of_week,
+                    julianday(datetime(CURRENT_TIMESTAMP, 'localtime')) - julianday(create_time) AS age_days
+                FROM file_version
+            )
+            SELECT id, file_hash FROM version
+            WHERE newest > ?1
+                AND NOT (newest_of_day = 1 AND age_days < ?2)
+                AND NOT (newest_of_week = 1 AND age_days < ?2 + 7 * ?3)";
+        let mut stmt = tx.prepare(sql)?;
+        let pruned = stmt
+            .query_map(rusqlite::params![keep_last, keep_daily_days, keep_weekly_weeks], |row| {
+                Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        drop(stmt);
+
+        for (version_id, file_hash) in &pruned {
+            tx.execute("DELETE FROM file_version WHERE id = ?", rusqlite::params![version_id])?;
+            Self::release_shared_file(&tx, file_hash)?;
+        }
+        tx.commit()?;
+        Ok(pruned.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER_ID: u32 = 1;
+
+    /// The schema before users, sessions and tokens were added
+    const BASELINE_SCHEMA: &str = "
+        CREATE TABLE user (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username TEXT NOT NULL,
+            password TEXT NOT NULL,
+            phone_number TEXT NOT NULL,
+            email TEXT NOT NULL,
+            create_time DATETIME NOT NULL DEFAULT (datetime(CURRENT_TIMESTAMP, 'localtime'))
+        );
+        CREATE TABLE shared_file (
+            file_hash TEXT PRIMARY KEY,
+            ref_count INTEGER NOT NULL,
+            file_size INTEGER NOT NULL,
+            sync_size INTEGER NOT NULL,
+            sync_completed INTEGER NOT NULL DEFAULT 0 CHECK (sync_completed IN (0, 1)),
+            create_time DATETIME NOT NULL DEFAULT (datetime(CURRENT_TIMESTAMP, 'localtime'))
+        );
+        CREATE TABLE user_file (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            file_hash TEXT NOT NULL,
+            file_dir TEXT NOT NULL,
+            file_name TEXT NOT NULL,
+            file_meta TEXT NOT NULL DEFAULT '',
+            file_create_time DATETIME NOT NULL DEFAULT (datetime(CURRENT_TIMESTAMP, 'localtime')),
+            record_create_time DATETIME NOT NULL DEFAULT (datetime(CURRENT_TIMESTAMP, 'localtime')),
+            UNIQUE (user_id, file_name, file_dir)
+        );";
+
+    fn file_info(file_name: &str, content: &str) -> SyncFileInfo {
+        SyncFileInfo {
+            file_hash: content.repeat(64),
+            sync_size: 100,
+            file_size: 100,
+            file_dir: "/d/".to_string(),
+            file_name: file_name.to_string(),
+            storage_backend: "local".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Uploads `content` over whatever the file holds
+    fn upload(db: &SqliteDatabase, file_name: &str, content: &str) {
+        let base_hash = SqliteDatabase::query_user_file_hash(&db.conn, USER_ID, "/d/", file_name);
+        assert!(db
+            .replace_file_content(USER_ID, &file_info(file_name, content), base_hash.as_deref())
+            .unwrap());
+    }
+
+    fn ref_count(db: &SqliteDatabase, content: &str) -> u32 {
+        let sql = "SELECT ref_count FROM shared_file WHERE file_hash = ?";
+        db.conn.query_row(sql, [content.repeat(64)], |row| row.get(0)).unwrap()
+    }
+
+    fn version_hashes(db: &SqliteDatabase, file_name: &str) -> Vec<String> {
+        let versions = db.query_file_versions(USER_ID, "/d/", file_name).unwrap();
+        versions.into_iter().map(|version| version.file_hash[..1].to_string()).collect()
+    }
+
+    #[test]
+    fn upgrading_merges_duplicate_users() {
+        let path = std::env::temp_dir().join(format!("rsdrive-upgrade-{}.db", rand::random::<u64>()));
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(BASELINE_SCHEMA).unwrap();
+        conn.execute_batch(
+            "
+            INSERT INTO user (username, password, phone_number, email) VALUES
+                ('test', 'password', '', ''), ('test', 'password', '', ''), ('other', 'password', '', ''),
+                ('test', 'password', '', '');
+            INSERT INTO shared_file (file_hash, ref_count, file_size, sync_size, sync_completed) VALUES
+                ('a', 1, 1, 1, 1), ('b', 2, 1, 1, 1), ('c', 1, 1, 1, 1);
+            INSERT INTO user_file (user_id, file_hash, file_dir, file_name) VALUES
+                (1, 'a', '/', 'kept'), (2, 'b', '/', 'moved'), (4, 'b', '/', 'kept'), (3, 'c', '/', 'kept');",
+        )
+        .unwrap();
+        drop(conn);
+
+        let db = SqliteDatabase::open(&path).unwrap();
+        assert_eq!(db.query_user("test").unwrap().id, 1);
+        let users: u32 = db.conn.query_row("SELECT COUNT(*) FROM user", [], |row| row.get(0)).unwrap();
+        assert_eq!(users, 2);
+        assert_eq!(db.query_file_info(1, "/", "kept").unwrap().file_hash, "a");
+        assert_eq!(db.query_file_info(1, "/", "moved").unwrap().file_hash, "b");
+        assert_eq!(db.query_file_info(3, "/", "kept").unwrap().file_hash, "c");
+        // the duplicate's file at a path the kept user already has is dropped
+        let ref_count: u32 = db
+            .conn
+            .query_row("SELECT ref_count FROM shared_file WHERE file_hash = 'b'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(ref_count, 1);
+        assert!(!db
+            .save_user(&User {
+                username: "test".to_string(),
+                ..Default::default()
+            })
+            .unwrap());
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn api_tokens_expire_in_utc() {
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        db.save_user(&User {
+            username: "u".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        for (token_hash, ttl_secs) in [("never", None), ("later", Some(60)), ("expired", Some(0))] {
+            let token = ApiToken {
+                user_id: USER_ID,
+                token_hash: token_hash.to_string(),
+                ..Default::default()
+            };
+            db.save_api_token(&token, ttl_secs).unwrap();
+        }
+
+        assert!(db.query_api_token_user("never").is_some());
+        assert!(db.query_api_token_user("later").is_some());
+        assert!(db.query_api_token_user("expired").is_none());
+        let sql = "SELECT expire_time = datetime(CURRENT_TIMESTAMP, '+60 seconds') FROM api_token WHERE token_hash = 'later'";
+        let utc: bool = db.conn.query_row(sql, [], |row| row.get(0)).unwrap();
+        assert!(utc);
+    }
+
+    #[test]
+    fn overwriting_keeps_the_prior_content_as_a_version() {
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        upload(&db, "f", "a");
+        upload(&db, "f", "b");
+        upload(&db, "f", "c");
+
+        assert_eq!(version_hashes(&db, "f"), ["b", "a"]);
+        assert_eq!(db.query_file_info(USER_ID, "/d/", "f").unwrap().file_hash, "c".repeat(64));
+        assert_eq!((ref_count(&db, "a"), ref_count(&db, "b"), ref_count(&db, "c")), (1, 1, 1));
+    }
+
+    #[test]
+    fn restoring_swaps_the_version_with_the_current_content() {
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        upload(&db, "f", "a");
+        upload(&db, "f", "b");
+        let version_id = db.query_file_versions(USER_ID, "/d/", "f").unwrap()[0].id;
+
+        assert!(db.restore_file_version(USER_ID, "/d/", "f", version_id).unwrap());
+        assert!(!db.restore_file_version(USER_ID, "/d/", "f", version_id).unwrap());
+        assert_eq!(db.query_file_info(USER_ID, "/d/", "f").unwrap().file_hash, "a".repeat(64));
+        assert_eq!(version_hashes(&db, "f"), ["b"]);
+        assert_eq!((ref_count(&db, "a"), ref_count(&db, "b")), (1, 1));
+    }
+
+    #[test]
+    fn a_deleted_file_keeps_its_history_and_can_be_restored() {
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        upload(&db, "f", "a");
+        upload(&db, "f", "b");
+
+        assert!(db.delete_file_info(USER_ID, &file_info("f", "b")).unwrap());
+        assert!(db.query_file_info(USER_ID, "/d/", "f").is_none());
+        assert_eq!(version_hashes(&db, "f"), ["b", "a"]);
+        assert_eq!((ref_count(&db, "a"), ref_count(&db, "b")), (1, 1));
+
+        let version_id = db.query_file_versions(USER_ID, "/d/", "f").unwrap()[1].id;
+        assert!(db.restore_file_version(USER_ID, "/d/", "f", version_id).unwrap());
+        assert_eq!(db.query_file_info(USER_ID, "/d/", "f").unwrap().file_hash, "a".repeat(64));
+        assert_eq!(version_hashes(&db, "f"), ["b"]);
+    }
+
+    #[test]
+    fn deleting_an_incomplete_file_drops_its_content() {
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        let incomplete = SyncFileInfo {
+            sync_size: 0,
+            ..file_info("f", "a")
+        };
+        db.save_file_info(USER_ID, &incomplete).unwrap();
+
+        assert!(db.delete_file_info(USER_ID, &incomplete).unwrap());
+        assert!(version_hashes(&db, "f").is_empty());
+        assert_eq!(ref_count(&db, "a"), 0);
+    }
+
+    #[test]
+    fn abandoned_uploads_expire_unless_touched() {
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        let incomplete = SyncFileInfo {
+            sync_size: 0,
+            ..file_info("f", "a")
+        };
+        db.save_file_info(USER_ID, &incomplete).unwrap();
+        upload(&db, "g", "b");
+        let sql = "UPDATE shared_file SET update_time = datetime(CURRENT_TIMESTAMP, 'localtime', '-2 hours')";
+        db.conn.execute(sql, []).unwrap();
+
+        db.touch_shared_file(&incomplete.file_hash).unwrap();
+        assert!(db.query_abandoned_files(3600).unwrap().is_empty());
+
+        db.conn.execute(sql, []).unwrap();
+        let abandoned = db.query_abandoned_files(3600).unwrap();
+        assert_eq!(abandoned.len(), 1);
+        assert!(db.delete_abandoned_file(&abandoned[0].file_hash, 3600).unwrap());
+        assert!(SqliteDatabase::query_user_file_hash(&db.conn, USER_ID, "/d/", "f").is_none());
+        assert!(SqliteDatabase::query_user_file_hash(&db.conn, USER_ID, "/d/", "g").is_some());
+    }
+
+    #[test]
+    fn pruning_keeps_the_newest_versions() {
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        for content in ["a", "b", "c", "d"] {
+            upload(&db, "f", content);
+        }
+
+        assert_eq!(db.prune_file_versions(2, 0, 0).unwrap(), 1);
+        assert_eq!(version_hashes(&db, "f"), ["c", "b"]);
+        assert_eq!((ref_count(&db, "a"), ref_count(&db, "b")), (0, 1));
+        assert_eq!(db.prune_file_versions(2, 0, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn pruning_thins_out_older_versions_by_day() {
+        let db = SqliteDatabase::open(":memory:").unwrap();
+        for content in ["a", "b", "c", "d"] {
+            upload(&db, "f", content);
+        }
+        // a and b from three days ago, c from yesterday
+        for (content, age) in [("a", "-3 days"), ("b", "-3 days"), ("c", "-1 days")] {
+            let sql = "UPDATE file_version SET create_time = datetime(CURRENT_TIMESTAMP, 'localtime', ?) WHERE file_hash = ?";
+            db.conn.execute(sql, rusqlite::params![age, content.repeat(64)]).unwrap();
+        }
+
+        assert_eq!(db.prune_file_versions(0, 5, 0).unwrap(), 1);
+        assert_eq!(version_hashes(&db, "f"), ["c", "b"]);
+        assert_eq!(db.prune_file_versions(0, 2, 0).unwrap(), 1);
+        assert_eq!(version_hashes(&db, "f"), ["c"]);
+        assert_eq!(db.prune_file_versions(0, 0, 0).unwrap(), 1);
+        assert!(version_hashes(&db, "f").is_empty());
+    }
 }
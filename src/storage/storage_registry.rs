@@ -0,0 +1,300 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use super::{
+    compressed_file_storage::{CompressedFileStorage, CompressionConfig},
+    encrypted_file_storage::{EncryptedFileStorage, EncryptionConfig},
+    file_storage::{BlobDigest, FileReader, FileStorage, FileWriter},
+    local_file_storage::LocalFileStorage,
+    s3_file_storage::{S3Config, S3FileStorage},
+};
+use crate::{common::hasher::HashAlgorithm, server::entity::SyncFileInfo};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use rs_utilities::log_and_bail;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct LocalConfig {
+    /// Defaults to the server's `storage_dir`
+    pub dir: Option<PathBuf>,
+}
+
+/// Where the blobs are kept, the metadata always stays in the database
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StorageBackend {
+    Local(LocalConfig),
+    S3(S3Config),
+    /// An in-process object store, for testing the object storage path without a bucket
+    Memory,
+    /// Wraps another backend, which then only gets to see encrypted blobs
+    Encrypted(EncryptionConfig),
+    /// Wraps another backend, blobs that compress well are stored compressed
+    Compressed(CompressionConfig),
+}
+
+/// The first route matching an upload picks the backend for its blob, the default backend is used otherwise
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct StorageRoute {
+    pub backend: String,
+    /// Matches any user when empty
+    pub user_ids: Vec<u32>,
+    pub min_file_size: Option<usize>,
+    pub max_file_size: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backends: BTreeMap<String, StorageBackend>,
+    pub default_backend: String,
+    pub routes: Vec<StorageRoute>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backends: BTreeMap::from([("local".to_string(), StorageBackend::Local(LocalConfig::default()))]),
+            default_backend: "local".to_string(),
+            routes: vec![],
+        }
+    }
+}
+
+impl StorageRoute {
+    fn matches(&self, user_id: u32, file_size: usize) -> bool {
+        (self.user_ids.is_empty() || self.user_ids.contains(&user_id))
+            && self.min_file_size.is_none_or(|min| file_size >= min)
+            && self.max_file_size.is_none_or(|max| file_size <= max)
+    }
+}
+
+impl StorageBackend {
+    /// `storage_dir` holds the staged uploads of backends that aren't local
+    pub fn create(&self, storage_dir: &Path, hash_algorithm: HashAlgorithm) -> Result<Arc<dyn FileStorage>> {
+        Ok(match self {
+            StorageBackend::Local(config) => {
+                let dir = config.dir.clone().unwrap_or_else(|| storage_dir.to_path_buf());
+                Arc::new(LocalFileStorage::new(dir, hash_algorithm))
+            }
+            StorageBackend::S3(config) => Arc::new(S3FileStorage::from_config(config, storage_dir.to_path_buf(), hash_algorithm)?),
+            StorageBackend::Memory => Arc::new(S3FileStorage::in_memory(storage_dir.to_path_buf(), hash_algorithm)),
+            StorageBackend::Encrypted(config) => {
+                match *config.backend {
+                    StorageBackend::Encrypted(_) => bail!("encrypted backends can't be nested"),
+                    // ciphertext doesn't compress, the compressed backend has to wrap the encrypted one instead
+                    StorageBackend::Compressed(_) => bail!("encrypted backends can't wrap compressed ones"),
+                    _ => {}
+                }
+                let inner = config.backend.create(storage_dir, hash_algorithm)?;
+                Arc::new(EncryptedFileStorage::new(inner, config, hash_algorithm)?)
+            }
+            StorageBackend::Compressed(config) => {
+                if matches!(*config.backend, StorageBackend::Compressed(_)) {
+                    bail!("compressed backends can't be nested");
+                }
+                let inner = config.backend.create(storage_dir, hash_algorithm)?;
+                Arc::new(CompressedFileStorage::new(inner, config, storage_dir, hash_algorithm)?)
+            }
+        })
+    }
+
+    /// The dir the blobs end up in, if they're kept locally
+    fn local_dir(&self, storage_dir: &Path) -> Option<PathBuf> {
+        match self {
+            StorageBackend::Local(config) => Some(config.dir.clone().unwrap_or_else(|| storage_dir.to_path_buf())),
+            StorageBackend::Encrypted(EncryptionConfig { backend, .. }) | StorageBackend::Compressed(CompressionConfig { backend, .. }) => {
+                backend.local_dir(storage_dir)
+            }
+            StorageBackend::S3(_) | StorageBackend::Memory => None,
+        }
+    }
+}
+
+/// Named backends, each blob is served by the backend recorded in its `storage_backend`
+pub struct StorageRegistry {
+    backends: HashMap<String, Arc<dyn FileStorage>>,
+    default_backend: String,
+    routes: Vec<StorageRoute>,
+    /// blobs being written, deleted or re-keyed. An upload is staged under the blob's hash so that any session
+    /// can resume it, which means only one writer at a time may touch it.
+    claims: Arc<Mutex<HashSet<String>>>,
+}
+
+/// Another writer holds the blob, e.g. another session uploading the same content
+#[derive(Debug)]
+pub struct BlobInUse(pub String);
+
+impl fmt::Display for BlobInUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "blob is in use by another transfer:{}", self.0)
+    }
+}
+
+impl std::error::Error for BlobInUse {}
+
+/// Released when dropped, so a writer that is dropped without being committed or discarded releases it too
+struct BlobClaim {
+    claims: Arc<Mutex<HashSet<String>>>,
+    file_hash: String,
+}
+
+impl Drop for BlobClaim {
+    fn drop(&mut self) {
+        self.claims.lock().unwrap().remove(&self.file_hash);
+    }
+}
+
+struct ClaimedWriter {
+    inner: Box<dyn FileWriter>,
+    _claim: BlobClaim,
+}
+
+#[async_trait]
+impl FileWriter for ClaimedWriter {
+    async fn write(&mut self, data: &[u8]) -> Result<usize> {
+        self.inner.write(data).await
+    }
+
+    async fn read_at(&mut self, pos: u64, data: &mut [u8]) -> Result<usize> {
+        self.inner.read_at(pos, data).await
+    }
+
+    async fn close(&mut self) -> Result<BlobDigest> {
+        self.inner.close().await
+    }
+
+    async fn commit(&mut self) -> Result<()> {
+        self.inner.commit().await
+    }
+
+    async fn discard(&mut self) -> Result<()> {
+        self.inner.discard().await
+    }
+}
+
+impl StorageRegistry {
+    pub fn new(config: &StorageConfig, storage_dir: &Path, hash_algorithm: HashAlgorithm) -> Result<Self> {
+        // blobs are named by their hash, two backends in one dir would be the same copy under different names, so
+        // moving a blob between them would delete it
+        let mut local_dirs = HashMap::new();
+        for (name, backend) in &config.backends {
+            let Some(dir) = backend.local_dir(storage_dir) else {
+                continue;
+            };
+            let dir = std::path::absolute(&dir).context(format!("invalid dir of storage backend:{name}, {dir:?}"))?;
+            if let Some(other) = local_dirs.insert(dir.clone(), name) {
+                log_and_bail!("storage backends:{other} and {name} share the dir:{dir:?}");
+            }
+        }
+
+        let mut backends = HashMap::new();
+        for (name, backend) in &config.backends {
+            let file_storage = backend
+                .create(storage_dir, hash_algorithm)
+                .context(format!("failed to create storage backend:{name}"))?;
+            info!("storage backend:{name}");
+            backends.insert(name.clone(), file_storage);
+        }
+
+        let registry = Self {
+            backends,
+            default_backend: config.default_backend.clone(),
+            routes: config.routes.clone(),
+            claims: Arc::default(),
+        };
+        registry.get(&registry.default_backend)?;
+        for route in &registry.routes {
+            registry.get(&route.backend)?;
+        }
+        Ok(registry)
+    }
+
+    pub fn default_backend(&self) -> &str {
+        &self.default_backend
+    }
+
+    /// Picks the backend for a new blob
+    pub fn route(&self, user_id: u32, file_size: usize) -> &str {
+        self.routes
+            .iter()
+            .find(|route| route.matches(user_id, file_size))
+            .map_or(&self.default_backend, |route| &route.backend)
+    }
+
+    pub fn get(&self, name: &str) -> Result<&dyn FileStorage> {
+        let Some(file_storage) = self.backends.get(name) else {
+            log_and_bail!("unknown storage backend:{name}");
+        };
+        Ok(file_storage.as_ref())
+    }
+
+    fn claim(&self, file_hash: &str) -> Result<BlobClaim> {
+        if !self.claims.lock().unwrap().insert(file_hash.to_string()) {
+            return Err(BlobInUse(file_hash.to_string()).into());
+        }
+        Ok(BlobClaim {
+            claims: self.claims.clone(),
+            file_hash: file_hash.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl FileStorage for StorageRegistry {
+    /// Fails with `BlobInUse` while another writer holds the blob
+    async fn open_writer(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileWriter>> {
+        let claim = self.claim(&file_info.file_hash)?;
+        let inner = self.get(&file_info.storage_backend)?.open_writer(file_info).await?;
+        Ok(Box::new(ClaimedWriter { inner, _claim: claim }))
+    }
+
+    async fn open_reader(&self, file_info: &SyncFileInfo) -> Result<Box<dyn FileReader>> {
+        self.get(&file_info.storage_backend)?.open_reader(file_info).await
+    }
+
+    async fn delete_file(&self, file_info: &SyncFileInfo) -> Result<()> {
+        let _claim = self.claim(&file_info.file_hash)?;
+        self.get(&file_info.storage_backend)?.delete_file(file_info).await
+    }
+
+    async fn rotate_key(&self, file_info: &SyncFileInfo) -> Result<bool> {
+        let _claim = self.claim(&file_info.file_hash)?;
+        self.get(&file_info.storage_backend)?.rotate_key(file_info).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_backends_need_their_own_dirs() {
+        let storage_dir = std::env::temp_dir().join(format!("rsdrive-registry-{}", rand::random::<u64>()));
+        let mut config = StorageConfig::default();
+        config.backends.insert(
+            "hdd".to_string(),
+            StorageBackend::Local(LocalConfig {
+                dir: Some(storage_dir.join(".")),
+            }),
+        );
+        assert!(StorageRegistry::new(&config, &storage_dir, HashAlgorithm::default()).is_err());
+
+        config.backends.insert(
+            "hdd".to_string(),
+            StorageBackend::Local(LocalConfig {
+                dir: Some(storage_dir.join("hdd")),
+            }),
+        );
+        assert!(StorageRegistry::new(&config, &storage_dir, HashAlgorithm::default()).is_ok());
+        let _ = std::fs::remove_dir_all(&storage_dir);
+    }
+}
@@ -0,0 +1,152 @@
+use super::{file_storage::FileStorage, storage_registry::StorageRegistry, StorageContext};
+use crate::server::entity::SyncFileInfo;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+const COPY_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Blobs in `from` that haven't been touched for `min_idle_secs` are moved to `to`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct MigrationRule {
+    pub from: String,
+    pub to: String,
+    pub min_idle_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MigrationConfig {
+    pub interval_secs: u64,
+    /// how many blobs each rule moves per run at most
+    pub batch_size: usize,
+    pub rules: Vec<MigrationRule>,
+}
+
+impl Default for MigrationConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 3600,
+            batch_size: 100,
+            rules: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MigrationStats {
+    pub moved_files: usize,
+    pub failed_files: usize,
+}
+
+/// Moves blobs between backends, the hash stays the same so nothing referencing a blob has to change
+pub struct TierMigrator {
+    config: MigrationConfig,
+}
+
+impl TierMigrator {
+    pub fn new(config: MigrationConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn start(self, storage_ctx: StorageContext) {
+        if self.config.rules.is_empty() {
+            return;
+        }
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+            let mut storage_ctx = storage_ctx;
+            loop {
+                interval.tick().await;
+
+                match self.migrate(&mut storage_ctx).await {
+                    Ok(stats) => info!("tier migration completed:{stats:?}"),
+                    Err(e) => error!("tier migration failed: {e:?}"),
+                }
+            }
+        });
+    }
+
+    pub async fn migrate(&self, storage_ctx: &mut StorageContext) -> Result<MigrationStats> {
+        let mut stats = MigrationStats::default();
+        for rule in &self.config.rules {
+            // fail early on a misconfigured rule rather than on every blob
+            storage_ctx.file_storage.get(&rule.from)?;
+            storage_ctx.file_storage.get(&rule.to)?;
+
+            for file_info in storage_ctx
+                .db
+                .query_cold_files(&rule.from, rule.min_idle_secs, self.config.batch_size)?
+            {
+                match Self::move_blob(storage_ctx, &file_info, &rule.to).await {
+                    Ok(true) => stats.moved_files += 1,
+                    Ok(false) => {}
+                    Err(e) => {
+                        warn!("failed to move blob:{}, {} -> {}, {e:?}", file_info.file_hash, rule.from, rule.to);
+                        stats.failed_files += 1;
+                    }
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Copies the blob, verifies the copy and only then points the record at it, the old copy is deleted last
+    async fn move_blob(storage_ctx: &mut StorageContext, file_info: &SyncFileInfo, to_backend: &str) -> Result<bool> {
+        let registry: &StorageRegistry = &storage_ctx.file_storage;
+        let target = SyncFileInfo {
+            file_hash: file_info.file_hash.clone(),
+            file_size: file_info.file_size,
+            storage_backend: to_backend.to_string(),
+            ..Default::default()
+        };
+
+        let mut reader = registry.open_reader(file_info).await?;
+        let mut writer = registry.open_writer(&target).await?;
+        let mut buf = vec![0u8; COPY_CHUNK_SIZE];
+        let copied = async {
+            loop {
+                let len = reader.read(&mut buf).await?;
+                if len == 0 {
+                    break;
+                }
+                writer.write(&buf[..len]).await?;
+            }
+            writer.close().await
+        }
+        .await;
+        reader.close().await;
+
+        let blob = match copied {
+            Ok(blob) => blob,
+            Err(e) => {
+                writer.discard().await?;
+                return Err(e);
+            }
+        };
+        if blob.digest != file_info.file_hash || blob.size != file_info.file_size as u64 {
+            writer.discard().await?;
+            bail!("copied blob doesn't match, hash:{}, actual:{blob:?}", file_info.file_hash);
+        }
+        writer.commit().await?;
+        // the writer holds the blob, which would keep either copy from being deleted
+        drop(writer);
+
+        // the blob may have been purged while it was being copied, in which case the copy is the one to go
+        if !storage_ctx
+            .db
+            .move_shared_file(&file_info.file_hash, &file_info.storage_backend, to_backend, &blob.codec)?
+        {
+            debug!("blob vanished while moving:{}", file_info.file_hash);
+            registry.delete_file(&target).await?;
+            return Ok(false);
+        }
+
+        debug!("moved blob:{}, {} -> {to_backend}", file_info.file_hash, file_info.storage_backend);
+        registry.delete_file(file_info).await?;
+        Ok(true)
+    }
+}
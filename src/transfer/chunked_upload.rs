@@ -0,0 +1,218 @@
+use crate::{
+    common::{
+        chunker::{MAX_CHUNK_SIZE, MIN_CHUNK_SIZE},
+        entity::ChunkInfo,
+        hasher::HashAlgorithm,
+    },
+    server::entity::SyncFileInfo,
+    storage::{
+        chunked_file_reader::{chunk_blob, ChunkedFileReader},
+        file_storage::{FileReader, FileStorage, FileWriter},
+        StorageContext,
+    },
+};
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use tracing::{debug, error};
+
+const VERIFY_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Receives the chunks of an offered file the server doesn't have yet, then stores the file as its manifest
+pub struct ChunkedUpload {
+    hash_algorithm: HashAlgorithm,
+    file_info: SyncFileInfo,
+    chunks: Vec<ChunkInfo>,
+    /// where each chunk starts in the file
+    offsets: Vec<usize>,
+    /// the chunks to be received, in the order they are sent
+    missing: Vec<usize>,
+    /// position in `missing` of the chunk being received
+    next: usize,
+    /// bytes of that chunk received so far
+    received: usize,
+    writer: Option<Box<dyn FileWriter>>,
+}
+
+impl ChunkedUpload {
+    /// The chunks have to add up to the file and be cut the way the chunker cuts them, tiny chunks would only
+    /// bloat the manifest
+    pub fn validate(hash_algorithm: HashAlgorithm, file_size: usize, chunks: &[ChunkInfo]) -> core::result::Result<(), &'static str> {
+        if chunks.iter().any(|chunk| !hash_algorithm.is_valid_hash(&chunk.hash)) {
+            return Err("invalid chunk hash");
+        }
+
+        let last = chunks.len().saturating_sub(1);
+        let cut_as_chunker = chunks
+            .iter()
+            .enumerate()
+            .all(|(index, chunk)| chunk.size > 0 && chunk.size <= MAX_CHUNK_SIZE && (index == last || chunk.size >= MIN_CHUNK_SIZE));
+        if !cut_as_chunker {
+            return Err("invalid chunk size");
+        }
+
+        if chunks.iter().map(|chunk| chunk.size).sum::<usize>() != file_size {
+            return Err("chunks don't add up to the file size");
+        }
+        Ok(())
+    }
+
+    /// A chunk listed more than once is only sent the first time
+    pub fn new(hash_algorithm: HashAlgorithm, storage_ctx: &StorageContext, file_info: SyncFileInfo, chunks: Vec<ChunkInfo>) -> Self {
+        let mut offsets = Vec::with_capacity(chunks.len());
+        let mut offset = 0;
+        for chunk in &chunks {
+            offsets.push(offset);
+            offset += chunk.size;
+        }
+
+        let mut seen = HashSet::new();
+        let missing: Vec<usize> = chunks
+            .iter()
+            .enumerate()
+            .filter(|(_, chunk)| seen.insert(chunk.hash.as_str()) && storage_ctx.db.query_chunk(&chunk.hash).is_none())
+            .map(|(index, _)| index)
+            .collect();
+        debug!(
+            "chunked upload:{}, chunks:{}, missing:{}",
+            file_info.file_hash,
+            chunks.len(),
+            missing.len()
+        );
+
+        Self {
+            hash_algorithm,
+            file_info,
+            chunks,
+            offsets,
+            missing,
+            next: 0,
+            received: 0,
+            writer: None,
+        }
+    }
+
+    pub fn file_info(&self) -> &SyncFileInfo {
+        &self.file_info
+    }
+
+    pub fn missing(&self) -> &[usize] {
+        &self.missing
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.next == self.missing.len()
+    }
+
+    /// Where the next data frame has to start, None once all missing chunks were received
+    pub fn next_offset(&self) -> Option<usize> {
+        self.missing.get(self.next).map(|index| self.offsets[*index] + self.received)
+    }
+
+    pub async fn write(&mut self, storage_ctx: &mut StorageContext, data: &[u8]) -> Result<()> {
+        let Some(&index) = self.missing.get(self.next) else {
+            bail!("all chunks already received");
+        };
+        if self.received + data.len() > self.chunks[index].size {
+            bail!("data crosses the end of chunk:{index}");
+        }
+
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => self
+                .writer
+                .insert(storage_ctx.file_storage.open_writer(&chunk_blob(&self.chunk_info(index))).await?),
+        };
+        writer.write(data).await?;
+        self.received += data.len();
+
+        if self.received == self.chunks[index].size {
+            self.store_chunk(storage_ctx, index).await?;
+        }
+        Ok(())
+    }
+
+    /// A chunk is stored as soon as it's complete, an interrupted upload doesn't need to send it again
+    async fn store_chunk(&mut self, storage_ctx: &mut StorageContext, index: usize) -> Result<()> {
+        let Some(mut writer) = self.writer.take() else {
+            bail!("no writer for chunk:{index}");
+        };
+
+        let chunk_hash = &self.chunks[index].hash;
+        let blob = writer.close().await?;
+        if blob.digest != *chunk_hash {
+            if let Err(e) = writer.discard().await {
+                error!("failed to discard rejected chunk: {e:?}");
+            }
+            bail!("chunk hash mismatch, declared:{chunk_hash}, actual:{}", blob.digest);
+        }
+        writer.commit().await?;
+
+        let mut chunk = self.chunk_info(index);
+        chunk.codec = blob.codec;
+        storage_ctx.db.save_chunk(&chunk)?;
+
+        self.next += 1;
+        self.received = 0;
+        Ok(())
+    }
+
+    /// Chunks stored by other uploads were only verified against their own hashes, so the file is read back
+    /// once to make sure they add up to the declared content before anyone can dedup against it
+    pub async fn finish(&mut self, storage_ctx: &mut StorageContext) -> Result<()> {
+        if !self.is_complete() {
+            bail!("chunks still missing:{}", self.missing.len() - self.next);
+        }
+
+        let mut chunks = Vec::with_capacity(self.chunks.len());
+        for chunk in &self.chunks {
+            let Some(stored) = storage_ctx.db.query_chunk(&chunk.hash) else {
+                bail!("chunk is gone:{}, try again", chunk.hash);
+            };
+            chunks.push(stored);
+        }
+
+        let mut reader = ChunkedFileReader::new(storage_ctx.file_storage.clone(), chunks);
+        let mut hasher = self.hash_algorithm.hasher();
+        let mut buf = vec![0u8; VERIFY_BUFFER_SIZE];
+        loop {
+            let len = reader.read(&mut buf).await?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+        }
+        reader.close().await;
+
+        let digest = hasher.finalize();
+        if digest != self.file_info.file_hash {
+            bail!("file hash mismatch, declared:{}, actual:{digest}", self.file_info.file_hash);
+        }
+
+        let chunk_hashes: Vec<String> = self.chunks.iter().map(|chunk| chunk.hash.clone()).collect();
+        if !storage_ctx.db.save_manifest(&self.file_info.file_hash, &chunk_hashes)? {
+            bail!("stored content changed during upload, try again");
+        }
+        self.file_info.sync_size = self.file_info.file_size;
+        self.file_info.chunked = true;
+        Ok(())
+    }
+
+    /// The chunk being received is dropped, the chunks completed before stay for a later upload to reuse
+    pub async fn abort(mut self) {
+        if let Some(mut writer) = self.writer.take() {
+            if let Err(e) = writer.discard().await {
+                error!("failed to discard partial chunk: {e:?}");
+            }
+        }
+    }
+
+    fn chunk_info(&self, index: usize) -> SyncFileInfo {
+        SyncFileInfo {
+            file_hash: self.chunks[index].hash.clone(),
+            file_size: self.chunks[index].size,
+            sync_size: 0,
+            storage_backend: self.file_info.storage_backend.clone(),
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{
+    common::{delta, entity::BlockSignature, hasher::HashAlgorithm},
+    server::entity::SyncFileInfo,
+    storage::{
+        file_storage::{read_stored, FileReader, FileStorage, FileWriter},
+        StorageContext,
+    },
+};
+use anyhow::{bail, Result};
+use tracing::{debug, error};
+
+const COPY_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Builds the new content of a file from blocks of its current content and the literal data in between. The new
+/// content is written as a blob of its own, the file is only pointed at it once it's complete and verified.
+pub struct DeltaUpload {
+    file_info: SyncFileInfo,
+    /// the content being updated, None if the file doesn't exist yet
+    base_hash: Option<String>,
+    base_reader: Option<Box<dyn FileReader>>,
+    block_size: usize,
+    /// only whole blocks are signed, a shorter block at the end can't be copied
+    block_count: usize,
+    writer: Box<dyn FileWriter>,
+}
+
+impl DeltaUpload {
+    /// Opens the blob of the new content `file_info` and the current content, whose blocks are signed with `sign_base`
+    pub async fn start(storage_ctx: &mut StorageContext, file_info: SyncFileInfo, base: Option<&SyncFileInfo>) -> Result<Self> {
+        let block_size = delta::block_size(base.map_or(0, |base| base.file_size));
+        let (base_hash, base_reader, block_count) = match base {
+            Some(base) => (
+                Some(base.file_hash.clone()),
+                Some(storage_ctx.open_reader(base).await?),
+                base.file_size / block_size,
+            ),
+            None => (None, None, 0),
+        };
+        debug!(
+            "delta upload:{}, base:{base_hash:?}, block size:{block_size}, blocks:{block_count}",
+            file_info.file_hash
+        );
+
+        // recorded without a reference, an update that doesn't complete is left to expire as abandoned
+        storage_ctx.db.save_shared_file(&file_info)?;
+        let writer = storage_ctx.file_storage.open_writer(&file_info).await?;
+        Ok(Self {
+            file_info,
+            base_hash,
+            base_reader,
+            block_size,
+            block_count,
+            writer,
+        })
+    }
+
+    /// Signs the blocks of the current content read by `reader`. It reads all of it, so it's run apart from the
+    /// transfer session.
+    pub async fn sign_base(
+        hash_algorithm: HashAlgorithm,
+        mut reader: Box<dyn FileReader>,
+        block_size: usize,
+        block_count: usize,
+    ) -> Result<Vec<BlockSignature>> {
+        let mut blocks = Vec::with_capacity(block_count);
+        let signed = async {
+            for index in 0..block_count {
+                let block = read_stored(&mut reader, (index * block_size) as u64, block_size as u64).await?;
+                blocks.push(delta::signature(hash_algorithm, &block));
+            }
+            Ok(())
+        }
+        .await;
+        reader.close().await;
+        signed.map(|_| blocks)
+    }
+
+    pub fn file_info(&self) -> &SyncFileInfo {
+        &self.file_info
+    }
+
+    pub fn base_hash(&self) -> Option<&str> {
+        self.base_hash.as_deref()
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn block_count(&self) -> usize {
+        self.block_count
+    }
+
+    /// Where the next copy or data frame has to start
+    pub fn next_offset(&self) -> usize {
+        self.file_info.sync_size
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.file_info.sync_size >= self.file_info.file_size
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        if self.file_info.sync_size + data.len() > self.file_info.file_size {
+            bail!("data exceeds the file size:{}", self.file_info.file_size);
+        }
+        self.file_info.sync_size += self.writer.write(data).await?;
+        Ok(())
+    }
+
+    pub async fn copy(&mut self, block_index: usize, count: usize) -> Result<()> {
+        let Some(reader) = &mut self.base_reader else {
+            bail!("no current content to copy from");
+        };
+        if count == 0 || block_index.checked_add(count).is_none_or(|end| end > self.block_count) {
+            bail!("blocks out of range:{block_index}+{count}, blocks:{}", self.block_count);
+        }
+
+        let mut pos = block_index * self.block_size;
+        let end = pos + count * self.block_size;
+        if self.file_info.sync_size + (end - pos) > self.file_info.file_size {
+            bail!("copy exceeds the file size:{}", self.file_info.file_size);
+        }
+
+        while pos < end {
+            let len = (end - pos).min(COPY_BUFFER_SIZE);
+            let data = read_stored(reader, pos as u64, len as u64).await?;
+            self.file_info.sync_size += self.writer.write(&data).await?;
+            pos += len;
+        }
+        Ok(())
+    }
+
+    /// Returns false if the file was changed by another upload in the meantime, the new content is then left
+    /// for the garbage collector
+    pub async fn finish(&mut self, user_id: u32, storage_ctx: &mut StorageContext) -> Result<bool> {
+        if let Some(mut reader) = self.base_reader.take() {
+            reader.close().await;
+        }
+
+        let blob = self.writer.close().await?;
+        if blob.digest != self.file_info.file_hash {
+            if let Err(e) = self.writer.discard().await {
+                error!("failed to discard rejected blob: {e:?}");
+            }
+            bail!("file hash mismatch, declared:{}, actual:{}", self.file_info.file_hash, blob.digest);
+        }
+        self.writer.commit().await?;
+
+        self.file_info.codec = blob.codec;
+        storage_ctx
+            .db
+            .replace_file_content(user_id, &self.file_info, self.base_hash.as_deref())
+    }
+
+    /// Nothing of an unfinished update is kept, it can't be resumed
+    pub async fn abort(mut self) {
+        if let Some(mut reader) = self.base_reader.take() {
+            reader.close().await;
+        }
+        if let Err(e) = self.writer.discard().await {
+            error!("failed to discard partial blob: {e:?}");
+        }
+    }
+}
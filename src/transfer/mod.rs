@@ -1 +1,4 @@
+pub mod chunked_upload;
+pub mod delta_upload;
+pub mod spooled_upload;
 pub mod transfer_task;
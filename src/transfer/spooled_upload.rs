@@ -0,0 +1,189 @@
+use crate::{
+    common::hasher::HashAlgorithm,
+    server::entity::SyncFileInfo,
+    storage::{file_storage::FileStorage, StorageContext},
+};
+use anyhow::{bail, Context, Result};
+use futures_util::{Stream, StreamExt};
+use rand::RngCore;
+use serde::Serialize;
+use std::{
+    fmt::{self, Display},
+    fs,
+    path::PathBuf,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{debug, info};
+
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Serialize, Debug)]
+pub struct UploadedFile {
+    pub file_hash: String,
+    pub file_size: usize,
+    pub file_dir: String,
+    pub file_name: String,
+    /// the content was already stored, no bytes were written
+    pub deduplicated: bool,
+}
+
+/// The client's upload was refused, as opposed to the server failing to spool it
+#[derive(Debug)]
+pub enum UploadRejected {
+    TooLarge(u64),
+    /// the body stream failed, e.g. the client disconnected or sent a malformed multipart field
+    Body(String),
+}
+
+impl fmt::Display for UploadRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadRejected::TooLarge(max_size) => write!(f, "upload exceeds the limit of {max_size} bytes"),
+            UploadRejected::Body(e) => write!(f, "failed to receive upload: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for UploadRejected {}
+
+/// HTTP uploads don't declare their hash up front, so the body is spooled to a temp file
+/// and hashed on the way, then stored through the same path as `TransferTask`.
+pub struct SpooledUpload {
+    path: PathBuf,
+    file_hash: String,
+    file_size: usize,
+}
+
+impl SpooledUpload {
+    pub async fn spool<S, B, E>(mut stream: S, max_size: u64, hash_algorithm: HashAlgorithm) -> Result<Self>
+    where
+        S: Stream<Item = core::result::Result<B, E>> + Unpin,
+        B: AsRef<[u8]>,
+        E: Display,
+    {
+        let mut suffix = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut suffix);
+        let path = std::env::temp_dir().join(format!("rsdrive-upload-{}", hex::encode(suffix)));
+
+        let mut spooled = Self {
+            path,
+            file_hash: String::new(),
+            file_size: 0,
+        };
+
+        let mut file = tokio::fs::File::create(&spooled.path)
+            .await
+            .context(format!("failed to create spool file:{:?}", spooled.path))?;
+        let mut hasher = hash_algorithm.hasher();
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => return Err(UploadRejected::Body(e.to_string()).into()),
+            };
+            let chunk = chunk.as_ref();
+
+            if spooled.file_size as u64 + chunk.len() as u64 > max_size {
+                return Err(UploadRejected::TooLarge(max_size).into());
+            }
+            spooled.file_size += chunk.len();
+
+            hasher.update(chunk);
+            file.write_all(chunk).await?;
+        }
+        file.flush().await?;
+
+        spooled.file_hash = hasher.finalize();
+        Ok(spooled)
+    }
+
+    pub fn file_hash(&self) -> &str {
+        &self.file_hash
+    }
+
+    pub fn file_size(&self) -> usize {
+        self.file_size
+    }
+
+    /// Links the content into the user's `file_dir`/`file_name`, writing the blob only if it isn't stored yet.
+    /// `base_hash` is the content the file has now (None if it doesn't exist), it's only replaced once the new
+    /// content is completely stored, so a failed upload leaves the file as it was.
+    pub async fn store(
+        &self,
+        user_id: u32,
+        device: &str,
+        storage_ctx: &mut StorageContext,
+        file_dir: &str,
+        file_name: &str,
+        base_hash: Option<&str>,
+    ) -> Result<UploadedFile> {
+        let shared = storage_ctx.db.query_shared_file(&self.file_hash);
+        let deduplicated = matches!(&shared, Some(shared) if shared.sync_size >= shared.file_size);
+        let mut file_info = SyncFileInfo {
+            file_hash: self.file_hash.clone(),
+            file_size: self.file_size,
+            sync_size: 0,
+            file_dir: file_dir.to_string(),
+            file_name: file_name.to_string(),
+            file_meta: "".to_string(),
+            storage_backend: match shared {
+                Some(shared) => shared.storage_backend,
+                None => storage_ctx.file_storage.route(user_id, self.file_size).to_string(),
+            },
+            codec: String::new(),
+            chunked: false,
+            device: device.to_string(),
+        };
+        if deduplicated {
+            debug!("content already stored:{}", self.file_hash);
+            file_info.sync_size = file_info.file_size;
+        } else {
+            // recorded without a reference, an upload that doesn't complete is left to expire as abandoned
+            storage_ctx.db.save_shared_file(&file_info)?;
+            let mut writer = storage_ctx.file_storage.open_writer(&file_info).await?;
+            let mut file = tokio::fs::File::open(&self.path).await?;
+            let mut buf = vec![0u8; COPY_CHUNK_SIZE];
+            loop {
+                let len = file.read(&mut buf).await?;
+                if len == 0 {
+                    break;
+                }
+                file_info.sync_size += writer.write(&buf[..len]).await?;
+            }
+            let blob = writer.close().await?;
+            if blob.digest != self.file_hash {
+                writer.discard().await?;
+                bail!(
+                    "stored content doesn't match the spooled hash:{}, actual:{}",
+                    self.file_hash,
+                    blob.digest
+                );
+            }
+            writer.commit().await?;
+            file_info.codec = blob.codec;
+            storage_ctx.db.update_sync_size(user_id, &file_info)?;
+        }
+
+        let unchanged = deduplicated && base_hash == Some(self.file_hash.as_str());
+        if !unchanged && !storage_ctx.db.replace_file_content(user_id, &file_info, base_hash)? {
+            bail!("file changed during upload:{file_dir}{file_name}");
+        }
+
+        info!(
+            "http upload stored:{file_dir}{file_name}, hash:{}, deduplicated:{deduplicated}",
+            self.file_hash
+        );
+        Ok(UploadedFile {
+            file_hash: file_info.file_hash,
+            file_size: file_info.file_size,
+            file_dir: file_info.file_dir,
+            file_name: file_info.file_name,
+            deduplicated,
+        })
+    }
+}
+
+impl Drop for SpooledUpload {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
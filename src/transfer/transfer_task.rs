@@ -1,111 +1,216 @@
 use crate::{
-    common::entity::{TransferControlMessage, TransferRequest, TransferResponse},
-    server::entity::SyncFileInfo,
-    storage::{file_storage::FileWriter, StorageContext},
+    common::{
+        entity::{
+            ChunkNeed, ChunkOffer, ConflictPolicy, DeleteRequest, DeleteResponse, DeltaCopy, DeltaSignatures, DownloadRequest,
+            DownloadResponse, RestoreRequest, RestoreResponse, TransferControlMessage, TransferError, TransferRequest, TransferResponse,
+            VersionList, VersionListRequest, WindowUpdate,
+        },
+        frame::{DataFrame, INITIAL_WINDOW_SIZE},
+        hasher::HashAlgorithm,
+    },
+    server::entity::{Credential, SyncFileInfo, TokenScope},
+    storage::{
+        async_file_reader::AsyncFileReader,
+        file_storage::{FileReader, FileStorage, FileWriter},
+        storage_registry::BlobInUse,
+        StorageContext,
+    },
+    transfer::{chunked_upload::ChunkedUpload, delta_upload::DeltaUpload},
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
 use axum::extract::ws::{Message, WebSocket};
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use std::{collections::HashMap, io::SeekFrom, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::{mpsc, Semaphore},
+    task::JoinHandle,
+};
 use tracing::{debug, error, info, warn};
 
-#[derive(PartialEq)]
+/// State of one multiplexed stream, its entry is dropped once its transfer ends so the stream can carry the next file
 pub enum TransferState {
+    /// requested but not accepted yet, the stream is released again if its request is refused
     Pending,
-    Receiving(TransferRequest),
+    Receiving {
+        file_info: SyncFileInfo,
+        writer: Box<dyn FileWriter>,
+        /// the content of the file being overwritten, it's only replaced once the new content is complete
+        replacing: Option<String>,
+        /// bytes the client may still send before it needs another window update
+        window: usize,
+        /// bytes written since the last window update
+        consumed: usize,
+    },
+    ReceivingChunks {
+        upload: ChunkedUpload,
+        replacing: Option<String>,
+        window: usize,
+        consumed: usize,
+    },
+    /// the blocks of the current content are signed by `task`, which sends the signatures and returns whether it did.
+    /// The client only sends copies and data once it has them.
+    SigningDelta { upload: DeltaUpload, task: JoinHandle<bool> },
+    /// only literal data counts against the window, copied blocks are read from storage
+    ReceivingDelta {
+        upload: DeltaUpload,
+        window: usize,
+        consumed: usize,
+    },
+    Sending {
+        /// bytes the download may still send, the client adds to it with window updates
+        credit: Arc<Semaphore>,
+        task: JoinHandle<()>,
+    },
+}
+
+impl TransferState {
+    /// The file the stream is uploading, if it's an upload
+    fn uploading(&self) -> Option<&SyncFileInfo> {
+        match self {
+            TransferState::Receiving { file_info, .. } => Some(file_info),
+            TransferState::ReceivingChunks { upload, .. } => Some(upload.file_info()),
+            TransferState::SigningDelta { upload, .. } | TransferState::ReceivingDelta { upload, .. } => Some(upload.file_info()),
+            TransferState::Pending | TransferState::Sending { .. } => None,
+        }
+    }
 }
 
+type Streams = HashMap<u32, TransferState>;
+
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+const OUTGOING_QUEUE_SIZE: usize = 64;
+const MAX_FILE_META_SIZE: usize = 4096;
+/// how many copies of a name keeping both files tries
+const MAX_KEPT_COPIES: usize = 1000;
+/// how often the blobs of running uploads are marked as progressing, see `GcConfig::partial_upload_ttl_secs`
+const UPLOAD_TOUCH_INTERVAL: Duration = Duration::from_secs(60);
+
 #[derive(Default)]
-pub struct TransferTask {}
+pub struct TransferTask {
+    hash_algorithm: HashAlgorithm,
+}
 
 impl TransferTask {
-    pub fn start(&self, user_id: u32, socket: WebSocket, storage_ctx: Box<StorageContext>) {
+    pub fn new(hash_algorithm: HashAlgorithm) -> Self {
+        Self { hash_algorithm }
+    }
+
+    pub fn start(&self, user_id: u32, credential: Credential, socket: WebSocket, storage_ctx: Box<StorageContext>) {
+        let hash_algorithm = self.hash_algorithm;
         tokio::spawn(async move {
-            TransferTask::run(user_id, socket, storage_ctx).await.map_err(|e| {
-                error!("{e}");
-            })
+            TransferTask::run(user_id, credential, hash_algorithm, socket, storage_ctx)
+                .await
+                .map_err(|e| {
+                    error!("{e}");
+                })
         });
     }
 
-    async fn run(user_id: u32, socket: WebSocket, storage_ctx: Box<StorageContext>) -> Result<()> {
-        let mut file_writer: Option<Box<dyn FileWriter>> = None;
-        let mut file_info = SyncFileInfo::default();
-
+    async fn run(
+        user_id: u32,
+        credential: Credential,
+        hash_algorithm: HashAlgorithm,
+        socket: WebSocket,
+        mut storage_ctx: Box<StorageContext>,
+    ) -> Result<()> {
         info!("start transferring...");
 
-        let (mut sender, mut receiver) = socket.split();
-        while let Some(msg) = receiver.next().await {
+        // all outgoing messages go through one queue, so downloads running as separate tasks can interleave
+        let (sink, receiver) = socket.split();
+        let (sender, outgoing) = mpsc::channel(OUTGOING_QUEUE_SIZE);
+        let forwarder = tokio::spawn(Self::forward_outgoing(sink, outgoing));
+
+        let mut streams = Streams::new();
+        let result = Self::serve(
+            user_id,
+            &credential,
+            hash_algorithm,
+            receiver,
+            &sender,
+            &mut storage_ctx,
+            &mut streams,
+        )
+        .await;
+
+        // every stream gets to save its progress, even if finalizing another one failed
+        let mut finalized = Ok(());
+        for (stream_id, state) in streams.drain() {
+            if let Err(e) = Self::finalize_stream(user_id, state, &mut storage_ctx).await {
+                error!("failed to finalize stream:{stream_id}: {e:?}");
+                finalized = finalized.and(Err(e));
+            }
+        }
+        drop(sender);
+        let _ = forwarder.await;
+
+        debug!("transfer task ended!");
+        result.and(finalized)
+    }
+
+    async fn serve(
+        user_id: u32,
+        credential: &Credential,
+        hash_algorithm: HashAlgorithm,
+        mut receiver: SplitStream<WebSocket>,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        streams: &mut Streams,
+    ) -> Result<()> {
+        let mut touch_interval = tokio::time::interval(UPLOAD_TOUCH_INTERVAL);
+        loop {
+            let msg = tokio::select! {
+                msg = receiver.next() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = touch_interval.tick() => {
+                    Self::touch_uploads(storage_ctx, streams);
+                    Self::settle_signed(sender, streams).await?;
+                    continue;
+                }
+            };
             match msg {
                 Ok(Message::Text(text)) => {
-                    let trans_req = match TransferControlMessage::try_from(text.as_str()) {
-                        Ok(TransferControlMessage::Request(req)) => req,
-                        Ok(msg) => {
-                            warn!("unexpected message:{msg:?}");
-                            continue;
-                        }
+                    let msg = match TransferControlMessage::try_from(text.as_str()) {
+                        Ok(msg) => msg,
+                        // a malformed message can't be tied to a stream, the session carries on without it
                         Err(e) => {
-                            error!("invalid request, failed to convert to json:{e:?}");
-                            break;
+                            warn!("invalid request, failed to convert to json:{e:?}");
+                            Self::send_error(sender, None, "invalid message").await?;
+                            continue;
                         }
                     };
-
-                    let mut trans_resp = TransferResponse {
-                        file_hash: trans_req.file_hash.clone(),
-                        sync_size: 0,
-                    };
-
-                    file_info = match storage_ctx.db.query_file_info(user_id, &trans_req.file_dir, &trans_req.file_name) {
-                        Some(file_info) => {
-                            debug!("transferring partial file:{file_info:?}");
-                            trans_resp.sync_size = file_info.sync_size;
-                            file_info
-                        }
-                        None => {
-                            debug!("transferring new file:{}, size:{}", trans_req.file_hash, trans_req.file_size);
-                            let file_info = SyncFileInfo {
-                                file_hash: trans_req.file_hash.clone(),
-                                file_dir: trans_req.file_dir.clone(),
-                                file_name: trans_req.file_name.clone(),
-                                file_size: trans_req.file_size,
-                                sync_size: 0,
-                                file_meta: "".to_string(),
-                            };
-
-                            storage_ctx.db.save_file_info(user_id, &file_info)?;
-                            file_info
+                    let stream_id = msg.stream_id();
+                    let opening = Self::opened_stream(&msg);
+                    if let Some(stream_id) = opening {
+                        Self::settle_signed(sender, streams).await?;
+                        if !Self::reserve_stream(streams, stream_id) {
+                            warn!("stream already in use:{stream_id}");
+                            Self::send_error(sender, Some(stream_id), "stream already in use").await?;
+                            continue;
                         }
-                    };
-
-                    let writer = match storage_ctx.file_storage.open_writer(&file_info) {
-                        Ok(writer) => writer,
-                        Err(e) => {
-                            error!("failed to open writer: {e:?}");
-                            break;
+                    }
+                    if let Err(e) = Self::handle_message(user_id, credential, hash_algorithm, sender, storage_ctx, streams, msg).await {
+                        Self::fail_stream(user_id, sender, storage_ctx, streams, stream_id, e).await?;
+                    }
+                    // a request that wasn't accepted leaves its stream pending
+                    if let Some(stream_id) = opening {
+                        if matches!(streams.get(&stream_id), Some(TransferState::Pending)) {
+                            streams.remove(&stream_id);
                         }
-                    };
-
-                    Self::finalize_writer_if_needed(user_id, file_writer, &storage_ctx, &file_info)?;
-
-                    file_writer = Some(writer);
-                    sender.send(TransferControlMessage::Response(trans_resp).into()).await.unwrap();
+                    }
                 }
 
-                Ok(Message::Binary(data)) => match &mut file_writer {
-                    Some(writer) => {
-                        file_info.sync_size += writer.write(&data)?;
-
-                        // debug!("transferring, len:{}, {}/{}", data.len(), file_info.sync_size, file_info.file_size);
-                        if file_info.sync_size >= file_info.file_size {
-                            writer.close();
-                            storage_ctx.db.update_sync_size(user_id, &file_info)?;
-                            file_writer = None;
-                            debug!("transfer completed, {}/{}", file_info.sync_size, file_info.file_size);
-                            break;
-                        }
-                    }
-                    None => {
-                        warn!("received unexpected binary data:{}", data.len());
+                Ok(Message::Binary(data)) => {
+                    if let Err(e) = Self::receive_frame(user_id, sender, storage_ctx, streams, &data).await {
+                        let stream_id = DataFrame::decode(&data).map(|frame| frame.stream_id);
+                        Self::fail_stream(user_id, sender, storage_ctx, streams, stream_id, e).await?;
                     }
-                },
+                }
 
                 Ok(Message::Close(c)) => {
                     debug!("received close frame:{c:?}");
@@ -123,24 +228,1237 @@ impl TransferTask {
             }
         }
 
-        Self::finalize_writer_if_needed(user_id, file_writer, &storage_ctx, &file_info)?;
+        Ok(())
+    }
+
+    /// The stream a request opens, if it's one that starts a transfer
+    fn opened_stream(msg: &TransferControlMessage) -> Option<u32> {
+        match msg {
+            TransferControlMessage::Request(_)
+            | TransferControlMessage::ChunkOffer(_)
+            | TransferControlMessage::DeltaRequest(_)
+            | TransferControlMessage::Download(_) => msg.stream_id(),
+            _ => None,
+        }
+    }
+
+    /// Marks the stream pending for a new request, unless it's still carrying a transfer
+    fn reserve_stream(streams: &mut Streams, stream_id: u32) -> bool {
+        streams.retain(|_, state| !matches!(state, TransferState::Sending { task, .. } if task.is_finished()));
+        if streams.contains_key(&stream_id) {
+            return false;
+        }
+        streams.insert(stream_id, TransferState::Pending);
+        true
+    }
+
+    /// The progress of an upload is only recorded once it stops, so its blob would look abandoned meanwhile
+    fn touch_uploads(storage_ctx: &StorageContext, streams: &Streams) {
+        for file_info in streams.values().filter_map(TransferState::uploading) {
+            if let Err(e) = storage_ctx.db.touch_shared_file(&file_info.file_hash) {
+                error!("failed to touch blob:{}, {e:?}", file_info.file_hash);
+            }
+        }
+    }
+
+    async fn handle_message(
+        user_id: u32,
+        credential: &Credential,
+        hash_algorithm: HashAlgorithm,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        streams: &mut Streams,
+        msg: TransferControlMessage,
+    ) -> Result<()> {
+        match msg {
+            TransferControlMessage::Request(req) => {
+                Self::start_upload(user_id, credential, hash_algorithm, sender, storage_ctx, streams, req).await?;
+            }
+            TransferControlMessage::ChunkOffer(offer) => {
+                Self::start_chunked_upload(user_id, credential, hash_algorithm, sender, storage_ctx, streams, offer).await?;
+            }
+            TransferControlMessage::DeltaRequest(req) => {
+                Self::start_delta_upload(user_id, credential, hash_algorithm, sender, storage_ctx, streams, req).await?;
+            }
+            TransferControlMessage::DeltaCopy(copy) => {
+                Self::await_signatures(sender, streams, copy.stream_id).await?;
+                Self::receive_delta_copy(user_id, sender, storage_ctx, streams, copy).await?;
+            }
+            TransferControlMessage::Download(req) => {
+                Self::start_download(user_id, credential, sender, storage_ctx, streams, req).await?;
+            }
+            TransferControlMessage::Delete(req) => {
+                if !credential.allows(TokenScope::Delete) {
+                    warn!("delete not permitted for credential:{credential:?}");
+                    return Self::send_error(sender, None, "permission denied").await;
+                }
+
+                // a file deleted while it is being uploaded stops receiving data
+                let deleting: Vec<u32> = streams
+                    .iter()
+                    .filter(|(_, state)| {
+                        matches!(state.uploading(), Some(file_info)
+                            if file_info.file_dir == req.file_dir && file_info.file_name == req.file_name)
+                    })
+                    .map(|(stream_id, _)| *stream_id)
+                    .collect();
+                for stream_id in deleting {
+                    match streams.remove(&stream_id) {
+                        Some(TransferState::Receiving { mut writer, .. }) => {
+                            if let Err(e) = writer.close().await {
+                                error!("failed to close writer: {e:?}");
+                            }
+                        }
+                        Some(TransferState::ReceivingChunks { upload, .. }) => upload.abort().await,
+                        Some(TransferState::SigningDelta { upload, task }) => {
+                            task.abort();
+                            upload.abort().await;
+                        }
+                        Some(TransferState::ReceivingDelta { upload, .. }) => upload.abort().await,
+                        _ => {}
+                    }
+                    Self::send_error(sender, Some(stream_id), "file deleted").await?;
+                }
+
+                let resp = Self::delete_file(user_id, storage_ctx, req)?;
+                sender.send(TransferControlMessage::Deleted(resp).into()).await?;
+            }
+            TransferControlMessage::ListVersions(req) => {
+                Self::list_versions(user_id, credential, sender, storage_ctx, req).await?;
+            }
+            TransferControlMessage::Restore(req) => {
+                Self::restore_version(user_id, credential, sender, storage_ctx, streams, req).await?;
+            }
+            TransferControlMessage::Window(update) => match streams.get(&update.stream_id) {
+                Some(TransferState::Sending { credit, .. }) => {
+                    credit.add_permits(update.size.min(Semaphore::MAX_PERMITS - credit.available_permits()));
+                }
+                _ => debug!("window update for inactive stream:{}", update.stream_id),
+            },
+            msg => {
+                warn!("unexpected message:{msg:?}");
+            }
+        }
 
-        debug!("transfer task ended!");
         Ok(())
     }
 
-    fn finalize_writer_if_needed(
+    /// A failure of one stream only ends that stream, the other transfers on the connection carry on. The session
+    /// only ends if the error can't even be reported.
+    async fn fail_stream(
+        user_id: u32,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        streams: &mut Streams,
+        stream_id: Option<u32>,
+        e: anyhow::Error,
+    ) -> Result<()> {
+        error!("stream:{stream_id:?} failed: {e:?}");
+        if let Some(state) = stream_id.and_then(|stream_id| streams.remove(&stream_id)) {
+            if let Err(e) = Self::finalize_stream(user_id, state, storage_ctx).await {
+                error!("failed to finalize stream:{stream_id:?}: {e:?}");
+            }
+        }
+        Self::send_error(sender, stream_id, &e.to_string()).await
+    }
+
+    async fn forward_outgoing(mut sink: SplitSink<WebSocket, Message>, mut outgoing: mpsc::Receiver<Message>) {
+        while let Some(msg) = outgoing.recv().await {
+            if let Err(e) = sink.send(msg).await {
+                debug!("send failed:{e}");
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    }
+
+    async fn start_upload(
+        user_id: u32,
+        credential: &Credential,
+        hash_algorithm: HashAlgorithm,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        streams: &mut Streams,
+        mut trans_req: TransferRequest,
+    ) -> Result<()> {
+        let stream_id = trans_req.stream_id;
+        let shared = match Self::check_upload(credential, hash_algorithm, storage_ctx, streams, &trans_req) {
+            Ok(shared) => shared,
+            Err(msg) => return Self::send_error(sender, Some(stream_id), msg).await,
+        };
+        let replacing = match Self::resolve_conflict(user_id, storage_ctx, &mut trans_req) {
+            Ok(replacing) => replacing,
+            Err(msg) => return Self::send_error(sender, Some(stream_id), msg).await,
+        };
+
+        if let Some(msg) = Self::link_existing_content(user_id, credential, storage_ctx, &trans_req, replacing.as_deref())? {
+            return Ok(sender.send(msg.into()).await?);
+        }
+
+        // a file found under the name has the uploaded content once conflicts are resolved
+        let file_info = match storage_ctx.db.query_file_info(user_id, &trans_req.file_dir, &trans_req.file_name) {
+            Some(file_info) if replacing.is_none() => {
+                debug!("transferring partial file:{file_info:?}");
+                file_info
+            }
+            _ => {
+                debug!("transferring new file:{}, size:{}", trans_req.file_hash, trans_req.file_size);
+                Self::create_file_info(user_id, credential, storage_ctx, &trans_req, shared, replacing.is_some())?
+            }
+        };
+        let trans_resp = TransferResponse {
+            stream_id,
+            file_hash: trans_req.file_hash.clone(),
+            sync_size: file_info.sync_size,
+            file_name: file_info.file_name.clone(),
+        };
+
+        let writer = match storage_ctx.file_storage.open_writer(&file_info).await {
+            Ok(writer) => writer,
+            Err(e) if e.is::<BlobInUse>() => {
+                warn!("{e}");
+                return Self::send_error(sender, Some(stream_id), "file already being uploaded").await;
+            }
+            Err(e) => {
+                error!("failed to open writer: {e:?}");
+                return Self::send_error(sender, Some(stream_id), "file not available").await;
+            }
+        };
+
+        streams.insert(
+            stream_id,
+            TransferState::Receiving {
+                file_info,
+                writer,
+                replacing,
+                window: INITIAL_WINDOW_SIZE,
+                consumed: 0,
+            },
+        );
+        Ok(sender.send(TransferControlMessage::Response(trans_resp).into()).await?)
+    }
+
+    /// Checks shared by both kinds of uploads, returns the blob already recorded for the hash
+    fn check_upload(
+        credential: &Credential,
+        hash_algorithm: HashAlgorithm,
+        storage_ctx: &StorageContext,
+        streams: &Streams,
+        trans_req: &TransferRequest,
+    ) -> core::result::Result<Option<SyncFileInfo>, &'static str> {
+        if !credential.allows(TokenScope::Upload) {
+            warn!("upload not permitted for credential:{credential:?}");
+            return Err("permission denied");
+        }
+
+        if !hash_algorithm.is_valid_hash(&trans_req.file_hash) {
+            warn!("invalid {hash_algorithm:?} file hash:{}", trans_req.file_hash);
+            return Err("invalid file hash");
+        }
+
+        if trans_req.file_meta.len() > MAX_FILE_META_SIZE {
+            warn!("file meta too large:{}", trans_req.file_meta.len());
+            return Err("file meta too large");
+        }
+
+        // the serve loop reserved the stream for this request
+        if !matches!(streams.get(&trans_req.stream_id), Some(TransferState::Pending)) {
+            warn!("stream not pending:{}", trans_req.stream_id);
+            return Err("stream already in use");
+        }
+
+        // two streams writing the same blob or the same file would corrupt each other
+        let busy = streams.values().any(|state| {
+            matches!(state.uploading(), Some(file_info)
+                if file_info.file_hash == trans_req.file_hash
+                    || (file_info.file_dir == trans_req.file_dir && file_info.file_name == trans_req.file_name))
+        });
+        if busy {
+            warn!("file already being uploaded:{}{}", trans_req.file_dir, trans_req.file_name);
+            return Err("file already being uploaded");
+        }
+
+        // the blob is shared, a conflicting size must not touch what others already stored
+        let shared = storage_ctx.db.query_shared_file(&trans_req.file_hash);
+        if matches!(&shared, Some(shared) if shared.sync_size > 0 && shared.file_size != trans_req.file_size) {
+            warn!(
+                "file size mismatch for stored content:{}, size:{}",
+                trans_req.file_hash, trans_req.file_size
+            );
+            return Err("file size doesn't match the stored content");
+        }
+        Ok(shared)
+    }
+
+    fn create_file_info(
+        user_id: u32,
+        credential: &Credential,
+        storage_ctx: &StorageContext,
+        trans_req: &TransferRequest,
+        shared: Option<SyncFileInfo>,
+        replacing: bool,
+    ) -> Result<SyncFileInfo> {
+        // a blob that is already (partially) stored stays where it is, an overwrite resumes where an earlier one stopped
+        let (storage_backend, sync_size) = match shared {
+            Some(shared) if replacing => (shared.storage_backend, shared.sync_size),
+            Some(shared) => (shared.storage_backend, 0),
+            None => (storage_ctx.file_storage.route(user_id, trans_req.file_size).to_string(), 0),
+        };
+        let file_info = SyncFileInfo {
+            file_hash: trans_req.file_hash.clone(),
+            file_dir: trans_req.file_dir.clone(),
+            file_name: trans_req.file_name.clone(),
+            file_size: trans_req.file_size,
+            sync_size,
+            file_meta: trans_req.file_meta.clone(),
+            storage_backend,
+            codec: String::new(),
+            chunked: false,
+            device: credential.device().to_string(),
+        };
+
+        if replacing {
+            // the file keeps its content until the new one is complete
+            storage_ctx.db.save_shared_file(&file_info)?;
+        } else {
+            storage_ctx.db.save_file_info(user_id, &file_info)?;
+        }
+        Ok(file_info)
+    }
+
+    /// Applies the request's conflict policy if another file exists under its name, keeping both moves the request
+    /// to a free name. Returns the content the upload replaces once it's complete.
+    fn resolve_conflict(
+        user_id: u32,
+        storage_ctx: &StorageContext,
+        trans_req: &mut TransferRequest,
+    ) -> core::result::Result<Option<String>, &'static str> {
+        let Some(existing) = storage_ctx.db.query_file_info(user_id, &trans_req.file_dir, &trans_req.file_name) else {
+            return Ok(None);
+        };
+        if existing.file_hash == trans_req.file_hash && existing.file_size == trans_req.file_size {
+            return Ok(None);
+        }
+
+        match trans_req.on_conflict {
+            ConflictPolicy::Overwrite => {
+                debug!(
+                    "overwriting:{}{}, content:{}",
+                    trans_req.file_dir, trans_req.file_name, existing.file_hash
+                );
+                Ok(Some(existing.file_hash))
+            }
+            ConflictPolicy::KeepBoth => match Self::kept_copy_name(
+                user_id,
+                storage_ctx,
+                &trans_req.file_dir,
+                &trans_req.file_name,
+                &trans_req.file_hash,
+                trans_req.file_size,
+            ) {
+                Some(file_name) => {
+                    debug!("keeping both:{}{}, new file:{file_name}", trans_req.file_dir, trans_req.file_name);
+                    trans_req.file_name = file_name;
+                    Ok(None)
+                }
+                None => Err("no free name to keep both files"),
+            },
+            ConflictPolicy::Reject => {
+                warn!("another file exists at:{}{}", trans_req.file_dir, trans_req.file_name);
+                Err("another file exists under this name")
+            }
+        }
+    }
+
+    /// "report.pdf" is kept as "report (1).pdf", a copy that already has the uploaded content is taken too so an
+    /// interrupted upload resumes where it was
+    pub fn kept_copy_name(
         user_id: u32,
-        writer: Option<Box<dyn FileWriter>>,
         storage_ctx: &StorageContext,
-        file_info: &SyncFileInfo,
+        file_dir: &str,
+        file_name: &str,
+        file_hash: &str,
+        file_size: usize,
+    ) -> Option<String> {
+        let (stem, extension) = match file_name.rfind('.') {
+            Some(pos) if pos > 0 => file_name.split_at(pos),
+            _ => (file_name, ""),
+        };
+
+        (1..=MAX_KEPT_COPIES).map(|n| format!("{stem} ({n}){extension}")).find(|name| {
+            match storage_ctx.db.query_file_info(user_id, file_dir, name) {
+                Some(copy) => copy.file_hash == file_hash && copy.file_size == file_size,
+                None => true,
+            }
+        })
+    }
+
+    /// The server answers an offer with the chunks it's missing, the file is complete once those are received
+    async fn start_chunked_upload(
+        user_id: u32,
+        credential: &Credential,
+        hash_algorithm: HashAlgorithm,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        streams: &mut Streams,
+        mut offer: ChunkOffer,
     ) -> Result<()> {
-        if let Some(mut writer) = writer {
-            writer.close();
+        let stream_id = offer.file.stream_id;
+        let shared = match Self::check_upload(credential, hash_algorithm, storage_ctx, streams, &offer.file) {
+            Ok(shared) => shared,
+            Err(msg) => return Self::send_error(sender, Some(stream_id), msg).await,
+        };
+
+        if let Err(msg) = ChunkedUpload::validate(hash_algorithm, offer.file.file_size, &offer.chunks) {
+            warn!("invalid chunks offered for:{}, {msg}", offer.file.file_hash);
+            return Self::send_error(sender, Some(stream_id), msg).await;
+        }
+
+        let replacing = match Self::resolve_conflict(user_id, storage_ctx, &mut offer.file) {
+            Ok(replacing) => replacing,
+            Err(msg) => return Self::send_error(sender, Some(stream_id), msg).await,
+        };
+        let trans_req = &offer.file;
+        if let Some(msg) = Self::link_existing_content(user_id, credential, storage_ctx, trans_req, replacing.as_deref())? {
+            return Ok(sender.send(msg.into()).await?);
+        }
+
+        // bytes of the blob were already stored as a whole, it can only be resumed that way
+        if matches!(&shared, Some(shared) if shared.sync_size > 0) {
+            warn!("file partially stored as a whole:{}", trans_req.file_hash);
+            return Self::send_error(sender, Some(stream_id), "file partially uploaded as a whole").await;
         }
 
-        if !file_info.file_hash.is_empty() {
-            storage_ctx.db.update_sync_size(user_id, file_info)?;
+        let file_info = match storage_ctx.db.query_file_info(user_id, &trans_req.file_dir, &trans_req.file_name) {
+            Some(file_info) if replacing.is_none() => file_info,
+            _ => Self::create_file_info(user_id, credential, storage_ctx, trans_req, shared, replacing.is_some())?,
+        };
+
+        let upload = ChunkedUpload::new(hash_algorithm, storage_ctx, file_info, offer.chunks);
+        let need = ChunkNeed {
+            stream_id,
+            file_hash: trans_req.file_hash.clone(),
+            missing: upload.missing().to_vec(),
+        };
+        sender.send(TransferControlMessage::ChunkNeed(need).into()).await?;
+
+        if upload.is_complete() {
+            let msg = Self::complete_chunked_upload(user_id, stream_id, storage_ctx, upload, replacing).await;
+            return Ok(sender.send(msg.into()).await?);
+        }
+
+        streams.insert(
+            stream_id,
+            TransferState::ReceivingChunks {
+                upload,
+                replacing,
+                window: INITIAL_WINDOW_SIZE,
+                consumed: 0,
+            },
+        );
+        Ok(())
+    }
+
+    async fn receive_frame(
+        user_id: u32,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        streams: &mut Streams,
+        data: &[u8],
+    ) -> Result<()> {
+        let Some(frame) = DataFrame::decode(data) else {
+            warn!("received malformed data frame:{}", data.len());
+            return Ok(());
+        };
+
+        let stream_id = frame.stream_id;
+        Self::await_signatures(sender, streams, stream_id).await?;
+        match streams.get(&stream_id) {
+            Some(TransferState::ReceivingChunks { .. }) => {
+                return Self::receive_chunk_frame(user_id, sender, storage_ctx, streams, frame).await;
+            }
+            Some(TransferState::ReceivingDelta { .. }) => {
+                return Self::receive_delta_frame(user_id, sender, storage_ctx, streams, frame).await;
+            }
+            _ => {}
+        }
+
+        let Some(TransferState::Receiving {
+            file_info,
+            writer,
+            window,
+            consumed,
+            ..
+        }) = streams.get_mut(&stream_id)
+        else {
+            warn!("received data for inactive stream:{stream_id}, len:{}", frame.data.len());
+            return Ok(());
+        };
+
+        let violation = if frame.data.len() > *window {
+            Some(format!("flow control window exceeded, window:{window}, len:{}", frame.data.len()))
+        } else if frame.offset != file_info.sync_size as u64 {
+            Some(format!("unexpected offset:{}, expected:{}", frame.offset, file_info.sync_size))
+        } else if file_info.sync_size + frame.data.len() > file_info.file_size {
+            // checked before writing, bytes past the declared size would spoil the blob others resume from
+            Some(format!("data exceeds the file size:{}", file_info.file_size))
+        } else {
+            None
+        };
+        if let Some(msg) = violation {
+            warn!("stream:{stream_id} aborted, {msg}");
+            if let Some(state) = streams.remove(&stream_id) {
+                Self::finalize_stream(user_id, state, storage_ctx).await?;
+            }
+            return Self::send_error(sender, Some(stream_id), &msg).await;
+        }
+
+        file_info.sync_size += writer.write(frame.data).await?;
+        *window -= frame.data.len();
+        *consumed += frame.data.len();
+
+        if file_info.sync_size >= file_info.file_size {
+            if let Some(TransferState::Receiving {
+                file_info,
+                writer,
+                replacing,
+                ..
+            }) = streams.remove(&stream_id)
+            {
+                let msg = Self::complete_upload(user_id, stream_id, storage_ctx, file_info, writer, replacing).await?;
+                sender.send(msg.into()).await?;
+            }
+        } else {
+            Self::update_window(sender, stream_id, window, consumed).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn receive_chunk_frame(
+        user_id: u32,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        streams: &mut Streams,
+        frame: DataFrame<'_>,
+    ) -> Result<()> {
+        let stream_id = frame.stream_id;
+        let Some(TransferState::ReceivingChunks {
+            upload, window, consumed, ..
+        }) = streams.get_mut(&stream_id)
+        else {
+            return Ok(());
+        };
+
+        let result = if frame.data.len() > *window {
+            Err(format!("flow control window exceeded, window:{window}, len:{}", frame.data.len()))
+        } else if upload.next_offset() != Some(frame.offset as usize) {
+            Err(format!("unexpected offset:{}, expected:{:?}", frame.offset, upload.next_offset()))
+        } else {
+            upload.write(storage_ctx, frame.data).await.map_err(|e| e.to_string())
+        };
+        if let Err(msg) = result {
+            warn!("stream:{stream_id} aborted, {msg}");
+            if let Some(state) = streams.remove(&stream_id) {
+                Self::finalize_stream(user_id, state, storage_ctx).await?;
+            }
+            return Self::send_error(sender, Some(stream_id), &msg).await;
+        }
+
+        *window -= frame.data.len();
+        *consumed += frame.data.len();
+
+        if upload.is_complete() {
+            if let Some(TransferState::ReceivingChunks { upload, replacing, .. }) = streams.remove(&stream_id) {
+                let msg = Self::complete_chunked_upload(user_id, stream_id, storage_ctx, upload, replacing).await;
+                sender.send(msg.into()).await?;
+            }
+        } else {
+            Self::update_window(sender, stream_id, window, consumed).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Updates an existing file, or creates it, with only what differs from its current content
+    async fn start_delta_upload(
+        user_id: u32,
+        credential: &Credential,
+        hash_algorithm: HashAlgorithm,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        streams: &mut Streams,
+        trans_req: TransferRequest,
+    ) -> Result<()> {
+        let stream_id = trans_req.stream_id;
+        let shared = match Self::check_upload(credential, hash_algorithm, storage_ctx, streams, &trans_req) {
+            Ok(shared) => shared,
+            Err(msg) => return Self::send_error(sender, Some(stream_id), msg).await,
+        };
+
+        let base = match storage_ctx.db.query_file_info(user_id, &trans_req.file_dir, &trans_req.file_name) {
+            Some(base) if base.sync_size < base.file_size => {
+                warn!("file not completely uploaded:{}{}", trans_req.file_dir, trans_req.file_name);
+                return Self::send_error(sender, Some(stream_id), "file not completely uploaded").await;
+            }
+            base => base,
+        };
+        let base_hash = base.as_ref().map(|base| base.file_hash.clone());
+        let exists = TransferControlMessage::Exists(TransferResponse {
+            stream_id,
+            file_hash: trans_req.file_hash.clone(),
+            sync_size: trans_req.file_size,
+            file_name: trans_req.file_name.clone(),
+        });
+        if base_hash.as_ref() == Some(&trans_req.file_hash) {
+            return Ok(sender.send(exists.into()).await?);
+        }
+
+        let mut file_info = SyncFileInfo {
+            file_hash: trans_req.file_hash.clone(),
+            file_dir: trans_req.file_dir.clone(),
+            file_name: trans_req.file_name.clone(),
+            file_size: trans_req.file_size,
+            sync_size: 0,
+            file_meta: trans_req.file_meta.clone(),
+            storage_backend: String::new(),
+            codec: String::new(),
+            chunked: false,
+            device: credential.device().to_string(),
+        };
+        match shared {
+            // the new content is already stored, the file only needs to be pointed at it
+            Some(shared) if shared.sync_size >= shared.file_size => {
+                file_info.sync_size = shared.sync_size;
+                file_info.storage_backend = shared.storage_backend;
+                file_info.codec = shared.codec;
+                if !storage_ctx.db.replace_file_content(user_id, &file_info, base_hash.as_deref())? {
+                    return Self::send_error(sender, Some(stream_id), "file changed during update").await;
+                }
+                debug!("linked existing content:{}, size:{}", file_info.file_hash, file_info.file_size);
+                return Ok(sender.send(exists.into()).await?);
+            }
+            Some(shared) if shared.sync_size > 0 => {
+                warn!("file partially stored as a whole:{}", trans_req.file_hash);
+                return Self::send_error(sender, Some(stream_id), "file partially uploaded as a whole").await;
+            }
+            Some(shared) => file_info.storage_backend = shared.storage_backend,
+            None => file_info.storage_backend = storage_ctx.file_storage.route(user_id, trans_req.file_size).to_string(),
+        }
+
+        let started = async {
+            // the signatures are of no use to an empty file
+            let reader = match &base {
+                Some(base) if trans_req.file_size > 0 => Some(storage_ctx.open_reader(base).await?),
+                _ => None,
+            };
+            let upload = DeltaUpload::start(storage_ctx, file_info, base.as_ref()).await?;
+            anyhow::Ok((upload, reader))
+        };
+        let (upload, reader) = match started.await {
+            Ok(started) => started,
+            Err(e) => {
+                error!("failed to start delta upload: {e:?}");
+                return Self::send_error(sender, Some(stream_id), "file not available").await;
+            }
+        };
+        let mut signatures = DeltaSignatures {
+            stream_id,
+            base_hash: base_hash.unwrap_or_default(),
+            block_size: upload.block_size(),
+            blocks: vec![],
+        };
+
+        // signing reads the whole current content, the other streams carry on meanwhile
+        if let Some(reader) = reader {
+            let block_count = upload.block_count();
+            let task = tokio::spawn({
+                let sender = sender.clone();
+                async move {
+                    let msg = match DeltaUpload::sign_base(hash_algorithm, reader, signatures.block_size, block_count).await {
+                        Ok(blocks) => {
+                            signatures.blocks = blocks;
+                            TransferControlMessage::DeltaSignatures(signatures)
+                        }
+                        Err(e) => {
+                            error!("failed to sign the base of stream:{stream_id}, {e:?}");
+                            TransferControlMessage::Error(TransferError {
+                                stream_id: Some(stream_id),
+                                message: "file not available".to_string(),
+                            })
+                        }
+                    };
+                    let signed = matches!(msg, TransferControlMessage::DeltaSignatures(_));
+                    let sent = sender.send(msg.into()).await.is_ok();
+                    signed && sent
+                }
+            });
+            streams.insert(stream_id, TransferState::SigningDelta { upload, task });
+            return Ok(());
+        }
+
+        sender.send(TransferControlMessage::DeltaSignatures(signatures).into()).await?;
+        if upload.is_complete() {
+            let msg = Self::complete_delta_upload(user_id, stream_id, storage_ctx, upload).await;
+            return Ok(sender.send(msg.into()).await?);
+        }
+
+        streams.insert(
+            stream_id,
+            TransferState::ReceivingDelta {
+                upload,
+                window: INITIAL_WINDOW_SIZE,
+                consumed: 0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Moves a delta upload on to receiving once its base is signed, waiting for the signing if it's still running.
+    /// A failed signing was already reported to the client and only releases the stream.
+    async fn await_signatures(sender: &mpsc::Sender<Message>, streams: &mut Streams, stream_id: u32) -> Result<()> {
+        if !matches!(streams.get(&stream_id), Some(TransferState::SigningDelta { .. })) {
+            return Ok(());
+        }
+        let Some(TransferState::SigningDelta { upload, task }) = streams.remove(&stream_id) else {
+            return Ok(());
+        };
+
+        match task.await {
+            Ok(true) => {
+                let state = TransferState::ReceivingDelta {
+                    upload,
+                    window: INITIAL_WINDOW_SIZE,
+                    consumed: 0,
+                };
+                streams.insert(stream_id, state);
+            }
+            Ok(false) => upload.abort().await,
+            Err(e) => {
+                error!("signing failed on stream:{stream_id}, {e:?}");
+                upload.abort().await;
+                Self::send_error(sender, Some(stream_id), "file not available").await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Settles the delta uploads whose signing has ended, so a failed one doesn't hold on to its stream and blob
+    async fn settle_signed(sender: &mpsc::Sender<Message>, streams: &mut Streams) -> Result<()> {
+        let signed: Vec<u32> = streams
+            .iter()
+            .filter(|(_, state)| matches!(state, TransferState::SigningDelta { task, .. } if task.is_finished()))
+            .map(|(stream_id, _)| *stream_id)
+            .collect();
+        for stream_id in signed {
+            Self::await_signatures(sender, streams, stream_id).await?;
+        }
+        Ok(())
+    }
+
+    async fn receive_delta_copy(
+        user_id: u32,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        streams: &mut Streams,
+        copy: DeltaCopy,
+    ) -> Result<()> {
+        let stream_id = copy.stream_id;
+        let Some(TransferState::ReceivingDelta { upload, .. }) = streams.get_mut(&stream_id) else {
+            warn!("received copy for inactive stream:{stream_id}");
+            return Ok(());
+        };
+
+        let result = if copy.offset != upload.next_offset() {
+            Err(format!("unexpected offset:{}, expected:{}", copy.offset, upload.next_offset()))
+        } else {
+            upload.copy(copy.block_index, copy.count).await.map_err(|e| e.to_string())
+        };
+        Self::continue_delta_upload(user_id, stream_id, sender, storage_ctx, streams, result).await
+    }
+
+    async fn receive_delta_frame(
+        user_id: u32,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        streams: &mut Streams,
+        frame: DataFrame<'_>,
+    ) -> Result<()> {
+        let stream_id = frame.stream_id;
+        let Some(TransferState::ReceivingDelta { upload, window, consumed }) = streams.get_mut(&stream_id) else {
+            return Ok(());
+        };
+
+        let result = if frame.data.len() > *window {
+            Err(format!("flow control window exceeded, window:{window}, len:{}", frame.data.len()))
+        } else if frame.offset != upload.next_offset() as u64 {
+            Err(format!("unexpected offset:{}, expected:{}", frame.offset, upload.next_offset()))
+        } else {
+            upload.write(frame.data).await.map_err(|e| e.to_string())
+        };
+        if result.is_ok() {
+            *window -= frame.data.len();
+            *consumed += frame.data.len();
+        }
+        Self::continue_delta_upload(user_id, stream_id, sender, storage_ctx, streams, result).await
+    }
+
+    /// Aborts the stream if applying the delta failed, completes it once the whole file was received
+    async fn continue_delta_upload(
+        user_id: u32,
+        stream_id: u32,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        streams: &mut Streams,
+        result: core::result::Result<(), String>,
+    ) -> Result<()> {
+        if let Err(msg) = result {
+            warn!("stream:{stream_id} aborted, {msg}");
+            if let Some(state) = streams.remove(&stream_id) {
+                Self::finalize_stream(user_id, state, storage_ctx).await?;
+            }
+            return Self::send_error(sender, Some(stream_id), &msg).await;
+        }
+
+        let Some(TransferState::ReceivingDelta { upload, window, consumed }) = streams.get_mut(&stream_id) else {
+            return Ok(());
+        };
+        if upload.is_complete() {
+            if let Some(TransferState::ReceivingDelta { upload, .. }) = streams.remove(&stream_id) {
+                let msg = Self::complete_delta_upload(user_id, stream_id, storage_ctx, upload).await;
+                sender.send(msg.into()).await?;
+            }
+            return Ok(());
+        }
+        Self::update_window(sender, stream_id, window, consumed).await
+    }
+
+    /// Hands the consumed part of the window back to the client once it's half used up
+    async fn update_window(sender: &mpsc::Sender<Message>, stream_id: u32, window: &mut usize, consumed: &mut usize) -> Result<()> {
+        if *consumed >= INITIAL_WINDOW_SIZE / 2 {
+            let size = std::mem::take(consumed);
+            *window += size;
+            sender
+                .send(TransferControlMessage::Window(WindowUpdate { stream_id, size }).into())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn start_download(
+        user_id: u32,
+        credential: &Credential,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        streams: &mut Streams,
+        req: DownloadRequest,
+    ) -> Result<()> {
+        let stream_id = req.stream_id;
+        if !matches!(streams.get(&stream_id), Some(TransferState::Pending)) {
+            warn!("stream not pending:{stream_id}");
+            return Self::send_error(sender, Some(stream_id), "stream already in use").await;
+        }
+
+        let (file_info, reader) = match Self::open_download(user_id, credential, storage_ctx, &req).await {
+            Ok(download) => download,
+            Err(msg) => return Self::send_error(sender, Some(stream_id), msg).await,
+        };
+
+        let credit = Arc::new(Semaphore::new(INITIAL_WINDOW_SIZE));
+        let task = tokio::spawn({
+            let sender = sender.clone();
+            let credit = credit.clone();
+            async move {
+                if let Err(e) = Self::send_file(sender, stream_id, file_info, reader, req.offset, credit).await {
+                    error!("download failed on stream:{stream_id}, {e:?}");
+                }
+            }
+        });
+        streams.insert(stream_id, TransferState::Sending { credit, task });
+        Ok(())
+    }
+
+    async fn complete_upload(
+        user_id: u32,
+        stream_id: u32,
+        storage_ctx: &mut StorageContext,
+        mut file_info: SyncFileInfo,
+        mut writer: Box<dyn FileWriter>,
+        replacing: Option<String>,
+    ) -> Result<TransferControlMessage> {
+        let blob = writer.close().await?;
+        if blob.digest != file_info.file_hash {
+            let message = format!("file hash mismatch, declared:{}, actual:{}", file_info.file_hash, blob.digest);
+            Self::reject_upload(user_id, storage_ctx, &mut file_info, writer, replacing.is_some()).await;
+            return Ok(TransferControlMessage::Error(TransferError {
+                stream_id: Some(stream_id),
+                message,
+            }));
+        }
+
+        writer.commit().await?;
+        file_info.codec = blob.codec;
+        storage_ctx.db.update_sync_size(user_id, &file_info)?;
+        // the file only gets the new content now, the complete blob is left for the garbage collector otherwise
+        if let Some(base_hash) = &replacing {
+            if !storage_ctx.db.replace_file_content(user_id, &file_info, Some(base_hash))? {
+                return Ok(TransferControlMessage::Error(TransferError {
+                    stream_id: Some(stream_id),
+                    message: "file changed during upload".to_string(),
+                }));
+            }
+        }
+
+        debug!("transfer completed, {}/{}", file_info.sync_size, file_info.file_size);
+        Ok(TransferControlMessage::Completed(TransferResponse {
+            stream_id,
+            file_hash: file_info.file_hash,
+            sync_size: file_info.sync_size,
+            file_name: file_info.file_name,
+        }))
+    }
+
+    /// A file that can't be completed is dropped like a rejected upload, its stored chunks stay for reuse.
+    /// A file being overwritten keeps its content instead.
+    async fn complete_chunked_upload(
+        user_id: u32,
+        stream_id: u32,
+        storage_ctx: &mut StorageContext,
+        mut upload: ChunkedUpload,
+        replacing: Option<String>,
+    ) -> TransferControlMessage {
+        if let Err(e) = upload.finish(storage_ctx).await {
+            warn!("rejecting chunked upload:{}, {e:?}", upload.file_info().file_hash);
+            if replacing.is_none() {
+                if let Err(e) = storage_ctx.db.delete_file_info(user_id, upload.file_info()) {
+                    error!("failed to delete file info: {e:?}");
+                }
+            }
+            return TransferControlMessage::Error(TransferError {
+                stream_id: Some(stream_id),
+                message: e.to_string(),
+            });
+        }
+
+        let file_info = upload.file_info();
+        if let Some(base_hash) = &replacing {
+            let message = match storage_ctx.db.replace_file_content(user_id, file_info, Some(base_hash)) {
+                Ok(true) => None,
+                Ok(false) => Some("file changed during upload".to_string()),
+                Err(e) => {
+                    error!("failed to replace file content: {e:?}");
+                    Some(e.to_string())
+                }
+            };
+            if let Some(message) = message {
+                return TransferControlMessage::Error(TransferError {
+                    stream_id: Some(stream_id),
+                    message,
+                });
+            }
+        }
+
+        debug!("chunked transfer completed:{}, size:{}", file_info.file_hash, file_info.file_size);
+        TransferControlMessage::Completed(TransferResponse {
+            stream_id,
+            file_hash: file_info.file_hash.clone(),
+            sync_size: file_info.sync_size,
+            file_name: file_info.file_name.clone(),
+        })
+    }
+
+    async fn complete_delta_upload(
+        user_id: u32,
+        stream_id: u32,
+        storage_ctx: &mut StorageContext,
+        mut upload: DeltaUpload,
+    ) -> TransferControlMessage {
+        let message = match upload.finish(user_id, storage_ctx).await {
+            Ok(true) => {
+                let file_info = upload.file_info();
+                debug!("delta transfer completed:{}, base:{:?}", file_info.file_hash, upload.base_hash());
+                return TransferControlMessage::Completed(TransferResponse {
+                    stream_id,
+                    file_hash: file_info.file_hash.clone(),
+                    sync_size: file_info.sync_size,
+                    file_name: file_info.file_name.clone(),
+                });
+            }
+            Ok(false) => "file changed during update".to_string(),
+            Err(e) => {
+                warn!("rejecting delta upload:{}, {e:?}", upload.file_info().file_hash);
+                e.to_string()
+            }
+        };
+        TransferControlMessage::Error(TransferError {
+            stream_id: Some(stream_id),
+            message,
+        })
+    }
+
+    /// The file's content stays as a version until pruned, the blob is purged by the garbage collector
+    /// once nothing references it anymore
+    fn delete_file(user_id: u32, storage_ctx: &StorageContext, req: DeleteRequest) -> Result<DeleteResponse> {
+        let file_info = SyncFileInfo {
+            file_dir: req.file_dir,
+            file_name: req.file_name,
+            ..Default::default()
+        };
+        let deleted = storage_ctx.db.delete_file_info(user_id, &file_info)?;
+        info!("deleted file:{}{}, deleted:{deleted}", file_info.file_dir, file_info.file_name);
+
+        Ok(DeleteResponse {
+            file_dir: file_info.file_dir,
+            file_name: file_info.file_name,
+            deleted,
+        })
+    }
+
+    async fn list_versions(
+        user_id: u32,
+        credential: &Credential,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        req: VersionListRequest,
+    ) -> Result<()> {
+        if !credential.allows(TokenScope::Read) {
+            warn!("listing versions not permitted for credential:{credential:?}");
+            return Self::send_error(sender, None, "permission denied").await;
+        }
+
+        let versions = storage_ctx.db.query_file_versions(user_id, &req.file_dir, &req.file_name)?;
+        let list = VersionList {
+            file_dir: req.file_dir,
+            file_name: req.file_name,
+            versions,
+        };
+        Ok(sender.send(TransferControlMessage::Versions(list).into()).await?)
+    }
+
+    /// The file can't be restored while it's being uploaded
+    async fn restore_version(
+        user_id: u32,
+        credential: &Credential,
+        sender: &mpsc::Sender<Message>,
+        storage_ctx: &mut StorageContext,
+        streams: &mut Streams,
+        req: RestoreRequest,
+    ) -> Result<()> {
+        if !credential.allows(TokenScope::Upload) {
+            warn!("restore not permitted for credential:{credential:?}");
+            return Self::send_error(sender, None, "permission denied").await;
+        }
+
+        let uploading = streams.values().any(|state| {
+            matches!(state.uploading(), Some(file_info)
+                if file_info.file_dir == req.file_dir && file_info.file_name == req.file_name)
+        });
+        if uploading {
+            warn!("restoring a file being uploaded:{}{}", req.file_dir, req.file_name);
+            return Self::send_error(sender, None, "file being uploaded").await;
+        }
+
+        let restored = storage_ctx
+            .db
+            .restore_file_version(user_id, &req.file_dir, &req.file_name, req.version_id)?;
+        info!(
+            "restored version:{} of:{}{}, restored:{restored}",
+            req.version_id, req.file_dir, req.file_name
+        );
+        let resp = RestoreResponse {
+            file_dir: req.file_dir,
+            file_name: req.file_name,
+            version_id: req.version_id,
+            restored,
+        };
+        Ok(sender.send(TransferControlMessage::Restored(resp).into()).await?)
+    }
+
+    /// Content that is already fully stored, by this user or anyone else, is linked without transferring any bytes.
+    /// Returns the reply to the request if nothing needs to be transferred.
+    fn link_existing_content(
+        user_id: u32,
+        credential: &Credential,
+        storage_ctx: &StorageContext,
+        req: &TransferRequest,
+        replacing: Option<&str>,
+    ) -> Result<Option<TransferControlMessage>> {
+        let resp = TransferResponse {
+            stream_id: req.stream_id,
+            file_hash: req.file_hash.clone(),
+            sync_size: req.file_size,
+            file_name: req.file_name.clone(),
+        };
+
+        if replacing.is_none() {
+            if let Some(file_info) = storage_ctx.db.query_file_info(user_id, &req.file_dir, &req.file_name) {
+                let completed = file_info.file_hash == req.file_hash && file_info.sync_size >= file_info.file_size;
+                return Ok(completed.then_some(TransferControlMessage::Exists(resp)));
+            }
+        }
+
+        match storage_ctx.db.query_shared_file(&req.file_hash) {
+            Some(shared) if shared.sync_size >= shared.file_size && shared.file_size == req.file_size => {
+                let file_info = SyncFileInfo {
+                    file_hash: req.file_hash.clone(),
+                    file_dir: req.file_dir.clone(),
+                    file_name: req.file_name.clone(),
+                    file_size: req.file_size,
+                    sync_size: req.file_size,
+                    file_meta: req.file_meta.clone(),
+                    storage_backend: shared.storage_backend,
+                    codec: shared.codec,
+                    chunked: shared.chunked,
+                    device: credential.device().to_string(),
+                };
+                match replacing {
+                    Some(base_hash) => {
+                        if !storage_ctx.db.replace_file_content(user_id, &file_info, Some(base_hash))? {
+                            return Ok(Some(TransferControlMessage::Error(TransferError {
+                                stream_id: Some(req.stream_id),
+                                message: "file changed during upload".to_string(),
+                            })));
+                        }
+                    }
+                    None => storage_ctx.db.save_file_info(user_id, &file_info)?,
+                }
+                debug!("linked existing content:{}, size:{}", req.file_hash, req.file_size);
+                Ok(Some(TransferControlMessage::Exists(resp)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The blob is useless once its content doesn't match its hash, so it is dropped and
+    /// whoever else references the hash starts over from scratch. A file being overwritten keeps its content.
+    async fn reject_upload(
+        user_id: u32,
+        storage_ctx: &mut StorageContext,
+        file_info: &mut SyncFileInfo,
+        mut writer: Box<dyn FileWriter>,
+        overwriting: bool,
+    ) {
+        warn!("rejecting upload with mismatched content:{}", file_info.file_hash);
+        file_info.sync_size = 0;
+        if let Err(e) = storage_ctx.db.update_sync_size(user_id, file_info) {
+            error!("failed to reset sync size: {e:?}");
+        }
+        if !overwriting {
+            if let Err(e) = storage_ctx.db.delete_file_info(user_id, file_info) {
+                error!("failed to delete file info: {e:?}");
+            }
+        }
+        if let Err(e) = writer.discard().await {
+            error!("failed to discard rejected blob: {e:?}");
+        }
+    }
+
+    async fn open_download(
+        user_id: u32,
+        credential: &Credential,
+        storage_ctx: &mut StorageContext,
+        req: &DownloadRequest,
+    ) -> core::result::Result<(SyncFileInfo, Box<dyn FileReader>), &'static str> {
+        if !credential.allows(TokenScope::Read) {
+            warn!("download not permitted for credential:{credential:?}");
+            return Err("permission denied");
+        }
+
+        let file_info = match storage_ctx.db.query_file_info(user_id, &req.file_dir, &req.file_name) {
+            Some(file_info) if file_info.sync_size >= file_info.file_size => file_info,
+            Some(_) => return Err("file not completely uploaded"),
+            None => return Err("file not found"),
+        };
+
+        if req.offset > file_info.file_size {
+            return Err("offset out of range");
+        }
+
+        let reader = storage_ctx.open_reader(&file_info).await.map_err(|e| {
+            error!("failed to open reader: {e:?}");
+            "file not available"
+        })?;
+        Ok((file_info, reader))
+    }
+
+    async fn send_file(
+        sender: mpsc::Sender<Message>,
+        stream_id: u32,
+        file_info: SyncFileInfo,
+        reader: Box<dyn FileReader>,
+        offset: usize,
+        credit: Arc<Semaphore>,
+    ) -> Result<()> {
+        debug!(
+            "sending file:{}, offset:{offset}, size:{}",
+            file_info.file_hash, file_info.file_size
+        );
+        let resp = DownloadResponse {
+            stream_id,
+            file_hash: file_info.file_hash.clone(),
+            file_size: file_info.file_size,
+            offset,
+            file_meta: file_info.file_meta.clone(),
+        };
+        sender.send(TransferControlMessage::DownloadResponse(resp).into()).await?;
+
+        let mut reader = AsyncFileReader::new(reader);
+        reader.seek(SeekFrom::Start(offset as u64)).await?;
+
+        let mut buf = vec![0u8; DOWNLOAD_CHUNK_SIZE];
+        let mut sent_size = offset;
+        while sent_size < file_info.file_size {
+            let len = DOWNLOAD_CHUNK_SIZE.min(file_info.file_size - sent_size);
+            credit.acquire_many(len as u32).await?.forget();
+
+            let read_len = reader.read(&mut buf[..len]).await?;
+            if read_len == 0 {
+                bail!(
+                    "unexpected end of file:{}, {sent_size}/{}",
+                    file_info.file_hash,
+                    file_info.file_size
+                );
+            }
+            // a short read gives back the credit it didn't use
+            credit.add_permits(len - read_len);
+
+            sender
+                .send(Message::Binary(DataFrame::encode(stream_id, sent_size as u64, &buf[..read_len])))
+                .await?;
+            sent_size += read_len;
+        }
+        reader.close().await;
+
+        debug!("download completed, {sent_size}/{}", file_info.file_size);
+        Ok(())
+    }
+
+    async fn send_error(sender: &mpsc::Sender<Message>, stream_id: Option<u32>, message: &str) -> Result<()> {
+        let err = TransferError {
+            stream_id,
+            message: message.to_string(),
+        };
+        sender.send(TransferControlMessage::Error(err).into()).await?;
+        Ok(())
+    }
+
+    /// Persists the progress of an unfinished upload so a later session can resume it, unfinished downloads are just stopped
+    async fn finalize_stream(user_id: u32, state: TransferState, storage_ctx: &mut StorageContext) -> Result<()> {
+        match state {
+            TransferState::Receiving { file_info, mut writer, .. } => match writer.close().await {
+                Ok(_) => storage_ctx.db.update_sync_size(user_id, &file_info)?,
+                // the recorded progress stays behind, bytes beyond it are dropped on resume
+                Err(e) => error!("failed to close writer: {e:?}"),
+            },
+            // the chunks received completely were already stored, an offer of the same file resumes from there
+            TransferState::ReceivingChunks { upload, .. } => upload.abort().await,
+            TransferState::SigningDelta { upload, task } => {
+                task.abort();
+                upload.abort().await;
+            }
+            TransferState::ReceivingDelta { upload, .. } => upload.abort().await,
+            TransferState::Sending { task, .. } => task.abort(),
+            TransferState::Pending => {}
         }
 
         Ok(())